@@ -0,0 +1,317 @@
+#![warn(missing_docs)]
+//! Procedural macros for [effectum](https://docs.rs/effectum). These are re-exported from the
+//! `effectum` crate when its `macros` feature is enabled, and should be used from there.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, ToTokens};
+use syn::{
+    meta::ParseNestedMeta, parse_macro_input, FnArg, GenericArgument, ItemFn, LitInt, LitStr,
+    PathArguments, ReturnType, Type,
+};
+
+#[derive(Default)]
+struct JobArgs {
+    name: Option<LitStr>,
+    retries: Option<LitInt>,
+    timeout: Option<u64>,
+    priority: Option<LitInt>,
+    weight: Option<LitInt>,
+}
+
+impl JobArgs {
+    fn parse(&mut self, meta: ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("name") {
+            self.name = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("retries") {
+            self.retries = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("timeout") {
+            let value: LitStr = meta.value()?.parse()?;
+            let seconds =
+                parse_duration(&value.value()).map_err(|e| syn::Error::new(value.span(), e))?;
+            self.timeout = Some(seconds);
+        } else if meta.path.is_ident("priority") {
+            self.priority = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("weight") {
+            self.weight = Some(meta.value()?.parse()?);
+        } else {
+            return Err(meta.error(
+                "unsupported job option, expected one of name, retries, timeout, priority, weight",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a duration like "30s", "10m", "2h", or "1d" into a number of seconds. A plain number is
+/// treated as seconds.
+fn parse_duration(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration {value:?}"))?;
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration unit in {value:?}, expected s, m, h, or d"
+            ))
+        }
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("duration {value:?} is too long"))
+}
+
+/// Get `T` and `E` from a return type of `Result<T, E>`.
+fn result_types(output: &ReturnType) -> syn::Result<(&Type, &Type)> {
+    let error = || syn::Error::new_spanned(output, "job functions must return a Result<T, E>");
+
+    let ReturnType::Type(_, ty) = output else {
+        return Err(error());
+    };
+    let Type::Path(path) = ty.as_ref() else {
+        return Err(error());
+    };
+    let segment = path.path.segments.last().ok_or_else(error)?;
+    if segment.ident != "Result" {
+        return Err(error());
+    }
+
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return Err(error());
+    };
+    let mut types = args.args.iter().filter_map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    });
+
+    match (types.next(), types.next(), types.next()) {
+        (Some(ok), Some(err), None) => Ok((ok, err)),
+        _ => Err(error()),
+    }
+}
+
+fn expand(args: JobArgs, item: ItemFn) -> syn::Result<TokenStream2> {
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = item;
+
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            sig.fn_token,
+            "job functions must be async",
+        ));
+    }
+
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &sig.generics,
+            "job functions can not be generic",
+        ));
+    }
+
+    let inputs = sig
+        .inputs
+        .iter()
+        .map(|arg| match arg {
+            FnArg::Typed(arg) => Ok(arg),
+            FnArg::Receiver(arg) => Err(syn::Error::new_spanned(
+                arg,
+                "job functions can not take self",
+            )),
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let (job_arg, payload_arg, context_arg) = match inputs.as_slice() {
+        [job, payload] => (*job, *payload, None),
+        [job, payload, context] => (*job, *payload, Some(*context)),
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &sig.inputs,
+                "job functions must take a RunningJob, a payload, and optionally a context",
+            ))
+        }
+    };
+
+    let (output_ty, error_ty) = result_types(&sig.output)?;
+
+    // Doc comments and `cfg` belong on the struct that replaces the function, and `cfg` also has
+    // to apply to its impls. Anything else, like `#[allow]` or `#[instrument]`, was written for
+    // the function, so it goes on the `run` method.
+    let (struct_attrs, fn_attrs): (Vec<_>, Vec<_>) = attrs
+        .into_iter()
+        .partition(|attr| attr.path().is_ident("doc") || attr.path().is_ident("cfg"));
+    let cfg_attrs = struct_attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("cfg"))
+        .collect::<Vec<_>>();
+
+    let ident = &sig.ident;
+    let name = args
+        .name
+        .map(|name| name.value())
+        .unwrap_or_else(|| ident.to_string());
+
+    let job_pat = &job_arg.pat;
+    let job_ty = &job_arg.ty;
+    let payload_pat = &payload_arg.pat;
+    let payload_ty = &payload_arg.ty;
+    let (context_pat, context_ty) = match context_arg {
+        Some(context) => (context.pat.to_token_stream(), context.ty.to_token_stream()),
+        None => (quote!(_), quote!(())),
+    };
+
+    let mut job_options = Vec::new();
+    if let Some(retries) = args.retries {
+        job_options.push(quote!(.max_retries(#retries)));
+    }
+    if let Some(timeout) = args.timeout {
+        job_options.push(quote!(.timeout(::std::time::Duration::from_secs(#timeout))));
+    }
+    if let Some(priority) = args.priority {
+        job_options.push(quote!(.priority(#priority)));
+    }
+    if let Some(weight) = args.weight {
+        job_options.push(quote!(.weight(#weight)));
+    }
+
+    Ok(quote! {
+        #(#struct_attrs)*
+        #[allow(non_camel_case_types)]
+        #[derive(Debug, Clone, Copy)]
+        #vis struct #ident;
+
+        #(#cfg_attrs)*
+        impl #ident {
+            /// Create a [JobRunner](::effectum::JobRunner) for this job.
+            #vis fn runner() -> ::effectum::JobRunner<#context_ty> {
+                ::effectum::JobRunner::from_handler::<Self>()
+            }
+
+            /// Create a [JobBuilder](::effectum::JobBuilder) for this job, with the options
+            /// from the job's attribute already applied.
+            #vis fn builder(
+                payload: &#payload_ty,
+            ) -> ::effectum::Result<::effectum::JobBuilder> {
+                ::effectum::Job::for_handler::<Self>(payload).map(|builder| builder #(#job_options)*)
+            }
+
+            /// Submit this job to the queue, with the options from the job's attribute.
+            #vis async fn enqueue(
+                queue: &::effectum::Queue,
+                payload: #payload_ty,
+            ) -> ::effectum::Result<::effectum::__private::Uuid> {
+                Self::builder(&payload)?.add_to(queue).await
+            }
+        }
+
+        #(#cfg_attrs)*
+        impl ::effectum::JobHandler for #ident {
+            const NAME: &'static str = #name;
+            type Payload = #payload_ty;
+            type Context = #context_ty;
+            type Output = #output_ty;
+            type Error = #error_ty;
+
+            #(#fn_attrs)*
+            async fn run(
+                #job_pat: #job_ty,
+                #payload_pat: #payload_ty,
+                #context_pat: #context_ty,
+            ) -> ::std::result::Result<#output_ty, #error_ty> #block
+        }
+    })
+}
+
+/// Declare a job from an async function.
+///
+/// The function takes a `RunningJob`, the job's payload, and optionally the worker's context,
+/// and returns a `Result<T, E>`. The macro replaces the function with a unit struct of the same
+/// name, which implements `effectum::JobHandler` and has these functions:
+///
+/// - `runner()` creates a `JobRunner` to register with a worker.
+/// - `builder(&payload)` creates a `JobBuilder` with the options from the attribute applied.
+/// - `enqueue(&queue, payload)` submits the job to the queue with those options.
+///
+/// The attribute accepts these options, all of which are optional:
+///
+/// - `name = "..."`: the job type. Defaults to the name of the function.
+/// - `retries = 5`: the maximum number of retries.
+/// - `timeout = "10m"`: how long the job can run. Units can be `s`, `m`, `h`, or `d`.
+/// - `priority = 1`: the job's priority.
+/// - `weight = 2`: the job's weight.
+///
+/// Doc comments and `cfg` attributes on the function are applied to the struct. Other attributes
+/// are applied to the `JobHandler::run` method that contains the function's body.
+///
+/// ```ignore
+/// #[effectum::job(name = "remind_me", retries = 5, timeout = "10m")]
+/// async fn remind_me(
+///     job: RunningJob,
+///     payload: RemindMePayload,
+///     context: Arc<JobContext>,
+/// ) -> Result<(), Error> {
+///     // send the reminder
+///     Ok(())
+/// }
+///
+/// let worker = Worker::builder(&queue, context)
+///     .jobs([remind_me::runner()])
+///     .build()
+///     .await?;
+///
+/// remind_me::enqueue(&queue, payload).await?;
+/// ```
+#[proc_macro_attribute]
+pub fn job(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = JobArgs::default();
+    let parser = syn::meta::parser(|meta| args.parse(meta));
+    parse_macro_input!(attr with parser);
+    let item = parse_macro_input!(item as ItemFn);
+
+    expand(args, item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("45"), Ok(45));
+        assert_eq!(parse_duration("30s"), Ok(30));
+        assert_eq!(parse_duration("10m"), Ok(600));
+        assert_eq!(parse_duration("2h"), Ok(7200));
+        assert_eq!(parse_duration("1d"), Ok(86400));
+        assert!(parse_duration("10y").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("300000000000000000d").is_err());
+    }
+
+    #[test]
+    fn result_type() {
+        let output: ReturnType = syn::parse_quote!(-> Result<String, effectum::Error>);
+        let (output_ty, error_ty) = result_types(&output).unwrap();
+        assert_eq!(quote!(#output_ty).to_string(), "String");
+        assert_eq!(quote!(#error_ty).to_string(), "effectum :: Error");
+
+        let output: ReturnType = syn::parse_quote!(-> effectum::Result<String>);
+        assert!(result_types(&output).is_err());
+    }
+}
@@ -0,0 +1,2 @@
+ALTER TABLE jobs
+  ADD COLUMN manually_triggered int not null default 0;
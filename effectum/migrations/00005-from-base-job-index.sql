@@ -0,0 +1,3 @@
+CREATE INDEX jobs_from_base_job ON jobs (from_base_job)
+WHERE
+  from_base_job IS NOT NULL;
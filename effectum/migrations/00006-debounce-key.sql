@@ -0,0 +1,6 @@
+ALTER TABLE jobs
+  ADD COLUMN debounce_key text;
+
+CREATE INDEX jobs_debounce_key ON jobs (debounce_key, job_type)
+WHERE
+  debounce_key IS NOT NULL;
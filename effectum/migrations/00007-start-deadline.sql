@@ -0,0 +1,10 @@
+ALTER TABLE jobs
+  ADD COLUMN start_deadline bigint;
+
+ALTER TABLE active_jobs
+  ADD COLUMN start_deadline bigint;
+
+CREATE INDEX active_start_deadline ON active_jobs (start_deadline)
+WHERE
+  active_worker_id IS NULL
+  AND start_deadline IS NOT NULL;
@@ -0,0 +1,9 @@
+ALTER TABLE jobs
+  ADD COLUMN fairness_key text NOT NULL DEFAULT '';
+
+ALTER TABLE active_jobs
+  ADD COLUMN fairness_key text NOT NULL DEFAULT '';
+
+CREATE INDEX active_fairness_key ON active_jobs (fairness_key, priority DESC, run_at)
+WHERE
+  active_worker_id IS NULL;
@@ -0,0 +1,5 @@
+ALTER TABLE jobs
+  ADD COLUMN snooze_count int NOT NULL DEFAULT 0;
+
+ALTER TABLE jobs
+  ADD COLUMN max_snoozes int NOT NULL DEFAULT 10;
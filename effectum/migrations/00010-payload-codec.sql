@@ -0,0 +1,5 @@
+ALTER TABLE jobs
+  ADD COLUMN payload_codec text NOT NULL DEFAULT 'json';
+
+ALTER TABLE jobs
+  ADD COLUMN checkpoint_codec text;
@@ -0,0 +1,5 @@
+ALTER TABLE jobs
+  ADD COLUMN payload_compression text;
+
+ALTER TABLE jobs
+  ADD COLUMN checkpoint_compression text;
@@ -0,0 +1,5 @@
+ALTER TABLE jobs
+  ADD COLUMN payload_key_id text;
+
+ALTER TABLE jobs
+  ADD COLUMN checkpoint_key_id text;
@@ -0,0 +1,4 @@
+ALTER TABLE jobs
+  ADD COLUMN payload_blob text;
+
+CREATE INDEX jobs_payload_blob ON jobs(payload_blob) WHERE payload_blob IS NOT NULL;
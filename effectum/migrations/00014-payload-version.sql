@@ -0,0 +1,5 @@
+ALTER TABLE jobs
+  ADD COLUMN payload_version integer NOT NULL DEFAULT 0;
+
+ALTER TABLE jobs
+  ADD COLUMN checkpoint_version integer;
@@ -0,0 +1,175 @@
+use std::{sync::Mutex, time::Duration};
+
+/// Settings for adjusting a worker's concurrency limit automatically, set with
+/// [WorkerBuilder::adaptive_concurrency](crate::WorkerBuilder::adaptive_concurrency).
+///
+/// The limit moves between the worker's `min_concurrency` and `max_concurrency` using additive
+/// increase and multiplicative decrease (AIMD). The worker looks at its finished jobs in rounds,
+/// where each round is as many jobs as the current limit. If a round goes well, the limit
+/// increases by one. If any job in the round timed out, too many failed, or the jobs took
+/// longer than the latency target on average, the limit is multiplied by `backoff`.
+#[derive(Debug, Clone)]
+pub struct AdaptiveConcurrency {
+    /// Lower the limit when the average run time of the jobs in a round is above this. By
+    /// default, run time is not considered.
+    pub latency_target: Option<Duration>,
+    /// Lower the limit when more than this fraction of the jobs in a round fail. Defaults to 0.1.
+    pub max_error_rate: f64,
+    /// The factor to multiply the limit by when lowering it, between 0 and 1. Defaults to 0.75.
+    pub backoff: f64,
+}
+
+impl Default for AdaptiveConcurrency {
+    fn default() -> Self {
+        Self {
+            latency_target: None,
+            max_error_rate: 0.1,
+            backoff: 0.75,
+        }
+    }
+}
+
+impl AdaptiveConcurrency {
+    /// Create adaptive concurrency settings with the default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lower the limit when jobs take longer than `target` on average.
+    pub fn latency_target(mut self, target: Duration) -> Self {
+        self.latency_target = Some(target);
+        self
+    }
+
+    /// Lower the limit when more than this fraction of jobs fail.
+    pub fn max_error_rate(mut self, rate: f64) -> Self {
+        assert!((0.0..=1.0).contains(&rate));
+        self.max_error_rate = rate;
+        self
+    }
+
+    /// Set the factor to multiply the limit by when lowering it.
+    pub fn backoff(mut self, backoff: f64) -> Self {
+        assert!(backoff > 0.0 && backoff < 1.0);
+        self.backoff = backoff;
+        self
+    }
+}
+
+/// How a finished job affects the concurrency limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JobSignal {
+    Succeeded(Duration),
+    Failed(Duration),
+    TimedOut,
+}
+
+#[derive(Debug, Default)]
+struct Round {
+    completed: u32,
+    failed: u32,
+    timed_out: bool,
+    total_time: Duration,
+}
+
+/// Tracks the results of a worker's jobs and decides when to change its concurrency limit.
+pub(crate) struct ConcurrencyController {
+    settings: AdaptiveConcurrency,
+    round: Mutex<Round>,
+}
+
+impl ConcurrencyController {
+    pub fn new(settings: AdaptiveConcurrency) -> Self {
+        Self {
+            settings,
+            round: Mutex::new(Round::default()),
+        }
+    }
+
+    /// Record a finished job, and return the new limit if it should change.
+    pub fn record(&self, signal: JobSignal, limit: u16, min: u16, max: u16) -> Option<u16> {
+        let mut round = self.round.lock().unwrap();
+        round.completed += 1;
+        match signal {
+            JobSignal::Succeeded(time) => round.total_time += time,
+            JobSignal::Failed(time) => {
+                round.failed += 1;
+                round.total_time += time;
+            }
+            JobSignal::TimedOut => round.timed_out = true,
+        }
+
+        // Back off as soon as a timeout shows up, instead of waiting for the round to finish.
+        if !round.timed_out && round.completed < limit as u32 {
+            return None;
+        }
+
+        let error_rate = round.failed as f64 / round.completed as f64;
+        let average_time = round.total_time / round.completed;
+        let congested = round.timed_out
+            || error_rate > self.settings.max_error_rate
+            || self
+                .settings
+                .latency_target
+                .is_some_and(|target| average_time > target);
+        *round = Round::default();
+
+        let new_limit = if congested {
+            ((limit as f64 * self.settings.backoff).floor() as u16).max(min)
+        } else {
+            limit.saturating_add(1).min(max)
+        };
+
+        (new_limit != limit).then_some(new_limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_round(controller: &ConcurrencyController, signal: JobSignal, limit: u16) -> Option<u16> {
+        (0..limit)
+            .filter_map(|_| controller.record(signal, limit, 1, 10))
+            .last()
+    }
+
+    #[test]
+    fn increase_after_good_round() {
+        let controller = ConcurrencyController::new(AdaptiveConcurrency::new());
+        let fast = JobSignal::Succeeded(Duration::from_millis(10));
+        assert_eq!(controller.record(fast, 3, 1, 10), None);
+        assert_eq!(controller.record(fast, 3, 1, 10), None);
+        assert_eq!(controller.record(fast, 3, 1, 10), Some(4));
+
+        // Never goes above the maximum.
+        assert_eq!(run_round(&controller, fast, 10), None);
+    }
+
+    #[test]
+    fn decrease_on_errors() {
+        let controller = ConcurrencyController::new(AdaptiveConcurrency::new().backoff(0.5));
+        let failed = JobSignal::Failed(Duration::from_millis(10));
+        assert_eq!(run_round(&controller, failed, 8), Some(4));
+
+        // Never goes below the minimum.
+        assert_eq!(run_round(&controller, failed, 1), None);
+    }
+
+    #[test]
+    fn decrease_on_latency() {
+        let controller = ConcurrencyController::new(
+            AdaptiveConcurrency::new().latency_target(Duration::from_secs(1)),
+        );
+        let slow = JobSignal::Succeeded(Duration::from_secs(2));
+        assert_eq!(run_round(&controller, slow, 8), Some(6));
+    }
+
+    #[test]
+    fn decrease_immediately_on_timeout() {
+        let controller = ConcurrencyController::new(AdaptiveConcurrency::new());
+        let fast = JobSignal::Succeeded(Duration::from_millis(10));
+        assert_eq!(controller.record(fast, 8, 1, 10), None);
+        assert_eq!(controller.record(JobSignal::TimedOut, 8, 1, 10), Some(6));
+    }
+}
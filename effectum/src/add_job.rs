@@ -1,4 +1,4 @@
-use std::{borrow::Cow, time::Duration};
+use std::{borrow::Cow, sync::Arc, time::Duration};
 
 use ahash::{HashMap, HashSet};
 use serde::{Deserialize, Serialize};
@@ -7,13 +7,20 @@ use tracing::{instrument, Span};
 use uuid::Uuid;
 
 use crate::{
+    codec::{JsonCodec, PayloadCodec},
     db_writer::{
         add_job::{AddJobArgs, AddMultipleJobsArgs, AddMultipleJobsResult},
         cancel_job::CancelJobArgs,
         update_job::UpdateJobArgs,
         DbOperation, DbOperationType,
     },
+    hooks::run_hook,
+    labels::Labels,
+    payload_storage::PayloadStorage,
+    queue_metrics::jobs_enqueued,
+    resources::Resources,
     shared_state::SharedState,
+    trace_context::current_trace_context,
     worker::log_error,
     Error, Queue, Result, SmartString,
 };
@@ -42,17 +49,60 @@ pub struct Job {
     /// For example, a video transcoding task might alter the weight depending on the resolution of
     /// the video or the processing requirements of the codec for each run.
     pub weight: u32,
+    /// Named resources that the job needs while it runs, such as `cpu` or `db`. A worker only
+    /// takes the job if it has enough of each resource left over from its other running jobs.
+    /// Resources that the worker doesn't declare with
+    /// [WorkerBuilder::resource_capacity](crate::WorkerBuilder::resource_capacity) are
+    /// unlimited.
+    #[serde(default)]
+    pub resources: Resources,
+    /// Labels that a worker must have to run the job, such as the region it runs in or a
+    /// model cache that it has on disk. The job only runs on workers that have every one of these
+    /// labels with the same value, set with [WorkerBuilder::label](crate::WorkerBuilder::label).
+    #[serde(default)]
+    pub required_labels: Labels,
     /// When to run the job. `None` means to run it right away.
     pub run_at: Option<time::OffsetDateTime>,
+    /// If the job has not started by this time, it is marked as [Expired](crate::JobState::Expired)
+    /// instead of running. This also applies to retries of a failed job.
+    pub start_deadline: Option<time::OffsetDateTime>,
     /// The payload to pass to the job when it runs.
     pub payload: Vec<u8>,
+    /// The name of the [PayloadCodec] used to encode the payload. This is set by
+    /// [JobBuilder::payload_with], and defaults to JSON.
+    #[serde(default = "default_payload_codec")]
+    pub payload_codec: Cow<'static, str>,
+    /// The version of the payload's format. When a job runs, payloads with an older version are
+    /// upgraded using the functions registered with
+    /// [JobRunnerBuilder::upgrade_payload](crate::JobRunnerBuilder::upgrade_payload).
+    #[serde(default)]
+    pub payload_version: u32,
     /// Retry behavior when the job fails.
     pub retries: Retries,
     /// How long to allow the job to run before it is considered failed.
     pub timeout: Duration,
     /// How much extra time a heartbeat will add to the expiration time.
     pub heartbeat_increment: Duration,
+    /// The maximum number of times the job can be snoozed. See [RunningJobData::snooze](crate::RunningJobData::snooze).
+    pub max_snoozes: u32,
+    /// Collapse multiple submissions of this job into a single run. See [JobBuilder::debounce].
+    pub debounce: Option<Debounce>,
+    /// Ready jobs are shared out evenly between fairness keys, such as a tenant ID, so that one
+    /// key with a large backlog doesn't hold up jobs for the others. Jobs with no key share a
+    /// single group.
+    pub fairness_key: Option<String>,
     pub(crate) from_recurring: Option<i64>,
+    /// How the payload is stored, once the queue has compressed or encrypted it. `None` means
+    /// that the payload has not been processed yet.
+    #[serde(skip)]
+    pub(crate) payload_storage: Option<PayloadStorage>,
+    /// True if this job was created from a recurring job template by
+    /// [Queue::trigger_recurring_job], outside of the normal schedule.
+    #[serde(default)]
+    pub(crate) manually_triggered: bool,
+    /// The serialized trace context of the span that added the job.
+    #[serde(skip)]
+    pub(crate) trace_context: Option<String>,
 }
 
 impl Job {
@@ -67,6 +117,34 @@ impl Job {
         job.id = Uuid::now_v7();
         job
     }
+
+    /// For debounced jobs, push the run time back by the debounce window.
+    fn apply_debounce_window(&mut self, now: OffsetDateTime) {
+        if let Some(debounce) = self.debounce.as_ref() {
+            self.run_at = Some(self.run_at.unwrap_or(now) + debounce.window);
+        }
+    }
+
+    /// Record the trace context of the current span, unless the job already has one.
+    pub(crate) fn capture_trace_context(&mut self) {
+        if self.trace_context.is_none() {
+            self.trace_context = current_trace_context();
+        }
+    }
+}
+
+fn default_payload_codec() -> Cow<'static, str> {
+    Cow::Borrowed(JsonCodec::NAME)
+}
+
+/// `Debounce` collapses multiple submissions of a job into a single run, which happens
+/// after the submissions stop for a while.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Debounce {
+    /// Jobs of the same type with the same key are collapsed together.
+    pub key: String,
+    /// How long to wait after the most recent submission before running the job.
+    pub window: Duration,
 }
 
 /// `Retries` controls the exponential backoff behavior when retrying failed jobs.
@@ -104,12 +182,23 @@ impl Default for Job {
             name: None,
             priority: 0,
             weight: 1,
+            resources: Resources::new(),
+            required_labels: Labels::new(),
             run_at: Default::default(),
+            start_deadline: None,
             payload: Default::default(),
+            payload_codec: default_payload_codec(),
+            payload_version: 0,
             retries: Default::default(),
             timeout: Duration::from_secs(300),
             heartbeat_increment: Duration::from_secs(120),
+            max_snoozes: 10,
+            debounce: None,
+            fairness_key: None,
             from_recurring: Default::default(),
+            payload_storage: None,
+            manually_triggered: false,
+            trace_context: None,
         }
     }
 }
@@ -157,13 +246,61 @@ impl JobBuilder {
         self
     }
 
+    /// Require `amount` of the named resource while the job runs. See [Job::resources].
+    pub fn resource(mut self, name: impl ToString, amount: u32) -> Self {
+        self.job.resources.insert(name.to_string(), amount);
+        self
+    }
+
+    /// Set all the resources that the job needs, replacing any that were already set.
+    pub(crate) fn resources(mut self, resources: Resources) -> Self {
+        self.job.resources = resources;
+        self
+    }
+
+    /// Only run the job on workers that have the label `name` set to `value`. See
+    /// [Job::required_labels].
+    pub fn require_label(mut self, name: impl ToString, value: impl ToString) -> Self {
+        self.job
+            .required_labels
+            .insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Set all the labels that the job requires, replacing any that were already set.
+    pub(crate) fn required_labels(mut self, labels: Labels) -> Self {
+        self.job.required_labels = labels;
+        self
+    }
+
     /// Set the time at which the job should run.
     pub fn run_at(mut self, run_at: time::OffsetDateTime) -> Self {
         self.job.run_at = Some(run_at);
         self
     }
 
-    /// Set the payload of the job.
+    /// Give up on the job if it can not start by the given time. See [Job::start_deadline].
+    pub fn expire_if_not_started_by(mut self, deadline: time::OffsetDateTime) -> Self {
+        self.job.start_deadline = Some(deadline);
+        self
+    }
+
+    /// Set the fairness key of the job. When workers fetch jobs, they take them from each fairness
+    /// key in turn instead of in strict priority order, so that a key with many jobs waiting
+    /// can't starve the others. Priority still applies among jobs with the same key.
+    pub fn fairness_key(mut self, key: impl ToString) -> Self {
+        self.job.fairness_key = Some(key.to_string());
+        self
+    }
+
+    /// Set the fairness key of the job, or clear it if `None`. See [JobBuilder::fairness_key].
+    pub fn fairness_key_opt(mut self, key: Option<String>) -> Self {
+        self.job.fairness_key = key;
+        self
+    }
+
+    /// Set the payload of the job. The payload is assumed to be JSON unless a codec is set with
+    /// [JobBuilder::payload_codec].
     pub fn payload(mut self, payload: Vec<u8>) -> Self {
         self.job.payload = payload;
         self
@@ -172,9 +309,48 @@ impl JobBuilder {
     /// Serialize the payload of the job using `serde_json`.
     pub fn json_payload<T: ?Sized + serde::Serialize>(mut self, payload: &T) -> Result<Self> {
         self.job.payload = serde_json::to_vec(payload).map_err(Error::PayloadError)?;
+        self.job.payload_codec = Cow::Borrowed(JsonCodec::NAME);
         Ok(self)
     }
 
+    /// Serialize the payload of the job using `codec`.
+    pub fn payload_with<T: ?Sized + serde::Serialize>(
+        mut self,
+        codec: impl PayloadCodec,
+        payload: &T,
+    ) -> Result<Self> {
+        self.job.payload = codec.encode(payload)?;
+        self.job.payload_codec = Cow::Borrowed(codec.name());
+        Ok(self)
+    }
+
+    /// Record that a payload set with [JobBuilder::payload] was encoded with `codec`.
+    pub fn payload_codec(mut self, codec: impl PayloadCodec) -> Self {
+        self.job.payload_codec = Cow::Borrowed(codec.name());
+        self
+    }
+
+    /// Set the version of the payload's format. This should match the
+    /// [payload version](crate::JobRunnerBuilder::payload_version) of the job's runner at the time
+    /// the job is submitted.
+    pub fn payload_version(mut self, version: u32) -> Self {
+        self.job.payload_version = version;
+        self
+    }
+
+    pub(crate) fn payload_codec_name(mut self, codec: String) -> Self {
+        self.job.payload_codec = Cow::Owned(codec);
+        self
+    }
+
+    /// Set a payload that was already processed for storage, such as from a recurring job's
+    /// template.
+    pub(crate) fn stored_payload(mut self, payload: Vec<u8>, storage: PayloadStorage) -> Self {
+        self.job.payload = payload;
+        self.job.payload_storage = Some(storage);
+        self
+    }
+
     /// Configure all of the retry behavior of the job.
     pub fn retries(mut self, retries: Retries) -> Self {
         self.job.retries = retries;
@@ -187,6 +363,12 @@ impl JobBuilder {
         self
     }
 
+    /// Set the maximum number of times the job can be snoozed. The default is 10.
+    pub fn max_snoozes(mut self, max_snoozes: u32) -> Self {
+        self.job.max_snoozes = max_snoozes;
+        self
+    }
+
     /// Set the initial backoff interval for the job. See [Retries::backoff_initial_interval] for more details
     pub fn backoff_initial_interval(mut self, backoff_initial_interval: Duration) -> Self {
         self.job.retries.backoff_initial_interval = backoff_initial_interval;
@@ -217,11 +399,31 @@ impl JobBuilder {
         self
     }
 
+    /// Debounce the job using `key`. If a job of the same type with the same key is waiting to
+    /// run and has not started yet, adding this job updates that job and pushes its run time
+    /// back, instead of adding a new job. The job runs once `window` has passed since it was last
+    /// added. If the existing job has already started, a new job is added as usual.
+    ///
+    /// The existing job takes all of this job's settings, including its payload, name, fairness
+    /// key, and start deadline. It keeps its own ID, and its run time only moves later.
+    pub fn debounce(mut self, key: impl ToString, window: Duration) -> Self {
+        self.job.debounce = Some(Debounce {
+            key: key.to_string(),
+            window,
+        });
+        self
+    }
+
     pub(crate) fn from_recurring(mut self, recurring_id: i64) -> Self {
         self.job.from_recurring = Some(recurring_id);
         self
     }
 
+    pub(crate) fn trace_context(mut self, trace_context: Option<String>) -> Self {
+        self.job.trace_context = trace_context;
+        self
+    }
+
     /// Build the job.
     pub fn build(self) -> Job {
         self.job
@@ -244,6 +446,14 @@ pub struct JobUpdate {
     pub run_at: Option<time::OffsetDateTime>,
     /// A new payload for the job
     pub payload: Option<Vec<u8>>,
+    /// The name of the [PayloadCodec] used to encode the new payload. If this is `None`, the job's
+    /// existing codec is kept.
+    #[serde(default)]
+    pub payload_codec: Option<Cow<'static, str>>,
+    /// The version of the new payload's format. If this is `None`, the job's existing version is
+    /// kept.
+    #[serde(default)]
+    pub payload_version: Option<u32>,
     /// When changing the payload on a job that has failed and has a checkpointed payload,
     /// set this to `true` to also update the checkpointed payload with the new one.
     /// Otherwise the original checkpointed payload remains in place and the new payload
@@ -275,6 +485,8 @@ impl JobUpdateBuilder {
                 id,
                 run_at: None,
                 payload: None,
+                payload_codec: None,
+                payload_version: None,
                 update_checkpointed_payload: false,
                 weight: None,
                 priority: None,
@@ -297,9 +509,27 @@ impl JobUpdateBuilder {
     /// Alter the job's payload, encoding the argument as JSON.
     pub fn json_payload<T: ?Sized + serde::Serialize>(mut self, payload: &T) -> Result<Self> {
         self.update.payload = Some(serde_json::to_vec(&payload).map_err(Error::PayloadError)?);
+        self.update.payload_codec = Some(Cow::Borrowed(JsonCodec::NAME));
         Ok(self)
     }
 
+    /// Alter the job's payload, encoding the argument using `codec`.
+    pub fn payload_with<T: ?Sized + serde::Serialize>(
+        mut self,
+        codec: impl PayloadCodec,
+        payload: &T,
+    ) -> Result<Self> {
+        self.update.payload = Some(codec.encode(payload)?);
+        self.update.payload_codec = Some(Cow::Borrowed(codec.name()));
+        Ok(self)
+    }
+
+    /// Set the version of the new payload's format. See [JobBuilder::payload_version].
+    pub fn payload_version(mut self, version: u32) -> Self {
+        self.update.payload_version = Some(version);
+        self
+    }
+
     /// Configure whether or not the updated payload should also update the checkpointed payload, if one exists.
     pub fn update_checkpointed_payload(mut self, update_checkpointed_payload: bool) -> Self {
         self.update.update_checkpointed_payload = update_checkpointed_payload;
@@ -330,10 +560,11 @@ impl SharedState {
         now: OffsetDateTime,
         run_time: OffsetDateTime,
         job_type: &str,
+        required_labels: &Labels,
     ) {
         if run_time <= now {
             let workers = self.workers.read().await;
-            workers.new_job_available(job_type);
+            workers.new_job_available(job_type, required_labels);
         } else {
             let mut job_type = SmartString::from(job_type);
             job_type.shrink_to_fit();
@@ -345,10 +576,37 @@ impl SharedState {
         }
     }
 
+    /// Compress and encrypt the job's payload, if that has not been done already.
+    pub(crate) fn store_job_payload(&self, job: &mut Job) -> Result<()> {
+        match &mut job.payload_storage {
+            None => {
+                let (payload, storage) = self.store_payload(std::mem::take(&mut job.payload))?;
+                job.payload = payload;
+                job.payload_storage = Some(storage);
+            }
+            Some(PayloadStorage {
+                blob: Some(hash),
+                blob_hold: hold @ None,
+                ..
+            }) => {
+                // The payload was copied from another job, so make sure the blob stays around
+                // until this job is saved.
+                *hold = Some(Arc::new(self.blob_store.hold(hash)));
+            }
+            Some(_) => {}
+        }
+
+        Ok(())
+    }
+
     /// Submit a job to the queue
-    pub(crate) async fn add_job(&self, job_config: Job) -> Result<Uuid> {
+    pub(crate) async fn add_job(&self, mut job_config: Job) -> Result<Uuid> {
         let job_type = job_config.job_type.clone();
+        let required_labels = job_config.required_labels.clone();
         let now = self.time.now();
+        job_config.apply_debounce_window(now);
+        job_config.capture_trace_context();
+        self.store_job_payload(&mut job_config)?;
         let run_time = job_config.run_at.unwrap_or(now);
 
         let (result_tx, result_rx) = tokio::sync::oneshot::channel();
@@ -371,27 +629,39 @@ impl SharedState {
             .await
             .map_err(|_| Error::QueueClosed)?;
         let ids = result_rx.await.map_err(|_| Error::QueueClosed)??;
+        jobs_enqueued(&job_type, 1);
 
-        self.notify_for_job_type(now, run_time, &job_type).await;
+        self.notify_for_job_type(now, run_time, &job_type, &required_labels)
+            .await;
 
         Ok(ids)
     }
 
     /// Submit multiple jobs to the queue
     #[instrument(skip(self))]
-    pub async fn add_jobs(&self, jobs: Vec<Job>) -> Result<Vec<Uuid>> {
-        let mut ready_job_types: HashSet<String> = HashSet::default();
+    pub async fn add_jobs(&self, mut jobs: Vec<Job>) -> Result<Vec<Uuid>> {
+        let mut ready_job_types: HashSet<(String, Labels)> = HashSet::default();
         let mut pending_job_types: HashMap<String, i64> = HashMap::default();
+        let mut job_type_counts: HashMap<Cow<'static, str>, u64> = HashMap::default();
 
         let now = self.time.now();
         let now_ts = now.unix_timestamp();
-        for job_config in &jobs {
+        for job_config in &mut jobs {
+            job_config.apply_debounce_window(now);
+            job_config.capture_trace_context();
+            self.store_job_payload(job_config)?;
+            *job_type_counts
+                .entry(job_config.job_type.clone())
+                .or_default() += 1;
             let run_time = job_config
                 .run_at
                 .map(|t| t.unix_timestamp())
                 .unwrap_or(now_ts);
             if run_time <= now_ts {
-                ready_job_types.insert(job_config.job_type.to_string());
+                ready_job_types.insert((
+                    job_config.job_type.to_string(),
+                    job_config.required_labels.clone(),
+                ));
             } else {
                 pending_job_types
                     .entry(job_config.job_type.to_string())
@@ -419,6 +689,9 @@ impl SharedState {
             .await
             .map_err(|_| Error::QueueClosed)?;
         let AddMultipleJobsResult { ids } = result_rx.await.map_err(|_| Error::QueueClosed)??;
+        for (job_type, count) in job_type_counts {
+            jobs_enqueued(&job_type, count);
+        }
 
         for (job_type, job_time) in pending_job_types {
             let mut job_type = SmartString::from(job_type);
@@ -428,8 +701,8 @@ impl SharedState {
 
         if !ready_job_types.is_empty() {
             let workers = self.workers.read().await;
-            for job_type in ready_job_types {
-                workers.new_job_available(&job_type);
+            for (job_type, required_labels) in ready_job_types {
+                workers.new_job_available(&job_type, &required_labels);
             }
         }
 
@@ -451,26 +724,36 @@ impl Queue {
     /// Update some aspects of a job. Jobs can not be updated while running or after they have
     /// finished.
     #[instrument(skip(self))]
-    pub async fn update_job(&self, job: JobUpdate) -> Result<()> {
+    pub async fn update_job(&self, mut job: JobUpdate) -> Result<()> {
         let (result_tx, result_rx) = tokio::sync::oneshot::channel();
 
         let new_run_at = job.run_at;
+        let mut payload_storage = PayloadStorage::default();
+        if let Some(payload) = job.payload.take() {
+            let (payload, storage) = self.state.store_payload(payload)?;
+            job.payload = Some(payload);
+            payload_storage = storage;
+        }
 
         self.state
             .db_write_tx
             .send(DbOperation {
                 worker_id: 0,
                 span: Span::current(),
-                operation: DbOperationType::UpdateJob(UpdateJobArgs { job, result_tx }),
+                operation: DbOperationType::UpdateJob(UpdateJobArgs {
+                    job,
+                    payload_storage,
+                    result_tx,
+                }),
             })
             .await
             .map_err(|_| Error::QueueClosed)?;
-        let job_type = result_rx.await.map_err(|_| Error::QueueClosed)??;
+        let (job_type, required_labels) = result_rx.await.map_err(|_| Error::QueueClosed)??;
 
         if let Some(new_run_at) = new_run_at {
             let now = self.state.time.now();
             self.state
-                .notify_for_job_type(now, new_run_at, &job_type)
+                .notify_for_job_type(now, new_run_at, &job_type, &required_labels)
                 .await;
         }
 
@@ -478,7 +761,7 @@ impl Queue {
     }
 
     /// Cancel a job. Jobs can not be cancelled while are running or after they have
-    /// finished.
+    /// finished. If the job's payload is in the blob store, it is deleted.
     #[instrument(skip(self))]
     pub async fn cancel_job(&self, job_id: Uuid) -> Result<()> {
         let (result_tx, result_rx) = tokio::sync::oneshot::channel();
@@ -496,7 +779,8 @@ impl Queue {
             })
             .await
             .map_err(|_| Error::QueueClosed)?;
-        result_rx.await.map_err(|_| Error::QueueClosed)??;
+        let event = result_rx.await.map_err(|_| Error::QueueClosed)??;
+        run_hook(&self.state.hooks.on_cancel, || event);
         Ok(())
     }
 }
@@ -553,6 +837,124 @@ mod tests {
         assert_eq!(status.priority, 0);
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn debounce_job() {
+        let test = TestEnvironment::new().await;
+
+        // Add the jobs before starting a worker. Paused time can jump ahead to the job's run time
+        // while the test waits on the database, and the job must not start in between.
+        let mut ids = Vec::new();
+        let mut last_added = test.time.now();
+        for i in 1..=3 {
+            last_added = test.time.now();
+            let id = Job::builder("counter")
+                .json_payload(&i)
+                .expect("payload")
+                .priority(i)
+                .debounce("doc-1", Duration::from_secs(10))
+                .add_to(&test.queue)
+                .await
+                .expect("Adding job to queue");
+            ids.push(id);
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+
+        assert_eq!(
+            ids[0], ids[1],
+            "second job should be collapsed into the first"
+        );
+        assert_eq!(
+            ids[0], ids[2],
+            "third job should be collapsed into the first"
+        );
+
+        let run_at = (last_added + Duration::from_secs(10))
+            .replace_nanosecond(0)
+            .unwrap();
+        let status = test.queue.get_job_status(ids[0]).await.unwrap();
+        assert_eq!(status.run_at, Some(run_at));
+        assert_eq!(status.priority, 3, "job should take the latest priority");
+
+        let _worker = test.worker().build().await.expect("Failed to build worker");
+        let status = wait_for_job("job to succeed", &test.queue, ids[0]).await;
+        assert_eq!(status.run_info.len(), 1);
+        assert!(status.started_at.expect("started_at") >= run_at);
+        assert_eq!(
+            test.context
+                .counter
+                .load(std::sync::atomic::Ordering::Relaxed),
+            3,
+            "job should run once with the latest payload"
+        );
+    }
+
+    // Runs in real time, since paused time can jump past the deadlines while the test waits on
+    // the database.
+    #[tokio::test]
+    async fn debounce_job_with_start_deadline() {
+        let test = TestEnvironment::new().await;
+        let _worker = test.worker().build().await.expect("Failed to build worker");
+
+        // Each submission's deadline is only a little after its own run time, so the job would
+        // expire if it kept the deadline of the first submission.
+        let mut id = None;
+        for i in 1..=3 {
+            let now = test.time.now();
+            let added = Job::builder("counter")
+                .json_payload(&i)
+                .expect("payload")
+                .name(format!("job-{i}"))
+                .debounce("doc-1", Duration::from_secs(3))
+                .expire_if_not_started_by(now + Duration::from_secs(5))
+                .add_to(&test.queue)
+                .await
+                .expect("Adding job to queue");
+            assert_eq!(*id.get_or_insert(added), added);
+            if i < 3 {
+                tokio::time::sleep(Duration::from_millis(1500)).await;
+            }
+        }
+
+        let id = id.unwrap();
+        let status = test.queue.get_job_status(id).await.unwrap();
+        assert_eq!(status.name.as_deref(), Some("job-3"));
+
+        let status = wait_for_job("job to succeed", &test.queue, id).await;
+        assert_eq!(status.run_info.len(), 1);
+        assert_eq!(
+            test.context
+                .counter
+                .load(std::sync::atomic::Ordering::Relaxed),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn debounce_after_job_started() {
+        let test = TestEnvironment::new().await;
+        let _worker = test.worker().build().await.expect("Failed to build worker");
+
+        let first = Job::builder("sleep")
+            .json_payload(&600000)
+            .expect("payload")
+            .debounce("doc-1", Duration::ZERO)
+            .add_to(&test.queue)
+            .await
+            .expect("adding job");
+
+        wait_for_job_status("job to start", &test.queue, first, JobState::Running).await;
+
+        let second = Job::builder("sleep")
+            .json_payload(&1)
+            .expect("payload")
+            .debounce("doc-1", Duration::ZERO)
+            .add_to(&test.queue)
+            .await
+            .expect("adding job");
+
+        assert_ne!(first, second, "running job should not be debounced");
+    }
+
     #[tokio::test]
     async fn full_update_job() {
         let test = TestEnvironment::new().await;
@@ -0,0 +1,454 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use rusqlite::{Connection, OptionalExtension};
+use uuid::Uuid;
+
+use crate::{shared_state::SharedStateData, Error, Result};
+
+/// Stores large payloads as files in a directory next to the database, so that they don't have
+/// to be read every time the jobs table is queried. Files are named by the BLAKE3 hash of their
+/// contents, so jobs with identical payloads share a file.
+#[derive(Debug)]
+pub(crate) struct BlobStore {
+    dir: PathBuf,
+    /// Payloads at least this large are stored as blobs. If `None`, new payloads are always
+    /// stored in the database, but existing blobs can still be read.
+    threshold: Option<usize>,
+    /// Blobs which are in use but may not be referenced from the database yet, either because
+    /// the job that references them hasn't been written yet or because a running job hasn't
+    /// loaded its payload. These are never garbage collected.
+    held: Mutex<HashMap<String, usize>>,
+    /// Blobs that were unused when the database writer last checked them. They are deleted once
+    /// the writer's transaction commits, so that a rollback never leaves a job without its
+    /// payload.
+    pending_removal: Mutex<Vec<String>>,
+}
+
+/// Prevents a blob from being garbage collected while it exists.
+#[derive(Debug)]
+pub(crate) struct BlobHold {
+    store: Arc<BlobStore>,
+    hash: String,
+}
+
+impl Drop for BlobHold {
+    fn drop(&mut self) {
+        let mut held = self.store.held.lock().unwrap();
+        if let Some(count) = held.get_mut(&self.hash) {
+            *count -= 1;
+            if *count == 0 {
+                held.remove(&self.hash);
+            }
+        }
+    }
+}
+
+/// A running job's payload that is in the blob store. The payload is read the first time that it
+/// is used, instead of when the job starts.
+#[derive(Debug)]
+pub(crate) struct BlobPayload {
+    hold: BlobHold,
+    compression: Option<String>,
+    key_id: Option<String>,
+    loaded: OnceLock<Vec<u8>>,
+}
+
+impl BlobPayload {
+    pub fn new(hold: BlobHold, compression: Option<String>, key_id: Option<String>) -> Self {
+        Self {
+            hold,
+            compression,
+            key_id,
+            loaded: OnceLock::new(),
+        }
+    }
+
+    /// Load the payload, or return it if it has already been loaded.
+    pub fn get(&self, state: &SharedStateData) -> Result<&[u8]> {
+        if let Some(payload) = self.loaded.get() {
+            return Ok(payload);
+        }
+
+        let payload = state.load_payload(
+            self.compression.as_deref(),
+            self.key_id.as_deref(),
+            Some(&self.hold.hash),
+            Vec::new(),
+        )?;
+        Ok(self.loaded.get_or_init(|| payload))
+    }
+}
+
+impl BlobStore {
+    /// Create a store for the database at `db_path`. Blobs are stored in a directory with the
+    /// same name as the database file, plus `-blobs`.
+    pub fn new(db_path: &Path, threshold: Option<usize>) -> Self {
+        let mut dir = db_path.as_os_str().to_owned();
+        dir.push("-blobs");
+        Self {
+            dir: PathBuf::from(dir),
+            threshold,
+            held: Mutex::new(HashMap::new()),
+            pending_removal: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns true if a payload of this size should be stored as a blob.
+    pub fn should_store(&self, len: usize) -> bool {
+        self.threshold.is_some_and(|t| len >= t)
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        // Spread the files across subdirectories to avoid having too many in one directory.
+        self.dir.join(&hash[0..2]).join(hash)
+    }
+
+    fn io_error(&self, e: std::io::Error) -> Error {
+        Error::BlobStore(self.dir.display().to_string(), e)
+    }
+
+    /// Keep the blob with this hash from being garbage collected until the returned value is
+    /// dropped.
+    pub fn hold(self: &Arc<Self>, hash: &str) -> BlobHold {
+        *self
+            .held
+            .lock()
+            .unwrap()
+            .entry(hash.to_string())
+            .or_default() += 1;
+        BlobHold {
+            store: self.clone(),
+            hash: hash.to_string(),
+        }
+    }
+
+    /// Write a blob, returning its hash and a hold that keeps it from being garbage collected
+    /// before the job that references it is saved.
+    pub fn write(self: &Arc<Self>, data: &[u8]) -> Result<(String, BlobHold)> {
+        let hash = blake3::hash(data).to_hex().to_string();
+        let hold = self.hold(&hash);
+
+        let path = self.path(&hash);
+        if !path.exists() {
+            let parent = path.parent().expect("blob path has a parent");
+            fs::create_dir_all(parent).map_err(|e| self.io_error(e))?;
+
+            // Write to a temporary file first so that a partially-written blob is never visible.
+            let temp_path = parent.join(format!("{hash}.{}.tmp", Uuid::now_v7()));
+            fs::write(&temp_path, data).map_err(|e| self.io_error(e))?;
+            fs::rename(&temp_path, &path).map_err(|e| self.io_error(e))?;
+        }
+
+        Ok((hash, hold))
+    }
+
+    /// Read the blob with the given hash.
+    pub fn read(&self, hash: &str) -> Result<Vec<u8>> {
+        fs::read(self.path(hash)).map_err(|e| self.io_error(e))
+    }
+
+    fn is_unused(
+        &self,
+        held: &HashMap<String, usize>,
+        conn: &Connection,
+        hash: &str,
+    ) -> Result<bool> {
+        if held.contains_key(hash) {
+            return Ok(false);
+        }
+
+        let mut stmt = conn.prepare_cached("SELECT 1 FROM jobs WHERE payload_blob = ? LIMIT 1")?;
+        let referenced = stmt.query_row([hash], |_| Ok(())).optional()?.is_some();
+        Ok(!referenced)
+    }
+
+    /// Mark a blob for deletion if it is not held and no job references it, returning true if it
+    /// was marked. The file is deleted by [BlobStore::finish_removals] after the transaction
+    /// commits. This must be called from the database writer, so that no new references are
+    /// added while it runs.
+    pub fn remove_if_unused(&self, tx: &Connection, hash: &str) -> Result<bool> {
+        let held = self.held.lock().unwrap();
+        if !self.is_unused(&held, tx, hash)? {
+            return Ok(false);
+        }
+
+        self.pending_removal.lock().unwrap().push(hash.to_string());
+        Ok(true)
+    }
+
+    /// Delete the blobs marked by [BlobStore::remove_if_unused], returning the number that were
+    /// deleted. Each blob is checked again first, since the savepoint that marked it may have
+    /// been rolled back. This must be called from the database writer after its transaction
+    /// commits.
+    pub fn finish_removals(&self, conn: &Connection) -> Result<usize> {
+        let pending = std::mem::take(&mut *self.pending_removal.lock().unwrap());
+
+        let held = self.held.lock().unwrap();
+        let mut count = 0;
+        for hash in pending {
+            if !self.is_unused(&held, conn, &hash)? {
+                continue;
+            }
+
+            match fs::remove_file(self.path(&hash)) {
+                Ok(()) => count += 1,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(self.io_error(e)),
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        test_util::{wait_for_job, TestContext, TestEnvironment},
+        Job, JobRunner, JobUpdate, RecurringJobSchedule,
+    };
+
+    #[test]
+    fn write_and_read() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(BlobStore::new(&dir.child("test.sqlite"), Some(10)));
+        assert!(!store.should_store(9));
+        assert!(store.should_store(10));
+
+        let (hash, hold) = store.write(b"some data").unwrap();
+        let (same_hash, _hold2) = store.write(b"some data").unwrap();
+        assert_eq!(hash, same_hash);
+        assert_eq!(store.read(&hash).unwrap(), b"some data");
+        assert_eq!(store.held.lock().unwrap().get(&hash), Some(&2));
+
+        drop(hold);
+        assert_eq!(store.held.lock().unwrap().get(&hash), Some(&1));
+    }
+
+    #[test]
+    fn removal_waits_for_commit() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(BlobStore::new(&dir.child("test.sqlite"), Some(10)));
+        let (hash, hold) = store.write(b"some data").unwrap();
+        drop(hold);
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE jobs (payload_blob TEXT)", [])
+            .unwrap();
+        conn.execute("INSERT INTO jobs (payload_blob) VALUES (?)", [&hash])
+            .unwrap();
+
+        // Marking a blob inside a transaction that is rolled back must not delete it.
+        let tx = conn.transaction().unwrap();
+        tx.execute("UPDATE jobs SET payload_blob = NULL", [])
+            .unwrap();
+        assert!(store.remove_if_unused(&tx, &hash).unwrap());
+        tx.rollback().unwrap();
+        assert_eq!(store.finish_removals(&conn).unwrap(), 0);
+        assert!(store.path(&hash).exists());
+
+        let tx = conn.transaction().unwrap();
+        tx.execute("UPDATE jobs SET payload_blob = NULL", [])
+            .unwrap();
+        assert!(store.remove_if_unused(&tx, &hash).unwrap());
+        tx.commit().unwrap();
+        assert_eq!(store.finish_removals(&conn).unwrap(), 1);
+        assert!(!store.path(&hash).exists());
+    }
+
+    async fn payload_blob(test: &TestEnvironment, job_id: uuid::Uuid) -> Option<String> {
+        let conn = test.queue.state.read_conn_pool.get().await.unwrap();
+        conn.interact(move |db| {
+            db.query_row(
+                "SELECT payload_blob FROM jobs WHERE external_id = ?",
+                [job_id],
+                |row| row.get(0),
+            )
+        })
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn large_payload() {
+        let mut test = TestEnvironment::with_options(|options| options.blob_threshold(100)).await;
+
+        let large = "a large payload ".repeat(100);
+        let job_def =
+            JobRunner::builder("blob_job", |job, _context: Arc<TestContext>| async move {
+                let payload = job.json_payload::<String>().unwrap();
+                // Only payloads over the threshold are read from the blob store.
+                assert_eq!(job.blob_payload.is_some(), payload.len() > 100);
+                Ok::<_, String>(payload.len())
+            })
+            .build();
+        test.registry.add(&job_def);
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let job_id = Job::builder("blob_job")
+            .json_payload(&large)
+            .unwrap()
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+
+        let status = wait_for_job("job to run", &test.queue, job_id).await;
+        assert_eq!(status.payload, serde_json::to_vec(&large).unwrap());
+        assert_eq!(status.run_info[0].info.get(), large.len().to_string());
+
+        let hash = payload_blob(&test, job_id)
+            .await
+            .expect("payload is a blob");
+        assert!(test.queue.state.blob_store.path(&hash).exists());
+
+        // Small payloads are still stored in the database.
+        let small_id = Job::builder("blob_job")
+            .json_payload(&"small")
+            .unwrap()
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        wait_for_job("small job to run", &test.queue, small_id).await;
+        assert_eq!(payload_blob(&test, small_id).await, None);
+    }
+
+    #[tokio::test]
+    async fn cancel_keeps_blob() {
+        let test = TestEnvironment::with_options(|options| options.blob_threshold(100)).await;
+        let blobs = test.queue.state.blob_store.clone();
+
+        let large = "a large payload ".repeat(100);
+        let add_job = || {
+            Job::builder("blob_job")
+                .json_payload(&large)
+                .unwrap()
+                .run_at(test.time.now() + Duration::from_secs(1000))
+                .add_to(&test.queue)
+        };
+        let first_id = add_job().await.expect("failed to add job");
+        let second_id = add_job().await.expect("failed to add job");
+
+        // Both jobs have the same payload, so they share a blob.
+        let hash = payload_blob(&test, first_id)
+            .await
+            .expect("payload is a blob");
+        assert_eq!(payload_blob(&test, second_id).await, Some(hash.clone()));
+
+        // Cancelled jobs keep their payload, like jobs with a payload in the database.
+        test.queue.cancel_job(first_id).await.unwrap();
+        test.queue.cancel_job(second_id).await.unwrap();
+        assert_eq!(payload_blob(&test, first_id).await, Some(hash.clone()));
+        assert!(blobs.path(&hash).exists());
+
+        let status = test.queue.get_job_status(first_id).await.unwrap();
+        assert_eq!(status.payload, serde_json::to_vec(&large).unwrap());
+    }
+
+    #[tokio::test]
+    async fn debounce_removes_replaced_blob() {
+        let test = TestEnvironment::with_options(|options| options.blob_threshold(100)).await;
+        let blobs = test.queue.state.blob_store.clone();
+
+        let add_job = |payload: String| {
+            Job::builder("blob_job")
+                .json_payload(&payload)
+                .unwrap()
+                .debounce("key", Duration::from_secs(1000))
+                .add_to(&test.queue)
+        };
+        let job_id = add_job("a large payload ".repeat(100))
+            .await
+            .expect("failed to add job");
+        let old_hash = payload_blob(&test, job_id)
+            .await
+            .expect("payload is a blob");
+
+        let same_id = add_job("another large payload ".repeat(100))
+            .await
+            .expect("failed to add job");
+        assert_eq!(job_id, same_id);
+        let new_hash = payload_blob(&test, job_id)
+            .await
+            .expect("payload is a blob");
+        assert_ne!(old_hash, new_hash);
+        assert!(!blobs.path(&old_hash).exists());
+        assert!(blobs.path(&new_hash).exists());
+    }
+
+    #[tokio::test]
+    async fn update_removes_replaced_blob() {
+        let test = TestEnvironment::with_options(|options| options.blob_threshold(100)).await;
+        let blobs = test.queue.state.blob_store.clone();
+
+        let job_id = Job::builder("blob_job")
+            .json_payload(&"a large payload ".repeat(100))
+            .unwrap()
+            .run_at(test.time.now() + Duration::from_secs(1000))
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        let old_hash = payload_blob(&test, job_id)
+            .await
+            .expect("payload is a blob");
+
+        test.queue
+            .update_job(
+                JobUpdate::builder(job_id)
+                    .json_payload(&"small")
+                    .unwrap()
+                    .build(),
+            )
+            .await
+            .expect("failed to update job");
+        assert_eq!(payload_blob(&test, job_id).await, None);
+        assert!(!blobs.path(&old_hash).exists());
+    }
+
+    #[tokio::test]
+    async fn delete_recurring_job_removes_blob() {
+        let test = TestEnvironment::with_options(|options| options.blob_threshold(100)).await;
+        let blobs = test.queue.state.blob_store.clone();
+
+        let job = Job::builder("blob_job")
+            .json_payload(&"a large payload ".repeat(100))
+            .unwrap()
+            .build();
+        let schedule = RecurringJobSchedule::RepeatEvery {
+            interval: Duration::from_secs(1000),
+        };
+        test.queue
+            .add_recurring_job("recurring".to_string(), schedule, job, false)
+            .await
+            .expect("failed to add recurring job");
+
+        let conn = test.queue.state.read_conn_pool.get().await.unwrap();
+        let hash: String = conn
+            .interact(|db| {
+                db.query_row(
+                    "SELECT payload_blob FROM jobs WHERE payload_blob IS NOT NULL LIMIT 1",
+                    [],
+                    |row| row.get(0),
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(blobs.path(&hash).exists());
+
+        test.queue
+            .delete_recurring_job("recurring".to_string())
+            .await
+            .expect("failed to delete recurring job");
+        assert!(!blobs.path(&hash).exists());
+    }
+}
@@ -0,0 +1,252 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Error, Result};
+
+/// A format for encoding job payloads and checkpoints.
+///
+/// The name of the codec is saved with each job, so that [RunningJobData::decode_payload](crate::RunningJobData::decode_payload)
+/// can decode the payload without the job runner needing to know which codec was used to submit
+/// it. [JsonCodec] is always available. [MessagePackCodec], [CborCodec], and [BincodeCodec] are
+/// enabled by the `msgpack`, `cbor`, and `bincode` features.
+///
+/// Other codecs can implement this trait too, but [RunningJobData::decode_payload](crate::RunningJobData::decode_payload)
+/// only recognizes the built-in ones, so jobs using them should call [PayloadCodec::decode] directly.
+pub trait PayloadCodec {
+    /// The name recorded with jobs that use this codec.
+    fn name(&self) -> &'static str;
+    /// Encode a value into a payload.
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>>;
+    /// Decode a payload into a value.
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T>;
+}
+
+/// Encode payloads as JSON. This is the default for jobs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl JsonCodec {
+    pub(crate) const NAME: &'static str = "json";
+}
+
+impl PayloadCodec for JsonCodec {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(Error::PayloadError)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        serde_json::from_slice(data).map_err(Error::PayloadError)
+    }
+}
+
+/// Encode payloads as MessagePack, using `rmp-serde`. Structs are encoded as maps, so fields can
+/// be added or reordered without breaking jobs that are already in the queue.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl MessagePackCodec {
+    pub(crate) const NAME: &'static str = "msgpack";
+}
+
+#[cfg(feature = "msgpack")]
+impl PayloadCodec for MessagePackCodec {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(|e| Error::PayloadCodec(Self::NAME, e.into()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(data).map_err(|e| Error::PayloadCodec(Self::NAME, e.into()))
+    }
+}
+
+/// Encode payloads as CBOR, using `ciborium`.
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl CborCodec {
+    pub(crate) const NAME: &'static str = "cbor";
+}
+
+#[cfg(feature = "cbor")]
+impl PayloadCodec for CborCodec {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        ciborium::into_writer(value, &mut output)
+            .map_err(|e| Error::PayloadCodec(Self::NAME, e.into()))?;
+        Ok(output)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        ciborium::from_reader(data).map_err(|e| Error::PayloadCodec(Self::NAME, e.into()))
+    }
+}
+
+/// Encode payloads with `bincode`. This is the most compact format, but it does not store field
+/// names, so changing the payload type will break jobs already in the queue. It also can't be
+/// used with types that rely on `deserialize_any`, such as `serde_json::Value` or untagged enums.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl BincodeCodec {
+    pub(crate) const NAME: &'static str = "bincode";
+}
+
+#[cfg(feature = "bincode")]
+impl PayloadCodec for BincodeCodec {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| Error::PayloadCodec(Self::NAME, e))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        bincode::deserialize(data).map_err(|e| Error::PayloadCodec(Self::NAME, e))
+    }
+}
+
+/// Decode a payload with the codec that has the given name.
+pub(crate) fn decode_with_codec<T: DeserializeOwned>(codec: &str, data: &[u8]) -> Result<T> {
+    match codec {
+        JsonCodec::NAME => JsonCodec.decode(data),
+        #[cfg(feature = "msgpack")]
+        MessagePackCodec::NAME => MessagePackCodec.decode(data),
+        #[cfg(feature = "cbor")]
+        CborCodec::NAME => CborCodec.decode(data),
+        #[cfg(feature = "bincode")]
+        BincodeCodec::NAME => BincodeCodec.decode(data),
+        _ => Err(Error::UnknownPayloadCodec(codec.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Payload {
+        name: String,
+        data: Vec<u8>,
+        count: Option<u32>,
+    }
+
+    fn round_trip(codec: impl PayloadCodec) {
+        let payload = Payload {
+            name: "a job".to_string(),
+            data: vec![1, 2, 3],
+            count: Some(5),
+        };
+
+        let encoded = codec.encode(&payload).expect("encoding");
+        let decoded: Payload = decode_with_codec(codec.name(), &encoded).expect("decoding");
+        assert_eq!(decoded, payload);
+    }
+
+    #[test]
+    fn json() {
+        round_trip(JsonCodec);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack() {
+        round_trip(MessagePackCodec);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        round_trip(CborCodec);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode() {
+        round_trip(BincodeCodec);
+    }
+
+    /// The payload and the checkpoint can use different codecs, and each run decodes whichever
+    /// one it gets with the right codec.
+    #[cfg(feature = "msgpack")]
+    #[tokio::test]
+    async fn checkpoint_with_other_codec() {
+        use std::{sync::Arc, time::Duration};
+
+        use crate::{
+            test_util::{wait_for_job, TestContext, TestEnvironment},
+            Job, JobRunner,
+        };
+
+        let mut test = TestEnvironment::new().await;
+        let job_def =
+            JobRunner::builder("codec_job", |job, _context: Arc<TestContext>| async move {
+                let payload = job.decode_payload::<Payload>().unwrap();
+                match job.current_try {
+                    0 => {
+                        assert_eq!(job.payload_codec, "msgpack");
+                        assert_eq!(payload.count, Some(1));
+                        let next = Payload {
+                            count: Some(2),
+                            ..payload
+                        };
+                        job.checkpoint_with(JsonCodec, next).await.unwrap();
+                        Err("fail")
+                    }
+                    _ => {
+                        assert_eq!(job.payload_codec, "json");
+                        assert_eq!(payload.count, Some(2));
+                        Ok("success")
+                    }
+                }
+            })
+            .build();
+
+        test.registry.add(&job_def);
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let job_id = Job::builder("codec_job")
+            .payload_with(
+                MessagePackCodec,
+                &Payload {
+                    name: "a job".to_string(),
+                    data: vec![1, 2, 3],
+                    count: Some(1),
+                },
+            )
+            .unwrap()
+            .backoff_initial_interval(Duration::from_millis(1))
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+
+        let status = wait_for_job("job to succeed", &test.queue, job_id).await;
+        assert_eq!(status.run_info.len(), 2);
+        assert_eq!(status.payload_codec, "msgpack");
+    }
+
+    #[test]
+    fn unknown_codec() {
+        let result = decode_with_codec::<String>("nope", b"\"a\"");
+        assert!(matches!(result, Err(Error::UnknownPayloadCodec(name)) if name == "nope"));
+    }
+}
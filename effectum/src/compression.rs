@@ -0,0 +1,219 @@
+#![cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+
+use crate::{Error, Result};
+
+/// An algorithm used to compress payloads. Each algorithm is enabled by the cargo feature of the
+/// same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    /// Compress with zstd at the given level. Level 3 is a good default.
+    #[cfg(feature = "zstd")]
+    Zstd {
+        /// The compression level, from 1 to 22.
+        level: i32,
+    },
+    /// Compress with lz4, which is faster than zstd but does not compress as well.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+#[cfg(feature = "zstd")]
+const ZSTD: &str = "zstd";
+#[cfg(feature = "lz4")]
+const LZ4: &str = "lz4";
+
+impl CompressionAlgorithm {
+    /// The name recorded in the database for payloads compressed with this algorithm.
+    fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd { .. } => ZSTD,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => LZ4,
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd { level } => {
+                zstd::bulk::compress(data, level).map_err(|e| Error::Compression(ZSTD, e.into()))
+            }
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+}
+
+/// Compress payloads and checkpoints before writing them to the database. Payloads are
+/// decompressed automatically when they are read, so this can be turned on or off at any time
+/// without affecting existing jobs.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// The algorithm to compress with.
+    pub algorithm: CompressionAlgorithm,
+    /// Payloads smaller than this many bytes are stored uncompressed.
+    pub threshold: usize,
+}
+
+impl Compression {
+    /// Compress payloads of at least `threshold` bytes with `algorithm`.
+    pub fn new(algorithm: CompressionAlgorithm, threshold: usize) -> Self {
+        Self {
+            algorithm,
+            threshold,
+        }
+    }
+
+    /// Compress a payload if it is large enough, returning the payload to store and the
+    /// algorithm used, if any.
+    pub(crate) fn compress(&self, payload: Vec<u8>) -> Result<(Vec<u8>, Option<&'static str>)> {
+        if payload.len() < self.threshold {
+            return Ok((payload, None));
+        }
+
+        let compressed = self.algorithm.compress(&payload)?;
+        if compressed.len() < payload.len() {
+            Ok((compressed, Some(self.algorithm.name())))
+        } else {
+            // Don't bother storing the compressed version if it didn't help.
+            Ok((payload, None))
+        }
+    }
+}
+
+/// Decompress a payload read from the database, using the algorithm recorded with it.
+pub(crate) fn decompress(algorithm: Option<&str>, data: Vec<u8>) -> Result<Vec<u8>> {
+    match algorithm {
+        None => Ok(data),
+        #[cfg(feature = "zstd")]
+        Some(ZSTD) => zstd::stream::decode_all(data.as_slice())
+            .map_err(|e| Error::Compression(ZSTD, e.into())),
+        #[cfg(feature = "lz4")]
+        Some(LZ4) => lz4_flex::decompress_size_prepended(&data)
+            .map_err(|e| Error::Compression(LZ4, e.into())),
+        Some(other) => Err(Error::UnknownCompression(other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncompressed() {
+        assert_eq!(decompress(None, vec![1, 2, 3]).unwrap(), vec![1, 2, 3]);
+        assert!(matches!(
+            decompress(Some("brotli"), vec![1, 2, 3]),
+            Err(Error::UnknownCompression(name)) if name == "brotli"
+        ));
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    fn round_trip(algorithm: CompressionAlgorithm) {
+        let compression = Compression::new(algorithm, 100);
+
+        let small = b"small".to_vec();
+        let (stored, used) = compression.compress(small.clone()).unwrap();
+        assert_eq!(used, None);
+        assert_eq!(stored, small);
+
+        let large = "a large payload ".repeat(100).into_bytes();
+        let (stored, used) = compression.compress(large.clone()).unwrap();
+        assert_eq!(used, Some(algorithm.name()));
+        assert!(stored.len() < large.len());
+        assert_eq!(decompress(used, stored).unwrap(), large);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() {
+        round_trip(CompressionAlgorithm::Zstd { level: 3 });
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4() {
+        round_trip(CompressionAlgorithm::Lz4);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn compressed_job() {
+        use std::{sync::Arc, time::Duration};
+
+        use crate::{
+            test_util::{wait_for_job, TestContext, TestEnvironment},
+            Job, JobRunner,
+        };
+
+        let mut test = TestEnvironment::with_options(|options| {
+            options.compression(Compression::new(
+                CompressionAlgorithm::Zstd { level: 3 },
+                100,
+            ))
+        })
+        .await;
+
+        let large = "a large payload ".repeat(100);
+        let checkpoint = "a large checkpoint ".repeat(100);
+        let job_def = JobRunner::builder("compressed_job", {
+            let large = large.clone();
+            let checkpoint = checkpoint.clone();
+            move |job, _context: Arc<TestContext>| {
+                let large = large.clone();
+                let checkpoint = checkpoint.clone();
+                async move {
+                    let payload = job.json_payload::<String>().unwrap();
+                    if job.current_try == 0 {
+                        assert_eq!(payload, large);
+                        job.checkpoint_json(&checkpoint).await.unwrap();
+                        Err("fail")
+                    } else {
+                        assert_eq!(payload, checkpoint);
+                        Ok("success")
+                    }
+                }
+            }
+        })
+        .build();
+
+        test.registry.add(&job_def);
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let job_id = Job::builder("compressed_job")
+            .json_payload(&large)
+            .unwrap()
+            .backoff_initial_interval(Duration::from_millis(1))
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+
+        let status = wait_for_job("job to succeed", &test.queue, job_id).await;
+        assert_eq!(status.run_info.len(), 2);
+        assert_eq!(status.payload, serde_json::to_vec(&large).unwrap());
+
+        let conn = test.queue.state.read_conn_pool.get().await.unwrap();
+        let stored = conn
+            .interact(move |db| {
+                db.query_row(
+                    "SELECT payload_compression, checkpoint_compression, length(payload)
+                    FROM jobs WHERE external_id = ?",
+                    [job_id],
+                    |row| {
+                        Ok((
+                            row.get::<_, Option<String>>(0)?,
+                            row.get::<_, Option<String>>(1)?,
+                            row.get::<_, usize>(2)?,
+                        ))
+                    },
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.0.as_deref(), Some("zstd"));
+        assert_eq!(stored.1.as_deref(), Some("zstd"));
+        assert!(stored.2 < large.len());
+    }
+}
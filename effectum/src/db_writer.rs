@@ -7,25 +7,34 @@ use self::{
     add_job::{add_job, add_jobs, AddJobArgs, AddMultipleJobsArgs, AddMultipleJobsResult},
     cancel_job::{cancel_job, CancelJobArgs},
     complete::{complete_job, CompleteJobArgs},
+    expire::{expire_jobs, ExpireJobsArgs},
     heartbeat::{write_checkpoint, write_heartbeat, WriteCheckpointArgs, WriteHeartbeatArgs},
     ready_jobs::{get_ready_jobs, GetReadyJobsArgs, ReadyJob},
     recurring::{
         add_recurring_job, delete_recurring_job, AddRecurringJobArgs, AddRecurringJobResult,
         DeleteRecurringJobArgs,
     },
+    reencrypt::{reencrypt_jobs, ReencryptJobsArgs},
     retry::{retry_job, RetryJobArgs},
+    snooze::{snooze_job, SnoozeJobArgs},
     update_job::{update_job, UpdateJobArgs},
 };
-use crate::{error::Result, shared_state::SharedState, worker::log_error};
+use crate::{
+    error::Result, hooks::JobHookEvent, labels::Labels, queue_metrics::db_writer_batch,
+    shared_state::SharedState, worker::log_error,
+};
 
 pub(crate) mod add_job;
 pub(crate) mod cancel_job;
 pub(crate) mod complete;
+pub(crate) mod expire;
 pub(crate) mod heartbeat;
 pub(crate) mod job_recovery;
 pub(crate) mod ready_jobs;
 pub(crate) mod recurring;
+pub(crate) mod reencrypt;
 pub(crate) mod retry;
+pub(crate) mod snooze;
 pub(crate) mod update_job;
 
 pub(crate) use job_recovery::handle_active_jobs_at_startup;
@@ -46,6 +55,7 @@ pub(crate) enum DbOperationType {
     Close,
     CompleteJob(CompleteJobArgs),
     RetryJob(RetryJobArgs),
+    SnoozeJob(SnoozeJobArgs),
     GetReadyJobs(GetReadyJobsArgs),
     WriteCheckpoint(WriteCheckpointArgs),
     WriteHeartbeat(WriteHeartbeatArgs),
@@ -55,6 +65,8 @@ pub(crate) enum DbOperationType {
     CancelJob(CancelJobArgs),
     AddRecurringJob(AddRecurringJobArgs),
     DeleteRecurringJob(DeleteRecurringJobArgs),
+    ExpireJobs(ExpireJobsArgs),
+    ReencryptJobs(ReencryptJobsArgs),
 }
 
 struct OperationResult<T> {
@@ -69,11 +81,13 @@ enum DbOperationResult {
     GetReadyJobs(OperationResult<Vec<ReadyJob>>),
     AddJob(OperationResult<Uuid>),
     AddMultipleJobs(OperationResult<AddMultipleJobsResult>),
-    UpdateJob(OperationResult<String>),
+    UpdateJob(OperationResult<(String, Labels)>),
     CompleteJob(OperationResult<Option<OffsetDateTime>>),
-    CancelJob(OperationResult<()>),
+    CancelJob(OperationResult<JobHookEvent>),
     DeleteRecurringJob(OperationResult<()>),
     AddRecurringJob(OperationResult<AddRecurringJobResult>),
+    ExpireJobs(OperationResult<usize>),
+    ReencryptJobs(OperationResult<usize>),
 }
 
 impl DbOperationResult {
@@ -90,6 +104,8 @@ impl DbOperationResult {
             DbOperationResult::CancelJob(result) => result.result.is_ok(),
             DbOperationResult::DeleteRecurringJob(result) => result.result.is_ok(),
             DbOperationResult::AddRecurringJob(result) => result.result.is_ok(),
+            DbOperationResult::ExpireJobs(result) => result.result.is_ok(),
+            DbOperationResult::ReencryptJobs(result) => result.result.is_ok(),
         }
     }
 
@@ -126,6 +142,12 @@ impl DbOperationResult {
             DbOperationResult::AddRecurringJob(result) => {
                 result.result_tx.send(result.result).ok();
             }
+            DbOperationResult::ExpireJobs(result) => {
+                result.result_tx.send(result.result).ok();
+            }
+            DbOperationResult::ReencryptJobs(result) => {
+                result.result_tx.send(result.result).ok();
+            }
         };
     }
 }
@@ -149,6 +171,7 @@ fn process_operations(
                 let result = match op.operation {
                     DbOperationType::CompleteJob(args) => complete_job(&sp, op.worker_id, args),
                     DbOperationType::RetryJob(args) => retry_job(&sp, op.worker_id, args),
+                    DbOperationType::SnoozeJob(args) => snooze_job(&sp, op.worker_id, args),
                     DbOperationType::GetReadyJobs(args) => {
                         get_ready_jobs(&sp, state, op.worker_id, args)
                     }
@@ -158,12 +181,16 @@ fn process_operations(
                     DbOperationType::WriteHeartbeat(args) => {
                         write_heartbeat(&sp, op.worker_id, args)
                     }
-                    DbOperationType::AddJob(args) => add_job(&sp, args),
-                    DbOperationType::AddMultipleJobs(args) => add_jobs(&sp, args),
-                    DbOperationType::UpdateJob(args) => update_job(&sp, args),
+                    DbOperationType::AddJob(args) => add_job(&sp, state, args),
+                    DbOperationType::AddMultipleJobs(args) => add_jobs(&sp, state, args),
+                    DbOperationType::UpdateJob(args) => update_job(&sp, state, args),
                     DbOperationType::CancelJob(args) => cancel_job(&sp, args),
-                    DbOperationType::AddRecurringJob(args) => add_recurring_job(&sp, args),
-                    DbOperationType::DeleteRecurringJob(args) => delete_recurring_job(&sp, args),
+                    DbOperationType::AddRecurringJob(args) => add_recurring_job(&sp, state, args),
+                    DbOperationType::DeleteRecurringJob(args) => {
+                        delete_recurring_job(&sp, state, args)
+                    }
+                    DbOperationType::ExpireJobs(args) => expire_jobs(&sp, state, args),
+                    DbOperationType::ReencryptJobs(args) => reencrypt_jobs(&sp, state, args),
                     DbOperationType::Close => {
                         closed = true;
                         DbOperationResult::Close
@@ -184,7 +211,13 @@ fn process_operations(
             }
         }
     }
+    let batch_size = results.len();
+    let commit_start = std::time::Instant::now();
     tx.commit()?;
+    db_writer_batch(batch_size, commit_start.elapsed());
+
+    // Delete blobs only now that the changes which stopped referencing them are committed.
+    log_error(state.blob_store.finish_removals(conn));
 
     for result in results {
         result.send();
@@ -1,10 +1,13 @@
-use rusqlite::{named_params, Connection, Statement};
+use rusqlite::{named_params, Connection, OptionalExtension, Statement};
 use time::OffsetDateTime;
 use tokio::sync::oneshot;
 use uuid::Uuid;
 
 use super::DbOperationResult;
-use crate::{Job, JobState, Result};
+use crate::{
+    labels::labels_to_json, resources::resources_to_json, shared_state::SharedState, Job, JobState,
+    Result,
+};
 
 pub(crate) struct AddJobArgs {
     pub job: Job,
@@ -26,18 +29,22 @@ pub(super) const INSERT_JOBS_QUERY: &str = r##"
     INSERT INTO jobs
     (external_id, job_type, name, status, priority, weight, from_base_job, orig_run_at, payload,
         max_retries, backoff_multiplier, backoff_randomization, backoff_initial_interval,
-        added_at, default_timeout, heartbeat_increment, run_info)
+        added_at, default_timeout, heartbeat_increment, manually_triggered, debounce_key,
+        start_deadline, fairness_key, max_snoozes, payload_codec, payload_compression,
+        payload_key_id, payload_blob, payload_version, trace_context, resources, required_labels, run_info)
     VALUES
     ($external_id, $job_type, $name, $status, $priority, $weight, $from_base_job, $run_at, $payload,
         $max_retries, $backoff_multiplier, $backoff_randomization, $backoff_initial_interval,
-        $added_at, $default_timeout, $heartbeat_increment, '[]')
+        $added_at, $default_timeout, $heartbeat_increment, $manually_triggered, $debounce_key,
+        $start_deadline, $fairness_key, $max_snoozes, $payload_codec, $payload_compression,
+        $payload_key_id, $payload_blob, $payload_version, $trace_context, $resources, $required_labels, '[]')
 "##;
 
 pub(super) const INSERT_ACTIVE_JOBS_QUERY: &str = r##"
     INSERT INTO active_jobs
-    (job_id,  priority, run_at)
+    (job_id,  priority, run_at, start_deadline, fairness_key)
     VALUES
-    ($job_id, $priority, $run_at)
+    ($job_id, $priority, $run_at, $start_deadline, $fairness_key)
 "##;
 
 pub(super) fn execute_add_job_stmt(
@@ -48,6 +55,7 @@ pub(super) fn execute_add_job_stmt(
     status: Option<JobState>,
 ) -> Result<(i64, Uuid)> {
     let run_time = job_config.run_at.unwrap_or(now).unix_timestamp();
+    let storage = job_config.payload_storage.as_ref();
 
     jobs_stmt.execute(named_params! {
         "$external_id": &job_config.id,
@@ -66,6 +74,19 @@ pub(super) fn execute_add_job_stmt(
         "$default_timeout" :job_config.timeout.as_secs(),
         "$heartbeat_increment": job_config.heartbeat_increment.as_secs(),
         "$added_at": now.unix_timestamp(),
+        "$manually_triggered": job_config.manually_triggered,
+        "$debounce_key": job_config.debounce.as_ref().map(|d| d.key.as_str()),
+        "$start_deadline": job_config.start_deadline.map(|t| t.unix_timestamp()),
+        "$fairness_key": job_config.fairness_key.as_deref().unwrap_or_default(),
+        "$max_snoozes": job_config.max_snoozes,
+        "$payload_codec": job_config.payload_codec,
+        "$payload_compression": storage.and_then(|s| s.compression.as_deref()),
+        "$payload_key_id": storage.and_then(|s| s.key_id.as_deref()),
+        "$payload_blob": storage.and_then(|s| s.blob.as_deref()),
+        "$payload_version": job_config.payload_version,
+        "$trace_context": job_config.trace_context,
+        "$resources": resources_to_json(&job_config.resources),
+        "$required_labels": labels_to_json(&job_config.required_labels),
     })?;
 
     let job_id = tx.last_insert_rowid();
@@ -84,35 +105,189 @@ pub(super) fn execute_add_active_job_stmt(
         "$job_id": job_id,
         "$priority": job_config.priority,
         "$run_at": run_time,
+        "$start_deadline": job_config.start_deadline.map(|t| t.unix_timestamp()),
+        "$fairness_key": job_config.fairness_key.as_deref().unwrap_or_default(),
     })?;
 
     Ok(())
 }
 
-fn do_add_job(tx: &Connection, job_config: &Job, now: OffsetDateTime) -> Result<Uuid> {
-    let mut jobs_stmt = tx.prepare_cached(INSERT_JOBS_QUERY)?;
-    let mut active_jobs_stmt = tx.prepare_cached(INSERT_ACTIVE_JOBS_QUERY)?;
+/// If the job is debounced and a job with the same key is waiting for its first run, give that
+/// job the new payload and settings and push its run time back. Returns the ID of the existing
+/// job, or `None` if a new job should be added instead.
+fn debounce_existing_job(
+    tx: &Connection,
+    state: &SharedState,
+    job_config: &Job,
+    now: OffsetDateTime,
+) -> Result<Option<Uuid>> {
+    let Some(debounce) = job_config.debounce.as_ref() else {
+        return Ok(None);
+    };
+
+    let mut find_stmt = tx.prepare_cached(
+        r##"SELECT job_id, external_id, payload_blob
+        FROM jobs
+        JOIN active_jobs USING(job_id)
+        WHERE debounce_key = $debounce_key
+            AND job_type = $job_type
+            AND active_worker_id IS NULL
+            AND active_jobs.started_at IS NULL
+        LIMIT 1"##,
+    )?;
+
+    let existing = find_stmt
+        .query_row(
+            named_params! {
+                "$debounce_key": debounce.key,
+                "$job_type": job_config.job_type,
+            },
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Uuid>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            },
+        )
+        .optional()?;
 
-    let (job_id, external_id) = execute_add_job_stmt(tx, &mut jobs_stmt, job_config, now, None)?;
+    let Some((job_id, external_id, old_blob)) = existing else {
+        return Ok(None);
+    };
 
-    execute_add_active_job_stmt(&mut active_jobs_stmt, job_id, job_config, now)?;
+    let run_time = job_config.run_at.unwrap_or(now).unix_timestamp();
+
+    let mut active_jobs_update = tx.prepare_cached(
+        r##"UPDATE active_jobs
+        SET run_at = MAX(run_at, $run_at),
+            priority = $priority,
+            start_deadline = $start_deadline,
+            fairness_key = $fairness_key
+        WHERE job_id = $job_id"##,
+    )?;
+    active_jobs_update.execute(named_params! {
+        "$job_id": job_id,
+        "$run_at": run_time,
+        "$priority": job_config.priority,
+        "$start_deadline": job_config.start_deadline.map(|t| t.unix_timestamp()),
+        "$fairness_key": job_config.fairness_key.as_deref().unwrap_or_default(),
+    })?;
+
+    let storage = job_config.payload_storage.as_ref();
+    let mut jobs_update = tx.prepare_cached(
+        r##"UPDATE jobs
+        SET name = $name,
+            payload = $payload,
+            payload_codec = $payload_codec,
+            payload_compression = $payload_compression,
+            payload_key_id = $payload_key_id,
+            payload_blob = $payload_blob,
+            payload_version = $payload_version,
+            orig_run_at = MAX(orig_run_at, $run_at),
+            priority = $priority,
+            weight = $weight,
+            default_timeout = $default_timeout,
+            heartbeat_increment = $heartbeat_increment,
+            max_retries = $max_retries,
+            backoff_multiplier = $backoff_multiplier,
+            backoff_randomization = $backoff_randomization,
+            backoff_initial_interval = $backoff_initial_interval,
+            resources = $resources,
+            required_labels = $required_labels,
+            start_deadline = $start_deadline,
+            fairness_key = $fairness_key,
+            max_snoozes = $max_snoozes,
+            trace_context = $trace_context
+        WHERE job_id = $job_id"##,
+    )?;
+    jobs_update.execute(named_params! {
+        "$job_id": job_id,
+        "$name": job_config.name,
+        "$payload": job_config.payload.as_slice(),
+        "$payload_codec": job_config.payload_codec,
+        "$payload_compression": storage.and_then(|s| s.compression.as_deref()),
+        "$payload_key_id": storage.and_then(|s| s.key_id.as_deref()),
+        "$payload_blob": storage.and_then(|s| s.blob.as_deref()),
+        "$payload_version": job_config.payload_version,
+        "$run_at": run_time,
+        "$priority": job_config.priority,
+        "$weight": job_config.weight,
+        "$default_timeout": job_config.timeout.as_secs(),
+        "$heartbeat_increment": job_config.heartbeat_increment.as_secs(),
+        "$max_retries": job_config.retries.max_retries,
+        "$backoff_multiplier": job_config.retries.backoff_multiplier,
+        "$backoff_randomization": job_config.retries.backoff_randomization,
+        "$backoff_initial_interval": job_config.retries.backoff_initial_interval.as_secs(),
+        "$resources": resources_to_json(&job_config.resources),
+        "$required_labels": labels_to_json(&job_config.required_labels),
+        "$start_deadline": job_config.start_deadline.map(|t| t.unix_timestamp()),
+        "$fairness_key": job_config.fairness_key.as_deref().unwrap_or_default(),
+        "$max_snoozes": job_config.max_snoozes,
+        "$trace_context": job_config.trace_context,
+    })?;
+
+    // The old payload may have been the last reference to its blob.
+    if let Some(old_blob) = old_blob {
+        if storage.and_then(|s| s.blob.as_deref()) != Some(old_blob.as_str()) {
+            state.blob_store.remove_if_unused(tx, &old_blob)?;
+        }
+    }
+
+    Ok(Some(external_id))
+}
+
+fn add_or_debounce_job(
+    tx: &Connection,
+    state: &SharedState,
+    jobs_stmt: &mut Statement,
+    active_jobs_stmt: &mut Statement,
+    job_config: &Job,
+    now: OffsetDateTime,
+) -> Result<Uuid> {
+    if let Some(existing_id) = debounce_existing_job(tx, state, job_config, now)? {
+        return Ok(existing_id);
+    }
+
+    let (job_id, external_id) = execute_add_job_stmt(tx, jobs_stmt, job_config, now, None)?;
+    execute_add_active_job_stmt(active_jobs_stmt, job_id, job_config, now)?;
 
     Ok(external_id)
 }
 
-pub(super) fn add_job(tx: &Connection, args: AddJobArgs) -> DbOperationResult {
+fn do_add_job(
+    tx: &Connection,
+    state: &SharedState,
+    job_config: &Job,
+    now: OffsetDateTime,
+) -> Result<Uuid> {
+    let mut jobs_stmt = tx.prepare_cached(INSERT_JOBS_QUERY)?;
+    let mut active_jobs_stmt = tx.prepare_cached(INSERT_ACTIVE_JOBS_QUERY)?;
+
+    add_or_debounce_job(
+        tx,
+        state,
+        &mut jobs_stmt,
+        &mut active_jobs_stmt,
+        job_config,
+        now,
+    )
+}
+
+pub(super) fn add_job(tx: &Connection, state: &SharedState, args: AddJobArgs) -> DbOperationResult {
     let AddJobArgs {
         job,
         now,
         result_tx,
     } = args;
 
-    let result = do_add_job(tx, &job, now);
+    let result = do_add_job(tx, state, &job, now);
     DbOperationResult::AddJob(super::OperationResult { result, result_tx })
 }
 
 fn do_add_jobs(
     tx: &Connection,
+    state: &SharedState,
     jobs: Vec<Job>,
     now: OffsetDateTime,
 ) -> Result<AddMultipleJobsResult> {
@@ -122,23 +297,31 @@ fn do_add_jobs(
     let mut active_jobs_stmt = tx.prepare_cached(INSERT_ACTIVE_JOBS_QUERY)?;
 
     for job_config in jobs {
-        let (internal, external) =
-            execute_add_job_stmt(tx, &mut jobs_stmt, &job_config, now, None)?;
-        execute_add_active_job_stmt(&mut active_jobs_stmt, internal, &job_config, now)?;
-
+        let external = add_or_debounce_job(
+            tx,
+            state,
+            &mut jobs_stmt,
+            &mut active_jobs_stmt,
+            &job_config,
+            now,
+        )?;
         ids.push(external);
     }
 
     Ok(AddMultipleJobsResult { ids })
 }
 
-pub(super) fn add_jobs(tx: &Connection, args: AddMultipleJobsArgs) -> DbOperationResult {
+pub(super) fn add_jobs(
+    tx: &Connection,
+    state: &SharedState,
+    args: AddMultipleJobsArgs,
+) -> DbOperationResult {
     let AddMultipleJobsArgs {
         jobs,
         now,
         result_tx,
     } = args;
 
-    let result = do_add_jobs(tx, jobs, now);
+    let result = do_add_jobs(tx, state, jobs, now);
     DbOperationResult::AddMultipleJobs(super::OperationResult { result, result_tx })
 }
@@ -4,25 +4,39 @@ use tokio::sync::oneshot;
 use uuid::Uuid;
 
 use super::DbOperationResult;
-use crate::{Error, Result};
+use crate::{hooks::JobHookEvent, Error, Result};
 
 pub(crate) struct CancelJobArgs {
     pub id: Uuid,
     pub now: OffsetDateTime,
-    pub result_tx: oneshot::Sender<Result<()>>,
+    pub result_tx: oneshot::Sender<Result<JobHookEvent>>,
 }
 
-fn do_cancel_job(tx: &Connection, now: OffsetDateTime, external_id: Uuid) -> Result<()> {
+fn do_cancel_job(tx: &Connection, now: OffsetDateTime, external_id: Uuid) -> Result<JobHookEvent> {
     let mut find_job_stmt = tx.prepare_cached(
-        r##"SELECT job_id, active_jobs.run_at IS NOT NULL, active_worker_id IS NOT NULL
+        r##"SELECT job_id, active_jobs.run_at IS NOT NULL, active_worker_id IS NOT NULL,
+            job_type, name, current_try
         FROM jobs
         LEFT JOIN active_jobs USING(job_id)
         WHERE external_id = ?"##,
     )?;
 
-    let (id, active, active_worker_id): (i64, bool, bool) = find_job_stmt
+    let (id, active, active_worker_id, event) = find_job_stmt
         .query_row([external_id], |row| {
-            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            let event = JobHookEvent {
+                id: external_id,
+                job_type: row.get(3)?,
+                name: row.get(4)?,
+                current_try: row.get(5)?,
+                run_info: None,
+                next_run_at: None,
+            };
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, bool>(1)?,
+                row.get::<_, bool>(2)?,
+                event,
+            ))
         })
         .optional()?
         .ok_or(Error::NotFound)?;
@@ -46,7 +60,7 @@ fn do_cancel_job(tx: &Connection, now: OffsetDateTime, external_id: Uuid) -> Res
     del_stmt.execute([id])?;
     update_stmt.execute([now.unix_timestamp(), id])?;
 
-    Ok(())
+    Ok(event)
 }
 
 pub(super) fn cancel_job(tx: &Connection, args: CancelJobArgs) -> DbOperationResult {
@@ -1,4 +1,4 @@
-use rusqlite::{named_params, params, Connection};
+use rusqlite::{named_params, params, Connection, OptionalExtension};
 use time::OffsetDateTime;
 use tokio::sync::oneshot;
 
@@ -12,7 +12,7 @@ pub(crate) struct CompleteJobArgs {
     pub run_info: String,
     pub now: i64,
     pub started_at: i64,
-    pub success: bool,
+    pub status: JobState,
     pub result_tx: oneshot::Sender<Result<Option<OffsetDateTime>>>,
 }
 
@@ -22,16 +22,17 @@ pub(super) fn do_complete_job(
     worker_id: u64,
     now: i64,
     started_at: i64,
-    success: bool,
+    status: JobState,
     this_run_info: String,
 ) -> Result<Option<OffsetDateTime>> {
-    let mut delete_stmt =
-        tx.prepare_cached(r##"DELETE FROM active_jobs WHERE job_id=?1 AND active_worker_id=?2"##)?;
+    let mut delete_stmt = tx.prepare_cached(
+        r##"DELETE FROM active_jobs WHERE job_id=?1 AND active_worker_id=?2 RETURNING run_at"##,
+    )?;
 
-    let altered = delete_stmt.execute(params![job_id, worker_id])?;
-    if altered == 0 {
-        return Err(Error::Expired);
-    }
+    let ready_at = delete_stmt
+        .query_row(params![job_id, worker_id], |row| row.get::<_, i64>(0))
+        .optional()?
+        .ok_or(Error::Expired)?;
 
     let mut stmt = tx.prepare_cached(
         r##"
@@ -39,24 +40,33 @@ pub(super) fn do_complete_job(
             status = $status,
             run_info = json_array_append(run_info, $this_run_info),
             started_at = $started_at,
-            finished_at = $now
+            finished_at = $now,
+            ready_at = $ready_at
         WHERE job_id=$job_id
-        RETURNING orig_run_at, from_base_job
+        RETURNING orig_run_at, from_base_job, manually_triggered
         "##,
     )?;
 
-    let (orig_run_at, from_recurring) = stmt.query_row(named_params! {
-        "$job_id": job_id,
-        "$now": now,
-        "$started_at": started_at,
-        "$this_run_info": this_run_info,
-        "$status": if success { JobState::Succeeded.as_str() } else { JobState::Failed.as_str() },
-    }, |row| {
-        let orig_run_at = row.get::<_, i64>(0)?;
-        let from_recurring = row.get::<_, Option<i64>>(1)?;
-        Ok((orig_run_at, from_recurring))
-    })?;
+    let (orig_run_at, from_recurring, manually_triggered) = stmt.query_row(
+        named_params! {
+            "$job_id": job_id,
+            "$now": now,
+            "$started_at": started_at,
+            "$ready_at": ready_at,
+            "$this_run_info": this_run_info,
+            "$status": status.as_str(),
+        },
+        |row| {
+            let orig_run_at = row.get::<_, i64>(0)?;
+            let from_recurring = row.get::<_, Option<i64>>(1)?;
+            let manually_triggered = row.get::<_, bool>(2)?;
+            Ok((orig_run_at, from_recurring, manually_triggered))
+        },
+    )?;
 
+    // Manually triggered runs sit outside the schedule, so the regularly scheduled job is still
+    // pending and there is nothing new to schedule here.
+    let from_recurring = from_recurring.filter(|_| !manually_triggered);
     let next_run_at = if let Some(from_recurring) = from_recurring {
         let orig_run_at = OffsetDateTime::from_unix_timestamp(orig_run_at)
             .map_err(|_| Error::TimestampOutOfRange("orig_run_at"))?;
@@ -88,10 +98,10 @@ pub(super) fn complete_job(
         run_info,
         now,
         started_at,
-        success,
+        status,
         result_tx,
     } = args;
 
-    let result = do_complete_job(tx, job_id, worker_id, now, started_at, success, run_info);
+    let result = do_complete_job(tx, job_id, worker_id, now, started_at, status, run_info);
     DbOperationResult::CompleteJob(super::OperationResult { result, result_tx })
 }
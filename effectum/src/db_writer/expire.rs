@@ -0,0 +1,114 @@
+use std::rc::Rc;
+
+use rusqlite::{named_params, types::Value, Connection};
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+
+use super::{
+    add_job::INSERT_JOBS_QUERY, recurring::schedule_next_recurring_job, DbOperationResult,
+};
+use crate::{
+    recurring::create_job_from_recurring_template, shared_state::SharedState, worker::log_error,
+    Error, JobState, Result, SmartString,
+};
+
+pub(crate) struct ExpireJobsArgs {
+    pub now: OffsetDateTime,
+    pub result_tx: oneshot::Sender<Result<usize>>,
+}
+
+/// Move pending jobs that missed their start deadline into the expired state, and schedule the
+/// next run of any recurring jobs among them. Returns the number of jobs that expired.
+pub(super) fn do_expire_jobs(
+    tx: &Connection,
+    queue: &SharedState,
+    now: OffsetDateTime,
+) -> Result<usize> {
+    let now_ts = now.unix_timestamp();
+    let mut delete_stmt = tx.prepare_cached(
+        r##"DELETE FROM active_jobs
+        WHERE active_worker_id IS NULL AND start_deadline < $now
+        RETURNING job_id"##,
+    )?;
+
+    let job_ids = delete_stmt
+        .query_map(named_params! { "$now": now_ts }, |row| {
+            row.get::<_, Value>(0)
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if job_ids.is_empty() {
+        return Ok(0);
+    }
+
+    let count = job_ids.len();
+    let mut update_stmt = tx.prepare_cached(
+        r##"UPDATE jobs
+        SET status = $status,
+            finished_at = $now
+        WHERE job_id IN rarray($job_ids)
+        RETURNING orig_run_at, from_base_job, manually_triggered"##,
+    )?;
+
+    let expired = update_stmt
+        .query_map(
+            named_params! {
+                "$status": JobState::Expired.as_str(),
+                "$now": now_ts,
+                "$job_ids": Rc::new(job_ids),
+            },
+            |row| {
+                let orig_run_at = row.get::<_, i64>(0)?;
+                let from_recurring = row.get::<_, Option<i64>>(1)?;
+                let manually_triggered = row.get::<_, bool>(2)?;
+                Ok((orig_run_at, from_recurring, manually_triggered))
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut insert_job_stmt = tx.prepare_cached(INSERT_JOBS_QUERY)?;
+    for (orig_run_at, from_recurring, manually_triggered) in expired {
+        // Manually triggered runs sit outside the schedule, so the regularly scheduled job is
+        // still pending and there is nothing new to schedule here.
+        let Some(from_recurring) = from_recurring.filter(|_| !manually_triggered) else {
+            continue;
+        };
+
+        let orig_run_at = OffsetDateTime::from_unix_timestamp(orig_run_at)
+            .map_err(|_| Error::TimestampOutOfRange("orig_run_at"))?;
+        let ids = vec![Value::from(from_recurring)];
+        let Some(job) = create_job_from_recurring_template(tx, now, orig_run_at, ids)?
+            .into_iter()
+            .next()
+        else {
+            // The recurring job was deleted.
+            continue;
+        };
+
+        let job_type = SmartString::from(job.job_type.as_ref());
+        let run_at = job.run_at;
+        schedule_next_recurring_job(tx, now, &mut insert_job_stmt, job)?;
+
+        // This runs on the database writer thread, which is outside the async runtime, so it can
+        // block until the pending jobs monitor has room.
+        if let Some(run_at) = run_at {
+            log_error(
+                queue
+                    .pending_jobs_tx
+                    .blocking_send((job_type, run_at.unix_timestamp())),
+            );
+        }
+    }
+
+    Ok(count)
+}
+
+pub(super) fn expire_jobs(
+    tx: &Connection,
+    queue: &SharedState,
+    args: ExpireJobsArgs,
+) -> DbOperationResult {
+    let ExpireJobsArgs { now, result_tx } = args;
+    let result = do_expire_jobs(tx, queue, now);
+    DbOperationResult::ExpireJobs(super::OperationResult { result, result_tx })
+}
@@ -1,7 +1,7 @@
 use rusqlite::{named_params, params, Connection, OptionalExtension};
 use tokio::sync::oneshot;
 
-use crate::Result;
+use crate::{payload_storage::PayloadStorage, Result};
 
 use super::DbOperationResult;
 
@@ -60,6 +60,12 @@ pub(crate) struct WriteCheckpointArgs {
     pub job_id: i64,
     pub new_expiration: i64,
     pub payload: Vec<u8>,
+    /// The codec used to encode the payload, or `None` to keep the current codec.
+    pub codec: Option<&'static str>,
+    /// The version of the payload's format.
+    pub version: u32,
+    /// How the payload is stored.
+    pub storage: PayloadStorage,
     pub result_tx: oneshot::Sender<Result<Option<i64>>>,
 }
 
@@ -68,7 +74,6 @@ fn do_write_checkpoint(
     job_id: i64,
     worker_id: u64,
     new_expire_time: i64,
-    payload: Vec<u8>,
 ) -> Result<Option<i64>> {
     let mut stmt = tx.prepare_cached(
         r##"UPDATE active_jobs
@@ -91,13 +96,38 @@ fn do_write_checkpoint(
         )
         .optional()?;
 
-    let mut payload_update_stmt =
-        tx.prepare_cached(r##"UPDATE jobs SET checkpointed_payload=?2 WHERE job_id=?1"##)?;
-    payload_update_stmt.execute(params![job_id, payload])?;
-
     Ok(actual_new_expire_time)
 }
 
+fn write_checkpoint_payload(
+    tx: &Connection,
+    job_id: i64,
+    payload: Vec<u8>,
+    codec: Option<&'static str>,
+    version: u32,
+    storage: PayloadStorage,
+) -> Result<()> {
+    let mut payload_update_stmt = tx.prepare_cached(
+        r##"UPDATE jobs
+            SET checkpointed_payload=?2,
+                checkpoint_codec=COALESCE(?3, checkpoint_codec),
+                checkpoint_compression=?4,
+                checkpoint_key_id=?5,
+                checkpoint_version=?6
+            WHERE job_id=?1"##,
+    )?;
+    payload_update_stmt.execute(params![
+        job_id,
+        payload,
+        codec,
+        storage.compression,
+        storage.key_id,
+        version
+    ])?;
+
+    Ok(())
+}
+
 pub(super) fn write_checkpoint(
     tx: &Connection,
     worker_id: u64,
@@ -107,9 +137,15 @@ pub(super) fn write_checkpoint(
         job_id,
         new_expiration,
         payload,
+        codec,
+        version,
+        storage,
         result_tx,
     } = args;
 
-    let result = do_write_checkpoint(tx, job_id, worker_id, new_expiration, payload);
+    let result = do_write_checkpoint(tx, job_id, worker_id, new_expiration).and_then(|expires| {
+        write_checkpoint_payload(tx, job_id, payload, codec, version, storage)?;
+        Ok(expires)
+    });
     DbOperationResult::NewExpirationResult(super::OperationResult { result, result_tx })
 }
@@ -4,7 +4,7 @@ use tracing::instrument;
 use super::{complete::do_complete_job, retry::do_retry_job};
 use crate::{
     error::Result, job::RunningJobData, shared_state::SharedState, Error, JobRecoveryBehavior,
-    RunInfo,
+    JobState, RunInfo,
 };
 
 /// Handle jobs that had been running when the process quit last time.
@@ -64,6 +64,7 @@ pub(crate) fn handle_active_jobs_at_startup(
             ) = row?;
 
             let run_info = serde_json::to_string(&RunInfo {
+                snoozed: false,
                 success: false,
                 start: time::OffsetDateTime::from_unix_timestamp(started_at)
                     .map_err(|_| Error::TimestampOutOfRange("started_at"))?,
@@ -79,7 +80,7 @@ pub(crate) fn handle_active_jobs_at_startup(
                     active_worker_id,
                     now_timestamp,
                     started_at,
-                    false,
+                    JobState::Failed,
                     run_info,
                 )?;
             } else {
@@ -1,11 +1,11 @@
 use std::{
+    collections::VecDeque,
     rc::Rc,
     sync::{
         atomic::{AtomicI64, Ordering},
-        Arc,
+        Arc, OnceLock,
     },
 };
-use log::info;
 
 use rusqlite::{named_params, types::Value, Connection};
 use time::OffsetDateTime;
@@ -13,14 +13,20 @@ use tokio::sync::Mutex;
 use tracing::{event, Level};
 use uuid::Uuid;
 
-use super::DbOperationResult;
+use super::{expire::do_expire_jobs, DbOperationResult};
 use crate::{
-    shared_state::SharedState, worker::RunningJobs, Error, Result, RunningJob, RunningJobData,
+    blob_store::BlobPayload,
+    job::RunOutcome,
+    priority_aging::DEFAULT_READY_ORDER,
+    resources::{resources_from_json, Resources},
+    shared_state::SharedState,
+    worker::RunningJobs,
+    Error, Result, RunningJob, RunningJobData,
 };
 
 pub(crate) struct ReadyJob {
     pub job: RunningJob,
-    pub done_rx: tokio::sync::watch::Receiver<bool>,
+    pub done_rx: tokio::sync::watch::Receiver<RunOutcome>,
 }
 
 pub(crate) struct GetReadyJobsArgs {
@@ -28,10 +34,155 @@ pub(crate) struct GetReadyJobsArgs {
     pub max_jobs: u32,
     pub max_concurrency: u32,
     pub running_jobs: Arc<RunningJobs>,
+    /// The worker's labels, encoded as JSON.
+    pub labels: String,
     pub now: OffsetDateTime,
     pub result_tx: tokio::sync::oneshot::Sender<Result<Vec<ReadyJob>>>,
 }
 
+#[derive(Debug)]
+struct JobResult {
+    job_id: i64,
+    external_id: Uuid,
+    name: Option<String>,
+    priority: i32,
+    weight: u16,
+    job_type: String,
+    current_try: i32,
+    payload: Option<Vec<u8>>,
+    payload_codec: String,
+    payload_compression: Option<String>,
+    payload_key_id: Option<String>,
+    payload_blob: Option<String>,
+    payload_version: u32,
+    default_timeout: i32,
+    heartbeat_increment: i32,
+    backoff_multiplier: f64,
+    backoff_randomization: f64,
+    backoff_initial_interval: i32,
+    max_retries: i32,
+    orig_run_at: i64,
+    run_at: i64,
+    trace_context: Option<String>,
+    scheduled_recurring: bool,
+    fairness_key: String,
+}
+
+impl JobResult {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(JobResult {
+            job_id: row.get(0)?,
+            external_id: row.get(1)?,
+            priority: row.get(2)?,
+            weight: row.get(3)?,
+            job_type: row.get(4)?,
+            current_try: row.get(5)?,
+            payload: row.get(6)?,
+            default_timeout: row.get(7)?,
+            heartbeat_increment: row.get(8)?,
+            backoff_multiplier: row.get(9)?,
+            backoff_randomization: row.get(10)?,
+            backoff_initial_interval: row.get(11)?,
+            max_retries: row.get(12)?,
+            orig_run_at: row.get(13)?,
+            name: row.get(14)?,
+            fairness_key: row.get(15)?,
+            payload_codec: row.get(16)?,
+            payload_compression: row.get(17)?,
+            payload_key_id: row.get(18)?,
+            payload_blob: row.get(19)?,
+            payload_version: row.get(20)?,
+            run_at: row.get(21)?,
+            trace_context: row.get(22)?,
+            scheduled_recurring: row.get(23)?,
+        })
+    }
+}
+
+/// A ready job that the worker may be able to run, with just the fields needed to decide whether
+/// it fits. The rest of the job is loaded only if it is claimed.
+#[derive(Debug)]
+struct ReadyCandidate {
+    job_id: i64,
+    weight: u16,
+    resources: Resources,
+}
+
+/// The query that finds the ready jobs for one fairness key, in the order they should run.
+pub(crate) fn candidates_query(order_by: &str) -> String {
+    format!(
+        r##"SELECT job_id, weight, jobs.resources
+            FROM active_jobs
+            JOIN jobs USING(job_id)
+            WHERE active_worker_id IS NULL
+                AND active_jobs.fairness_key = $fairness_key
+                AND run_at <= $now
+                AND job_type in rarray($job_types)
+                AND weight <= $max_concurrency
+                AND NOT EXISTS (
+                    SELECT 1 FROM json_each(jobs.resources) needed
+                    JOIN json_each($resource_capacity) capacity USING(key)
+                    WHERE needed.value > capacity.value
+                )
+                AND NOT EXISTS (
+                    SELECT 1 FROM json_each(jobs.required_labels) required
+                    WHERE required.value IS NOT (
+                        SELECT label.value FROM json_each($labels) label
+                        WHERE label.key = required.key
+                    )
+                )
+            ORDER BY {order_by}
+            LIMIT $limit"##
+    )
+}
+
+/// Find the first fairness key after `after` that has a job of one of `job_types` ready to run,
+/// or the first such key overall if `after` is `None`. Keys that only have future or
+/// other-type jobs are skipped inside the query, so they never cost a candidates query.
+fn next_fairness_key(
+    tx: &Connection,
+    after: Option<&str>,
+    job_types: &Rc<Vec<Value>>,
+    now: i64,
+) -> Result<Option<String>> {
+    let key = match after {
+        Some(after) => tx
+            .prepare_cached(
+                r##"SELECT MIN(active_jobs.fairness_key) FROM active_jobs
+                JOIN jobs USING(job_id)
+                WHERE active_worker_id IS NULL
+                    AND active_jobs.fairness_key > $after
+                    AND run_at <= $now
+                    AND job_type IN rarray($job_types)"##,
+            )?
+            .query_row(
+                named_params! {
+                    "$after": after,
+                    "$now": now,
+                    "$job_types": job_types.clone(),
+                },
+                |row| row.get(0),
+            )?,
+        None => tx
+            .prepare_cached(
+                r##"SELECT MIN(active_jobs.fairness_key) FROM active_jobs
+                JOIN jobs USING(job_id)
+                WHERE active_worker_id IS NULL
+                    AND run_at <= $now
+                    AND job_type IN rarray($job_types)"##,
+            )?
+            .query_row(
+                named_params! {
+                    "$now": now,
+                    "$job_types": job_types.clone(),
+                },
+                |row| row.get(0),
+            )?,
+    };
+
+    Ok(key)
+}
+
 fn do_get_ready_jobs(
     tx: &Connection,
     queue: &SharedState,
@@ -40,10 +191,20 @@ fn do_get_ready_jobs(
     max_jobs: u32,
     max_concurrency: u32,
     running_jobs: Arc<RunningJobs>,
+    labels: String,
     now: OffsetDateTime,
 ) -> Result<Vec<ReadyJob>> {
-    println!("Getting ready jobs");
-    let mut stmt = tx.prepare_cached(
+    // Clear out jobs that missed their start deadline so that they don't get picked up below.
+    do_expire_jobs(tx, queue, now)?;
+
+    let order_by = queue
+        .priority_aging
+        .as_ref()
+        .map(|aging| aging.order_by())
+        .unwrap_or(DEFAULT_READY_ORDER);
+    let mut candidates_stmt = tx.prepare_cached(&candidates_query(order_by))?;
+
+    let mut load_stmt = tx.prepare_cached(
         r##"SELECT job_id, external_id, active_jobs.priority, weight,
                 job_type, current_try,
                 COALESCE(checkpointed_payload, payload) as payload,
@@ -54,80 +215,112 @@ fn do_get_ready_jobs(
                 backoff_initial_interval,
                 max_retries,
                 orig_run_at,
-                jobs.name
+                jobs.name,
+                active_jobs.fairness_key,
+                CASE
+                    WHEN checkpointed_payload IS NULL THEN payload_codec
+                    ELSE COALESCE(checkpoint_codec, payload_codec)
+                END as payload_codec,
+                CASE
+                    WHEN checkpointed_payload IS NULL THEN payload_compression
+                    ELSE checkpoint_compression
+                END as payload_compression,
+                CASE
+                    WHEN checkpointed_payload IS NULL THEN payload_key_id
+                    ELSE checkpoint_key_id
+                END as payload_key_id,
+                CASE
+                    WHEN checkpointed_payload IS NULL THEN payload_blob
+                END as payload_blob,
+                CASE
+                    WHEN checkpointed_payload IS NULL THEN payload_version
+                    ELSE COALESCE(checkpoint_version, payload_version)
+                END as payload_version,
+                active_jobs.run_at,
+                trace_context,
+                from_base_job IS NOT NULL AND NOT manually_triggered
             FROM active_jobs
             JOIN jobs USING(job_id)
-            WHERE active_worker_id IS NULL
-                AND run_at <= $now
-                AND job_type in rarray($job_types)
-                AND weight <= $max_concurrency
-            ORDER BY active_jobs.priority DESC, run_at
-            LIMIT $limit"##,
+            WHERE job_id = ?"##,
     )?;
 
-    #[derive(Debug)]
-    struct JobResult {
-        job_id: i64,
-        external_id: Uuid,
-        name: Option<String>,
-        priority: i32,
-        weight: u16,
-        job_type: String,
-        current_try: i32,
-        payload: Option<Vec<u8>>,
-        default_timeout: i32,
-        heartbeat_increment: i32,
-        backoff_multiplier: f64,
-        backoff_randomization: f64,
-        backoff_initial_interval: i32,
-        max_retries: i32,
-        orig_run_at: i64,
+    let now_timestamp = now.unix_timestamp();
+    let job_types = Rc::new(job_types);
+
+    // Take jobs from each fairness key in turn, starting after the key that was served last, so
+    // that one key with a lot of ready jobs can't crowd out the others. The walk stops once
+    // there are enough keys with ready jobs to fill the batch.
+    let mut running_count = running_jobs.current_weighted.load(Ordering::Relaxed);
+    let cursor = queue.fairness_cursor.lock().unwrap().clone();
+    let mut groups = Vec::new();
+    let mut next_key = next_fairness_key(tx, Some(&cursor), &job_types, now_timestamp)?;
+    let mut wrapped = false;
+    while groups.len() < max_jobs as usize {
+        let key = match next_key {
+            // Back where we started, so every key has been checked.
+            Some(key) if wrapped && key > cursor => break,
+            Some(key) => key,
+            None if !wrapped => {
+                wrapped = true;
+                next_key = next_fairness_key(tx, None, &job_types, now_timestamp)?;
+                continue;
+            }
+            None => break,
+        };
+
+        let rows = candidates_stmt.query_map(
+            named_params! {
+                "$fairness_key": key,
+                "$job_types": job_types.clone(),
+                "$now": now_timestamp,
+                "$max_concurrency": max_concurrency,
+                "$resource_capacity": running_jobs.resources.capacity_json(),
+                "$labels": labels,
+                "$limit": max_jobs,
+            },
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, u16>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            },
+        )?;
+
+        let mut group = VecDeque::new();
+        for row in rows {
+            let (job_id, weight, resources) = row?;
+            let resources = resources_from_json(resources.as_deref())?;
+            // Leave out jobs that can't run alongside the ones already running, so that they don't
+            // use up this key's turn.
+            if running_count + weight as u32 <= max_concurrency
+                && running_jobs.resources.fits(&resources)
+            {
+                group.push_back(ReadyCandidate {
+                    job_id,
+                    weight,
+                    resources,
+                });
+            }
+        }
+
+        if !group.is_empty() {
+            groups.push(group);
+        }
+
+        next_key = next_fairness_key(tx, Some(&key), &job_types, now_timestamp)?;
     }
 
-    let now_timestamp = now.unix_timestamp();
-    let jobs = stmt.query_map(
-        named_params! {
-            "$job_types": Rc::new(job_types),
-            "$now": now_timestamp,
-            "$max_concurrency": max_concurrency,
-            "$limit": max_jobs,
-        },
-        |row| {
-            let job_id: i64 = row.get(0)?;
-            let external_id: Uuid = row.get(1)?;
-            let priority: i32 = row.get(2)?;
-            let weight: u16 = row.get(3)?;
-            let job_type: String = row.get(4)?;
-            let current_try: i32 = row.get(5)?;
-            let payload: Option<Vec<u8>> = row.get(6)?;
-            let default_timeout: i32 = row.get(7)?;
-            let heartbeat_increment: i32 = row.get(8)?;
-            let backoff_multiplier: f64 = row.get(9)?;
-            let backoff_randomization: f64 = row.get(10)?;
-            let backoff_initial_interval: i32 = row.get(11)?;
-            let max_retries: i32 = row.get(12)?;
-            let orig_run_at: i64 = row.get(13)?;
-            let name: Option<String> = row.get(14)?;
-
-            Ok(JobResult {
-                job_id,
-                priority,
-                weight,
-                job_type,
-                current_try,
-                payload,
-                default_timeout,
-                external_id,
-                heartbeat_increment,
-                backoff_multiplier,
-                backoff_randomization,
-                backoff_initial_interval,
-                max_retries,
-                orig_run_at,
-                name,
-            })
-        },
-    )?;
+    let mut jobs = Vec::with_capacity(max_jobs as usize);
+    while jobs.len() < max_jobs as usize && !groups.is_empty() {
+        groups.retain_mut(|group| match group.pop_front() {
+            Some(job) if jobs.len() < max_jobs as usize => {
+                jobs.push(job);
+                true
+            }
+            _ => false,
+        });
+    }
 
     let mut set_running = tx.prepare_cached(
         r##"UPDATE active_jobs
@@ -136,17 +329,19 @@ fn do_get_ready_jobs(
     )?;
 
     let mut ready_jobs = Vec::with_capacity(max_jobs as usize);
-    let mut running_count = running_jobs.current_weighted.load(Ordering::Relaxed);
-    for job in jobs {
-        let job = job?;
-        let weight = job.weight as u32;
+    let mut last_key = None;
+    for candidate in jobs {
+        let weight = candidate.weight as u32;
 
         event!(Level::DEBUG, running_count, weight, max_concurrency);
 
-        if running_count + weight > max_concurrency {
-            break;
+        let resources = candidate.resources;
+        if running_count + weight > max_concurrency || !running_jobs.resources.fits(&resources) {
+            // A smaller job from another key may still fit.
+            continue;
         }
 
+        let job = load_stmt.query_row([candidate.job_id], JobResult::from_row)?;
         let expiration = now_timestamp + job.default_timeout as i64;
 
         set_running.execute(named_params! {
@@ -155,14 +350,37 @@ fn do_get_ready_jobs(
             "$now": now_timestamp,
             "$expiration": expiration
         })?;
+        last_key = Some(job.fairness_key);
 
         running_count = running_jobs
             .current_weighted
             .fetch_add(weight, Ordering::Relaxed)
             + weight;
+        running_jobs.resources.claim(&resources);
         running_jobs.started.fetch_add(1, Ordering::Relaxed);
 
-        let (done_tx, done_rx) = tokio::sync::watch::channel(false);
+        let (payload, blob_payload) = match job.payload_blob {
+            // Large payloads are loaded when the job asks for them, not here in the writer thread.
+            Some(hash) => (
+                Vec::new(),
+                Some(BlobPayload::new(
+                    queue.blob_store.hold(&hash),
+                    job.payload_compression,
+                    job.payload_key_id,
+                )),
+            ),
+            None => (
+                queue.load_payload(
+                    job.payload_compression.as_deref(),
+                    job.payload_key_id.as_deref(),
+                    None,
+                    job.payload.unwrap_or_default(),
+                )?,
+                None,
+            ),
+        };
+
+        let (done_tx, done_rx) = tokio::sync::watch::channel(RunOutcome::Running);
         let job = RunningJob(Arc::new(RunningJobData {
             id: job.external_id,
             job_id: job.job_id,
@@ -170,9 +388,15 @@ fn do_get_ready_jobs(
             worker_id,
             heartbeat_increment: job.heartbeat_increment,
             job_type: job.job_type,
-            payload: job.payload.unwrap_or_default(),
+            payload,
+            blob_payload,
+            payload_codec: job.payload_codec,
+            payload_version: job.payload_version,
+            payload_versions: OnceLock::new(),
+            upgraded_payload: OnceLock::new(),
             priority: job.priority,
             weight: job.weight,
+            resources,
             start_time: now,
             current_try: job.current_try,
             backoff_multiplier: job.backoff_multiplier,
@@ -184,11 +408,21 @@ fn do_get_ready_jobs(
             expires: AtomicI64::new(expiration),
             orig_run_at: OffsetDateTime::from_unix_timestamp(job.orig_run_at)
                 .map_err(|_| Error::TimestampOutOfRange("orig_run_at"))?,
+            run_at: OffsetDateTime::from_unix_timestamp(job.run_at)
+                .map_err(|_| Error::TimestampOutOfRange("run_at"))?,
+            trace_context: job.trace_context,
+            scheduled_recurring: job.scheduled_recurring,
         }));
 
         ready_jobs.push(ReadyJob { job, done_rx });
     }
 
+    // Only move the cursor once all of the claims have been written, so that a failure doesn't
+    // skip over a key.
+    if let Some(key) = last_key {
+        *queue.fairness_cursor.lock().unwrap() = key;
+    }
+
     Ok(ready_jobs)
 }
 
@@ -203,6 +437,7 @@ pub(super) fn get_ready_jobs(
         max_jobs,
         max_concurrency,
         running_jobs,
+        labels,
         now,
         result_tx,
     } = args;
@@ -215,6 +450,7 @@ pub(super) fn get_ready_jobs(
         max_jobs,
         max_concurrency,
         running_jobs,
+        labels,
         now,
     );
 
@@ -12,7 +12,10 @@ use super::{
 };
 use crate::{
     db_writer::add_job::{execute_add_job_stmt, INSERT_JOBS_QUERY},
+    labels::labels_to_json,
     recurring::RecurringJobSchedule,
+    resources::resources_to_json,
+    shared_state::SharedState,
     Error, Job, Result,
 };
 
@@ -38,7 +41,11 @@ pub(crate) struct AddRecurringJobResult {
     pub new_run_at: Option<OffsetDateTime>,
 }
 
-pub(super) fn add_recurring_job(tx: &Connection, args: AddRecurringJobArgs) -> DbOperationResult {
+pub(super) fn add_recurring_job(
+    tx: &Connection,
+    state: &SharedState,
+    args: AddRecurringJobArgs,
+) -> DbOperationResult {
     let AddRecurringJobArgs {
         external_id,
         now,
@@ -48,15 +55,19 @@ pub(super) fn add_recurring_job(tx: &Connection, args: AddRecurringJobArgs) -> D
         result_tx,
         run_immediately_on_insert,
     } = args;
-    let result = do_add_recurring_job(
-        tx,
-        external_id,
-        now,
-        schedule,
-        run_immediately_on_insert,
-        upsert_mode,
-        job,
-    );
+    let result = recurring_job_blobs(tx, &external_id).and_then(|old_blobs| {
+        let result = do_add_recurring_job(
+            tx,
+            external_id,
+            now,
+            schedule,
+            run_immediately_on_insert,
+            upsert_mode,
+            job,
+        )?;
+        remove_blobs_if_unused(tx, state, old_blobs)?;
+        Ok(result)
+    });
     DbOperationResult::AddRecurringJob(super::OperationResult { result, result_tx })
 }
 
@@ -220,9 +231,18 @@ fn update_existing_recurring_job(
             backoff_initial_interval = ?9,
             default_timeout = ?10,
             heartbeat_increment = ?11,
-            name = ?12
+            name = ?12,
+            payload_codec = ?13,
+            payload_compression = ?14,
+            payload_key_id = ?15,
+            payload_blob = ?16,
+            payload_version = ?17,
+            trace_context = COALESCE(?18, trace_context),
+            resources = ?19,
+            required_labels = ?20
         WHERE job_id=?1"##,
     )?;
+    let storage = job.payload_storage.as_ref();
     base_update_stmt.execute(params![
         base_job_id,
         job.job_type,
@@ -236,6 +256,14 @@ fn update_existing_recurring_job(
         job.timeout.as_secs(),
         job.heartbeat_increment.as_secs(),
         job.name,
+        job.payload_codec,
+        storage.and_then(|s| s.compression.as_deref()),
+        storage.and_then(|s| s.key_id.as_deref()),
+        storage.and_then(|s| s.blob.as_deref()),
+        job.payload_version,
+        job.trace_context,
+        resources_to_json(&job.resources),
+        labels_to_json(&job.required_labels),
     ])?;
 
     // Update any pending jobs
@@ -253,8 +281,15 @@ fn update_existing_recurring_job(
             backoff_initial_interval = ?,
             default_timeout = ?,
             heartbeat_increment = ?,
-            name = ?
-        WHERE from_base_job = ? AND status = 'pending'
+            name = ?,
+            payload_codec = ?,
+            payload_compression = ?,
+            payload_key_id = ?,
+            payload_blob = ?,
+            payload_version = ?,
+            resources = ?,
+            required_labels = ?
+        WHERE from_base_job = ? AND status = 'pending' AND NOT manually_triggered
         RETURNING job_id"##,
     )?;
     let next_timestamp = next_time.map(|t| t.unix_timestamp());
@@ -273,6 +308,13 @@ fn update_existing_recurring_job(
                 job.timeout.as_secs(),
                 job.heartbeat_increment.as_secs(),
                 job.name,
+                job.payload_codec,
+                storage.and_then(|s| s.compression.as_deref()),
+                storage.and_then(|s| s.key_id.as_deref()),
+                storage.and_then(|s| s.blob.as_deref()),
+                job.payload_version,
+                resources_to_json(&job.resources),
+                labels_to_json(&job.required_labels),
                 base_job_id,
             ],
             |row| row.get::<_, rusqlite::types::Value>(0),
@@ -304,12 +346,39 @@ fn update_existing_recurring_job(
     })
 }
 
+/// The blobs used by the payloads of a recurring job's base job and its pending jobs. These may
+/// become unused when the recurring job is updated or deleted.
+fn recurring_job_blobs(tx: &Connection, external_id: &str) -> Result<Vec<String>> {
+    let mut stmt = tx.prepare_cached(
+        r##"SELECT DISTINCT jobs.payload_blob
+        FROM recurring
+        JOIN jobs ON jobs.job_id = recurring.base_job_id
+            OR (jobs.from_base_job = recurring.base_job_id AND jobs.status = 'pending')
+        WHERE recurring.external_id = ? AND jobs.payload_blob IS NOT NULL"##,
+    )?;
+    let blobs = stmt
+        .query_map([external_id], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(blobs)
+}
+
+fn remove_blobs_if_unused(tx: &Connection, state: &SharedState, blobs: Vec<String>) -> Result<()> {
+    for blob in blobs {
+        state.blob_store.remove_if_unused(tx, &blob)?;
+    }
+    Ok(())
+}
+
 pub(super) fn delete_recurring_job(
     tx: &Connection,
+    state: &SharedState,
     args: DeleteRecurringJobArgs,
 ) -> DbOperationResult {
     let DeleteRecurringJobArgs { id, result_tx } = args;
-    let result = do_delete_recurring_job(tx, id);
+    let result = recurring_job_blobs(tx, &id).and_then(|old_blobs| {
+        do_delete_recurring_job(tx, id)?;
+        remove_blobs_if_unused(tx, state, old_blobs)
+    });
     DbOperationResult::DeleteRecurringJob(super::OperationResult { result, result_tx })
 }
 
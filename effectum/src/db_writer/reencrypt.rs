@@ -0,0 +1,168 @@
+use std::sync::Arc;
+
+use rusqlite::{named_params, Connection};
+use serde_json::value::RawValue;
+use tokio::sync::oneshot;
+
+use super::DbOperationResult;
+use crate::{
+    blob_store::BlobStore,
+    encryption::{
+        decrypt, decrypt_run_info, encrypt, encrypt_run_info, KeyProvider, RUN_INFO_KEY_PATH,
+    },
+    job_status::RunInfo,
+    shared_state::SharedState,
+    Error, Result,
+};
+
+pub(crate) struct ReencryptJobsArgs {
+    pub keys: Arc<dyn KeyProvider>,
+    pub limit: usize,
+    pub result_tx: oneshot::Sender<Result<usize>>,
+}
+
+/// Re-encrypt an encrypted value with the current key, if it isn't already using it.
+fn reencrypt_value(
+    keys: &dyn KeyProvider,
+    current_key: &str,
+    key_id: Option<&str>,
+    data: Vec<u8>,
+) -> Result<(Vec<u8>, String)> {
+    if key_id == Some(current_key) {
+        return Ok((data, current_key.to_string()));
+    }
+
+    let decrypted = decrypt(Some(keys), key_id, data)?;
+    encrypt(keys, &decrypted)
+}
+
+fn reencrypt_run_info(keys: &dyn KeyProvider, run_info: &str) -> Result<String> {
+    let run_info: Vec<RunInfo<Box<RawValue>>> =
+        serde_json::from_str(run_info).map_err(Error::InvalidJobRunInfo)?;
+    let run_info = run_info
+        .into_iter()
+        .map(|run| {
+            let info = decrypt_run_info(Some(keys), run.info)?;
+            encrypt_run_info(keys, RunInfo { info, ..run })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    serde_json::to_string(&run_info).map_err(Error::InvalidJobRunInfo)
+}
+
+/// Re-encrypt up to `limit` jobs that have data which is not encrypted with the current key,
+/// including data that was written before encryption was enabled. Returns the number of jobs
+/// that were updated.
+fn do_reencrypt_jobs(
+    tx: &Connection,
+    blob_store: &Arc<BlobStore>,
+    keys: &dyn KeyProvider,
+    limit: usize,
+) -> Result<usize> {
+    let current_key = keys.current_key()?;
+    let current_key = current_key.id();
+
+    let mut find_stmt = tx.prepare_cached(&format!(
+        r##"SELECT job_id, payload, payload_key_id, checkpointed_payload, checkpoint_key_id,
+            run_info, payload_blob
+        FROM jobs
+        WHERE (payload IS NOT NULL AND payload_key_id IS NOT $current_key)
+            OR (checkpointed_payload IS NOT NULL AND checkpoint_key_id IS NOT $current_key)
+            OR EXISTS (
+                SELECT 1 FROM json_each(jobs.run_info)
+                WHERE json_extract(value, '{RUN_INFO_KEY_PATH}') IS NOT $current_key
+            )
+        LIMIT $limit"##
+    ))?;
+
+    let rows = find_stmt
+        .query_map(
+            named_params! { "$current_key": current_key, "$limit": limit as i64 },
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<Vec<u8>>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<Vec<u8>>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                ))
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut update_stmt = tx.prepare_cached(
+        r##"UPDATE jobs
+        SET payload = $payload,
+            payload_key_id = $payload_key_id,
+            checkpointed_payload = $checkpoint,
+            checkpoint_key_id = $checkpoint_key_id,
+            run_info = $run_info,
+            payload_blob = $payload_blob
+        WHERE job_id = $job_id"##,
+    )?;
+
+    let count = rows.len();
+    for (job_id, payload, payload_key_id, checkpoint, checkpoint_key_id, run_info, blob) in rows {
+        let mut new_blob = None;
+        let (payload, payload_key_id) = match (payload, &blob) {
+            (_, Some(hash)) if payload_key_id.as_deref() != Some(current_key) => {
+                let data = blob_store.read(hash)?;
+                let (data, key_id) =
+                    reencrypt_value(keys, current_key, payload_key_id.as_deref(), data)?;
+                new_blob = Some(blob_store.write(&data)?);
+                (Some(Vec::new()), Some(key_id))
+            }
+            (payload, Some(_)) => (payload, payload_key_id),
+            (Some(payload), None) => {
+                let (payload, key_id) =
+                    reencrypt_value(keys, current_key, payload_key_id.as_deref(), payload)?;
+                (Some(payload), Some(key_id))
+            }
+            (None, None) => (None, None),
+        };
+
+        let (checkpoint, checkpoint_key_id) = match checkpoint {
+            Some(checkpoint) => {
+                let (checkpoint, key_id) =
+                    reencrypt_value(keys, current_key, checkpoint_key_id.as_deref(), checkpoint)?;
+                (Some(checkpoint), Some(key_id))
+            }
+            None => (None, None),
+        };
+
+        let run_info = run_info
+            .map(|run_info| reencrypt_run_info(keys, &run_info))
+            .transpose()?;
+
+        update_stmt.execute(named_params! {
+            "$payload": payload,
+            "$payload_key_id": payload_key_id,
+            "$checkpoint": checkpoint,
+            "$checkpoint_key_id": checkpoint_key_id,
+            "$run_info": run_info,
+            "$payload_blob": new_blob.as_ref().map(|(hash, _)| hash).or(blob.as_ref()),
+            "$job_id": job_id,
+        })?;
+
+        if let (Some(old_hash), Some(_)) = (blob, new_blob) {
+            blob_store.remove_if_unused(tx, &old_hash)?;
+        }
+    }
+
+    Ok(count)
+}
+
+pub(super) fn reencrypt_jobs(
+    tx: &Connection,
+    state: &SharedState,
+    args: ReencryptJobsArgs,
+) -> DbOperationResult {
+    let ReencryptJobsArgs {
+        keys,
+        limit,
+        result_tx,
+    } = args;
+    let result = do_reencrypt_jobs(tx, &state.blob_store, keys.as_ref(), limit);
+    DbOperationResult::ReencryptJobs(super::OperationResult { result, result_tx })
+}
@@ -0,0 +1,86 @@
+use rusqlite::{named_params, Connection, OptionalExtension};
+use tokio::sync::oneshot;
+
+use super::DbOperationResult;
+use crate::{Error, Result};
+
+pub(crate) struct SnoozeJobArgs {
+    pub job_id: i64,
+    pub run_info: String,
+    pub next_time: i64,
+    pub result_tx: oneshot::Sender<Result<()>>,
+}
+
+pub(super) fn do_snooze_job(
+    tx: &Connection,
+    worker_id: u64,
+    job_id: i64,
+    run_info: String,
+    next_time: i64,
+) -> Result<()> {
+    let mut check_stmt = tx.prepare_cached(
+        r##"SELECT snooze_count < max_snoozes
+        FROM jobs
+        JOIN active_jobs USING(job_id)
+        WHERE job_id = $job_id AND active_worker_id = $worker_id"##,
+    )?;
+
+    let can_snooze = check_stmt
+        .query_row(
+            named_params! {
+                "$job_id": job_id,
+                "$worker_id": worker_id,
+            },
+            |row| row.get::<_, bool>(0),
+        )
+        .optional()?
+        .ok_or(Error::Expired)?;
+
+    if !can_snooze {
+        return Err(Error::SnoozeLimit);
+    }
+
+    let mut active_stmt = tx.prepare_cached(
+        r##"UPDATE active_jobs SET
+            active_worker_id = null,
+            run_at = $next_run_time
+        WHERE job_id = $job_id"##,
+    )?;
+
+    active_stmt.execute(named_params! {
+        "$job_id": job_id,
+        "$next_run_time": next_time,
+    })?;
+
+    // Unlike a retry, this leaves `current_try` alone.
+    let mut jobs_stmt = tx.prepare_cached(
+        r##"UPDATE jobs SET
+            snooze_count = snooze_count + 1,
+            run_info = json_array_append(run_info, $run_info)
+        WHERE job_id = $job_id"##,
+    )?;
+
+    jobs_stmt.execute(named_params! {
+        "$job_id": job_id,
+        "$run_info": run_info,
+    })?;
+
+    Ok(())
+}
+
+pub(super) fn snooze_job(
+    tx: &Connection,
+    worker_id: u64,
+    args: SnoozeJobArgs,
+) -> DbOperationResult {
+    let SnoozeJobArgs {
+        job_id,
+        run_info,
+        next_time,
+        result_tx,
+    } = args;
+
+    let result = do_snooze_job(tx, worker_id, job_id, run_info, next_time);
+
+    DbOperationResult::EmptyValue(super::OperationResult { result, result_tx })
+}
@@ -2,24 +2,53 @@ use rusqlite::{params, Connection, OptionalExtension};
 use tokio::sync::oneshot;
 
 use super::DbOperationResult;
-use crate::{add_job::JobUpdate, Error, Result};
+use crate::{
+    add_job::JobUpdate,
+    labels::{labels_from_json, Labels},
+    payload_storage::PayloadStorage,
+    shared_state::SharedState,
+    Error, Result,
+};
 
 pub(crate) struct UpdateJobArgs {
     pub job: JobUpdate,
-    pub result_tx: oneshot::Sender<Result<String>>,
+    /// How the new payload is stored, if there is one.
+    pub payload_storage: PayloadStorage,
+    /// Returns the job's type and required labels, so that the right workers can be notified.
+    pub result_tx: oneshot::Sender<Result<(String, Labels)>>,
 }
 
-fn do_update_job(tx: &Connection, job: JobUpdate) -> Result<String> {
+fn do_update_job(
+    tx: &Connection,
+    state: &SharedState,
+    job: JobUpdate,
+    payload_storage: PayloadStorage,
+) -> Result<(String, Labels)> {
     let mut find_job_stmt = tx.prepare_cached(
-        r##"SELECT job_id, job_type, active_jobs.run_at IS NOT NULL, active_worker_id IS NOT NULL
+        r##"SELECT job_id, job_type, active_jobs.run_at IS NOT NULL, active_worker_id IS NOT NULL,
+            required_labels, payload_blob
         FROM jobs
         LEFT JOIN active_jobs USING(job_id)
         WHERE external_id = ?"##,
     )?;
 
-    let (id, job_type, active, active_worker_id): (i64, String, bool, bool) = find_job_stmt
+    let (id, job_type, active, active_worker_id, required_labels, old_blob): (
+        i64,
+        String,
+        bool,
+        bool,
+        Option<String>,
+        Option<String>,
+    ) = find_job_stmt
         .query_row([job.id], |row| {
-            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ))
         })
         .optional()?
         .ok_or(Error::NotFound)?;
@@ -50,34 +79,83 @@ fn do_update_job(tx: &Connection, job: JobUpdate) -> Result<String> {
     if job.weight.is_some() || job.priority.is_some() || job.payload.is_some() {
         let mut jobs_update = tx.prepare_cached(
             r##"UPDATE jobs
-            SET weight = COALESCE(?, weight),
-                priority = COALESCE(?, priority),
-                payload = COALESCE(?, payload),
+            SET weight = COALESCE(?1, weight),
+                priority = COALESCE(?2, priority),
+                payload = COALESCE(?3, payload),
+                payload_codec = COALESCE(?4, payload_codec),
+                payload_version = COALESCE(?12, payload_version),
+                payload_compression = CASE
+                    WHEN ?3 IS NOT NULL THEN ?5
+                    ELSE payload_compression END,
+                payload_key_id = CASE
+                    WHEN ?3 IS NOT NULL THEN ?6
+                    ELSE payload_key_id END,
+                payload_blob = CASE
+                    WHEN ?3 IS NOT NULL THEN ?10
+                    ELSE payload_blob END,
                 checkpointed_payload = CASE
-                    WHEN checkpointed_payload IS NOT NULL
-                        THEN COALESCE(?, checkpointed_payload)
-                    ELSE NULL END
-            WHERE job_id = ?"##,
+                    WHEN checkpointed_payload IS NOT NULL AND NOT ?11
+                        THEN COALESCE(?7, checkpointed_payload)
+                    ELSE NULL END,
+                checkpoint_codec = CASE
+                    WHEN checkpointed_payload IS NOT NULL AND ?8
+                        THEN COALESCE(?4, payload_codec)
+                    ELSE checkpoint_codec END,
+                checkpoint_version = CASE
+                    WHEN checkpointed_payload IS NOT NULL AND ?8
+                        THEN COALESCE(?12, payload_version)
+                    ELSE checkpoint_version END,
+                checkpoint_compression = CASE
+                    WHEN checkpointed_payload IS NOT NULL AND ?8 THEN ?5
+                    ELSE checkpoint_compression END,
+                checkpoint_key_id = CASE
+                    WHEN checkpointed_payload IS NOT NULL AND ?8 THEN ?6
+                    ELSE checkpoint_key_id END
+            WHERE job_id = ?9"##,
         )?;
 
+        let update_checkpoint = job.payload.is_some() && job.update_checkpointed_payload;
+        // Checkpoints can't be stored as blobs, so instead of copying a blob payload into the
+        // checkpoint, clear the checkpoint so that the next run uses the new payload.
+        let clear_checkpoint = update_checkpoint && payload_storage.blob.is_some();
         jobs_update.execute(params![
             job.weight,
             job.priority,
             &job.payload,
-            if job.update_checkpointed_payload {
+            job.payload.as_ref().and(job.payload_codec.as_deref()),
+            payload_storage.compression,
+            payload_storage.key_id,
+            if update_checkpoint {
                 job.payload.as_ref()
             } else {
                 None
             },
-            id
+            update_checkpoint,
+            id,
+            payload_storage.blob,
+            clear_checkpoint,
+            job.payload.as_ref().and(job.payload_version),
         ])?;
+
+        // The old payload may have been the last reference to its blob.
+        if let Some(old_blob) = old_blob.filter(|_| job.payload.is_some()) {
+            state.blob_store.remove_if_unused(tx, &old_blob)?;
+        }
     }
 
-    Ok(job_type)
+    Ok((job_type, labels_from_json(required_labels.as_deref())?))
 }
 
-pub(super) fn update_job(tx: &Connection, args: UpdateJobArgs) -> DbOperationResult {
-    let UpdateJobArgs { job, result_tx } = args;
-    let result = do_update_job(tx, job);
+pub(super) fn update_job(
+    tx: &Connection,
+    state: &SharedState,
+    args: UpdateJobArgs,
+) -> DbOperationResult {
+    let UpdateJobArgs {
+        job,
+        payload_storage,
+        result_tx,
+    } = args;
+    let result = do_update_job(tx, state, job, payload_storage);
     DbOperationResult::UpdateJob(super::OperationResult { result, result_tx })
 }
@@ -0,0 +1,430 @@
+use std::{collections::HashMap, fmt::Debug};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use tracing::{instrument, Span};
+
+use crate::{
+    db_writer::{reencrypt::ReencryptJobsArgs, DbOperation, DbOperationType},
+    job_status::RunInfo,
+    Error, Queue, Result,
+};
+
+const NONCE_LEN: usize = 24;
+
+/// A key used to encrypt payloads and run info. Data is encrypted with XChaCha20-Poly1305.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: String,
+    key: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// Create a key. The `id` is stored alongside everything encrypted with this key, so that the
+    /// right key can be found to decrypt it after the current key has been rotated.
+    pub fn new(id: impl Into<String>, key: [u8; 32]) -> Self {
+        Self { id: id.into(), key }
+    }
+
+    /// The ID of this key.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&Key::from(self.key))
+    }
+
+    fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: data,
+                    aad: self.id.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::Encryption(format!("failed to encrypt with key {}", self.id)))?;
+
+        let mut output = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&ciphertext);
+        Ok(output)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let error = || Error::Encryption(format!("failed to decrypt with key {}", self.id));
+        if data.len() < NONCE_LEN {
+            return Err(error());
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: self.id.as_bytes(),
+                },
+            )
+            .map_err(|_| error())
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Supplies the keys used to encrypt job payloads, checkpoints, and run info.
+///
+/// To rotate keys, start returning a new key from `current_key` while still returning the old
+/// key from `key`. New data is encrypted with the new key, and [Queue::reencrypt](crate::Queue::reencrypt)
+/// rewrites existing data with it, after which the old key can be removed.
+pub trait KeyProvider: Debug + Send + Sync + 'static {
+    /// The key used to encrypt new data.
+    fn current_key(&self) -> Result<EncryptionKey>;
+
+    /// Look up a key by its ID, to decrypt data that was encrypted with it. Returns `None` if
+    /// the key is not known.
+    fn key(&self, id: &str) -> Result<Option<EncryptionKey>>;
+}
+
+/// A [KeyProvider] with a fixed set of keys.
+#[derive(Debug, Clone)]
+pub struct StaticKeyProvider {
+    current: EncryptionKey,
+    old: HashMap<String, EncryptionKey>,
+}
+
+impl StaticKeyProvider {
+    /// Create a provider that encrypts with `current`.
+    pub fn new(current: EncryptionKey) -> Self {
+        Self {
+            current,
+            old: HashMap::new(),
+        }
+    }
+
+    /// Add an old key, which is used only to decrypt data encrypted before it was rotated out.
+    pub fn with_old_key(mut self, key: EncryptionKey) -> Self {
+        self.old.insert(key.id.clone(), key);
+        self
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn current_key(&self) -> Result<EncryptionKey> {
+        Ok(self.current.clone())
+    }
+
+    fn key(&self, id: &str) -> Result<Option<EncryptionKey>> {
+        if id == self.current.id {
+            Ok(Some(self.current.clone()))
+        } else {
+            Ok(self.old.get(id).cloned())
+        }
+    }
+}
+
+/// Encrypt data with the provider's current key, returning the encrypted data and the key ID.
+pub(crate) fn encrypt(keys: &dyn KeyProvider, data: &[u8]) -> Result<(Vec<u8>, String)> {
+    let key = keys.current_key()?;
+    let encrypted = key.encrypt(data)?;
+    Ok((encrypted, key.id))
+}
+
+/// Decrypt data that was encrypted with the key `key_id`. Data with no key ID is returned as-is.
+pub(crate) fn decrypt(
+    keys: Option<&dyn KeyProvider>,
+    key_id: Option<&str>,
+    data: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let Some(key_id) = key_id else {
+        return Ok(data);
+    };
+
+    let key = keys
+        .map(|keys| keys.key(key_id))
+        .transpose()?
+        .flatten()
+        .ok_or_else(|| Error::UnknownEncryptionKey(key_id.to_string()))?;
+    key.decrypt(&data)
+}
+
+/// The form of an encrypted `info` value in a job's run info. Only the `info` field is encrypted,
+/// so that the rest of the run info can still be read and appended to by SQL.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EncryptedRunInfo {
+    #[serde(rename = "_effectum_enc")]
+    value: EncryptedValue,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedValue {
+    key: String,
+    data: String,
+}
+
+/// The path to the key ID of an encrypted run info entry, for use with SQLite's JSON functions.
+pub(crate) const RUN_INFO_KEY_PATH: &str = "$.info._effectum_enc.key";
+
+/// Encrypt the `info` of a run.
+pub(crate) fn encrypt_run_info<T: Serialize + Send + Debug>(
+    keys: &dyn KeyProvider,
+    run_info: RunInfo<T>,
+) -> Result<RunInfo<EncryptedRunInfo>> {
+    let info_json = serde_json::to_vec(&run_info.info).map_err(Error::InvalidJobRunInfo)?;
+    let (data, key) = encrypt(keys, &info_json)?;
+    Ok(RunInfo {
+        success: run_info.success,
+        snoozed: run_info.snoozed,
+        start: run_info.start,
+        end: run_info.end,
+        info: EncryptedRunInfo {
+            value: EncryptedValue {
+                key,
+                data: BASE64.encode(data),
+            },
+        },
+    })
+}
+
+/// Decrypt the `info` of a run, if it is encrypted.
+pub(crate) fn decrypt_run_info(
+    keys: Option<&dyn KeyProvider>,
+    info: Box<RawValue>,
+) -> Result<Box<RawValue>> {
+    // Check the prefix first to avoid trying to parse every value.
+    if !info.get().starts_with(r#"{"_effectum_enc""#) {
+        return Ok(info);
+    }
+
+    let Ok(encrypted) = serde_json::from_str::<EncryptedRunInfo>(info.get()) else {
+        return Ok(info);
+    };
+
+    let EncryptedValue { key, data } = encrypted.value;
+    let data = BASE64
+        .decode(data)
+        .map_err(|e| Error::Encryption(format!("invalid encrypted run info: {e}")))?;
+    let decrypted = decrypt(keys, Some(&key), data)?;
+    let decrypted = String::from_utf8(decrypted)
+        .map_err(|e| Error::Encryption(format!("invalid encrypted run info: {e}")))?;
+    RawValue::from_string(decrypted).map_err(Error::InvalidJobRunInfo)
+}
+
+impl Queue {
+    /// Rewrite the payloads, checkpoints, and run info of existing jobs with the current
+    /// encryption key, `batch_size` jobs at a time. Run this after rotating keys, so that the old
+    /// key can be retired. Data that was written before encryption was enabled is encrypted too.
+    ///
+    /// Returns the number of jobs that were updated.
+    #[instrument(skip(self))]
+    pub async fn reencrypt(&self, batch_size: usize) -> Result<usize> {
+        let keys = self
+            .state
+            .encryption_keys
+            .clone()
+            .ok_or_else(|| Error::Encryption("encryption is not enabled".to_string()))?;
+
+        let mut total = 0;
+        loop {
+            let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+            self.state
+                .db_write_tx
+                .send(DbOperation {
+                    worker_id: 0,
+                    span: Span::current(),
+                    operation: DbOperationType::ReencryptJobs(ReencryptJobsArgs {
+                        keys: keys.clone(),
+                        limit: batch_size.max(1),
+                        result_tx,
+                    }),
+                })
+                .await
+                .map_err(|_| Error::QueueClosed)?;
+
+            let count = result_rx.await.map_err(|_| Error::QueueClosed)??;
+            total += count;
+            if count < batch_size.max(1) {
+                return Ok(total);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider() -> StaticKeyProvider {
+        StaticKeyProvider::new(EncryptionKey::new("new", [2; 32]))
+            .with_old_key(EncryptionKey::new("old", [1; 32]))
+    }
+
+    #[test]
+    fn round_trip() {
+        let keys = provider();
+        let (encrypted, key_id) = encrypt(&keys, b"secret").unwrap();
+        assert_eq!(key_id, "new");
+        assert_ne!(&encrypted[NONCE_LEN..], b"secret");
+
+        let decrypted = decrypt(Some(&keys), Some(&key_id), encrypted).unwrap();
+        assert_eq!(decrypted, b"secret");
+    }
+
+    #[test]
+    fn old_key() {
+        let old = StaticKeyProvider::new(EncryptionKey::new("old", [1; 32]));
+        let (encrypted, key_id) = encrypt(&old, b"secret").unwrap();
+
+        let decrypted = decrypt(Some(&provider()), Some(&key_id), encrypted.clone()).unwrap();
+        assert_eq!(decrypted, b"secret");
+
+        let unknown = StaticKeyProvider::new(EncryptionKey::new("other", [3; 32]));
+        assert!(matches!(
+            decrypt(Some(&unknown), Some(&key_id), encrypted),
+            Err(Error::UnknownEncryptionKey(id)) if id == "old"
+        ));
+    }
+
+    #[test]
+    fn tampered() {
+        let keys = provider();
+        let (mut encrypted, key_id) = encrypt(&keys, b"secret").unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert!(decrypt(Some(&keys), Some(&key_id), encrypted).is_err());
+    }
+
+    #[test]
+    fn run_info() {
+        let keys = provider();
+        let run_info = RunInfo {
+            success: true,
+            snoozed: false,
+            start: time::OffsetDateTime::UNIX_EPOCH,
+            end: time::OffsetDateTime::UNIX_EPOCH,
+            info: "a secret",
+        };
+
+        let encrypted = encrypt_run_info(&keys, run_info).unwrap();
+        let json = serde_json::to_string(&encrypted).unwrap();
+        assert!(!json.contains("a secret"));
+
+        let parsed: RunInfo<Box<RawValue>> = serde_json::from_str(&json).unwrap();
+        assert!(parsed.success);
+        let info = decrypt_run_info(Some(&keys), parsed.info).unwrap();
+        assert_eq!(info.get(), r#""a secret""#);
+
+        let plain = RawValue::from_string(r#"{"a":1}"#.to_string()).unwrap();
+        assert_eq!(decrypt_run_info(None, plain).unwrap().get(), r#"{"a":1}"#);
+    }
+
+    async fn stored_key_ids(
+        queue: &Queue,
+        job_id: uuid::Uuid,
+    ) -> (Option<String>, Vec<u8>, String) {
+        let conn = queue.state.read_conn_pool.get().await.unwrap();
+        conn.interact(move |db| {
+            db.query_row(
+                "SELECT payload_key_id, payload, run_info FROM jobs WHERE external_id = ?",
+                [job_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+        })
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn encrypted_job() {
+        use std::sync::Arc;
+
+        use temp_dir::TempDir;
+
+        use crate::{
+            test_util::{create_test_queue_with_options, wait_for_job, TestContext},
+            Job, JobRunner, Worker,
+        };
+
+        let job_def = JobRunner::builder(
+            "encrypted_job",
+            |job, _context: Arc<TestContext>| async move {
+                let payload = job.json_payload::<String>().unwrap();
+                Ok::<_, String>(format!("ran with {payload}"))
+            },
+        )
+        .build();
+
+        let dir = TempDir::new().unwrap();
+        let queue = create_test_queue_with_options(dir, |options| {
+            options.encryption(StaticKeyProvider::new(EncryptionKey::new("old", [1; 32])))
+        })
+        .await;
+        let _worker = Worker::builder(&queue, TestContext::new())
+            .jobs([job_def])
+            .build()
+            .await
+            .expect("failed to build worker");
+
+        let job_id = Job::builder("encrypted_job")
+            .json_payload(&"a secret")
+            .unwrap()
+            .add_to(&queue)
+            .await
+            .expect("failed to add job");
+
+        let status = wait_for_job("job to run", &queue, job_id).await;
+        assert_eq!(status.payload, serde_json::to_vec("a secret").unwrap());
+        assert_eq!(status.run_info[0].info.get(), r#""ran with a secret""#);
+
+        let (key_id, payload, run_info) = stored_key_ids(&queue, job_id).await;
+        assert_eq!(key_id.as_deref(), Some("old"));
+        assert!(!String::from_utf8_lossy(&payload).contains("a secret"));
+        assert!(!run_info.contains("a secret"));
+
+        // Rotate to a new key, keeping the old one around to read existing data.
+        let dir = queue.close_and_persist().await;
+        let queue = create_test_queue_with_options(dir, |options| {
+            options.encryption(
+                StaticKeyProvider::new(EncryptionKey::new("new", [2; 32]))
+                    .with_old_key(EncryptionKey::new("old", [1; 32])),
+            )
+        })
+        .await;
+
+        let status = queue.get_job_status(job_id).await.unwrap();
+        assert_eq!(status.payload, serde_json::to_vec("a secret").unwrap());
+
+        assert_eq!(queue.reencrypt(10).await.unwrap(), 1);
+        assert_eq!(queue.reencrypt(10).await.unwrap(), 0);
+        let (key_id, _, run_info) = stored_key_ids(&queue, job_id).await;
+        assert_eq!(key_id.as_deref(), Some("new"));
+        assert!(run_info.contains(r#""key":"new""#));
+
+        // Now the old key is no longer needed.
+        let dir = queue.close_and_persist().await;
+        let queue = create_test_queue_with_options(dir, |options| {
+            options.encryption(StaticKeyProvider::new(EncryptionKey::new("new", [2; 32])))
+        })
+        .await;
+
+        let status = queue.get_job_status(job_id).await.unwrap();
+        assert_eq!(status.payload, serde_json::to_vec("a secret").unwrap());
+        assert_eq!(status.run_info[0].info.get(), r#""ran with a secret""#);
+    }
+}
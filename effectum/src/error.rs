@@ -36,9 +36,62 @@ pub enum Error {
     /// Failed to serialize or deserialize information when recording information about a job run.
     #[error("Error decoding job run info {0}")]
     InvalidJobRunInfo(serde_json::Error),
+
+    /// Failed to decode the resources that a job needs.
+    #[error("Error decoding job resources: {0}")]
+    InvalidResources(serde_json::Error),
+    /// Failed to decode the labels that a job requires.
+    #[error("Error decoding job labels: {0}")]
+    InvalidLabels(serde_json::Error),
     /// Failed to serialize or deserialize a job payload
     #[error("Error processing payload: {0}")]
     PayloadError(serde_json::Error),
+    /// Failed to encode or decode a job payload with a [PayloadCodec](crate::PayloadCodec)
+    #[error("Error processing {0} payload: {1}")]
+    PayloadCodec(
+        &'static str,
+        #[source] Box<dyn std::error::Error + Send + Sync + 'static>,
+    ),
+    /// The job's payload was encoded with a codec that is not enabled in this build
+    #[error("Unknown payload codec {0}")]
+    UnknownPayloadCodec(String),
+    /// Failed to compress or decompress a job payload
+    #[error("Error compressing payload with {0}: {1}")]
+    Compression(
+        &'static str,
+        #[source] Box<dyn std::error::Error + Send + Sync + 'static>,
+    ),
+    /// The job's payload was compressed with an algorithm that is not enabled in this build
+    #[error("Unknown compression algorithm {0}")]
+    UnknownCompression(String),
+    /// Failed to encrypt or decrypt a job payload or run info
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+    /// The data was encrypted with a key that the key provider does not know about
+    #[error("Unknown encryption key {0}")]
+    UnknownEncryptionKey(String),
+    /// Failed to read or write a payload in the blob store
+    #[error("Blob store error in {0}: {1}")]
+    BlobStore(String, #[source] std::io::Error),
+    /// The job's payload is newer than the versions that its [JobRunner](crate::JobRunner) knows
+    /// how to handle
+    #[error("Payload version {version} is newer than the supported version {supported}")]
+    PayloadVersionTooNew {
+        /// The version of the payload
+        version: u32,
+        /// The newest version that the runner supports
+        supported: u32,
+    },
+    /// There is no function to upgrade a payload from this version
+    #[error("No upgrade registered for payload version {0}")]
+    MissingPayloadUpgrade(u32),
+    /// A payload upgrade function returned an error
+    #[error("Failed to upgrade payload from version {0}: {1}")]
+    PayloadUpgrade(u32, String),
+    /// Failed to start the metrics exporter
+    #[cfg(feature = "prometheus")]
+    #[error("Failed to start metrics exporter: {0}")]
+    MetricsExporter(String),
     /// Invalid value for a job timestamp
     #[error("Timestamp {0} out of range")]
     TimestampOutOfRange(&'static str),
@@ -58,9 +111,15 @@ pub enum Error {
     /// The current job has expired.
     #[error("Job expired")]
     Expired,
+    /// The job has already been snoozed the maximum number of times.
+    #[error("Job has reached its snooze limit")]
+    SnoozeLimit,
     /// An unregistered worker tried to communicate with the queue.
     #[error("Worker {0} not found")]
     WorkerNotFound(u64),
+    /// A worker was asked to run a job type that it has no job runner for.
+    #[error("Worker does not have a runner for job type {0}")]
+    UnsupportedJobType(String),
     /// Indicates that the queue has closed, and so the attempted operation could not be completed.
     #[error("Queue closed unexpectedly")]
     QueueClosed,
@@ -0,0 +1,239 @@
+use std::{fmt::Debug, sync::Arc};
+
+use futures::{future::BoxFuture, Future, FutureExt};
+use serde::Serialize;
+use serde_json::value::RawValue;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{Error, Result, RunInfo, RunningJobData};
+
+/// Information about a job, passed to the lifecycle hooks set on
+/// [QueueOptions](crate::QueueOptions).
+#[derive(Debug)]
+pub struct JobHookEvent {
+    /// The ID of the job.
+    pub id: Uuid,
+    /// The type of the job.
+    pub job_type: String,
+    /// The name given to the job, if any.
+    pub name: Option<String>,
+    /// How many times the job had been tried before this run. On the first run, this will be 0.
+    pub current_try: i32,
+    /// Information about the run that just finished. This is `None` for
+    /// [on_start](crate::QueueOptions::on_start), and for jobs cancelled with
+    /// [Queue::cancel_job](crate::Queue::cancel_job) before they ran.
+    pub run_info: Option<RunInfo<Box<RawValue>>>,
+    /// When the job will run again, for [on_retry_scheduled](crate::QueueOptions::on_retry_scheduled).
+    pub next_run_at: Option<OffsetDateTime>,
+}
+
+impl JobHookEvent {
+    /// Create an event for a running job.
+    pub(crate) fn for_job(job: &RunningJobData) -> Self {
+        Self {
+            id: job.id,
+            job_type: job.job_type.clone(),
+            name: job.name.clone(),
+            current_try: job.current_try,
+            run_info: None,
+            next_run_at: None,
+        }
+    }
+
+    /// Add the info for the run that just finished.
+    pub(crate) fn with_run_info<T: Serialize + Send + Debug>(
+        mut self,
+        run_info: &RunInfo<T>,
+    ) -> Result<Self> {
+        self.run_info = Some(RunInfo {
+            success: run_info.success,
+            snoozed: run_info.snoozed,
+            start: run_info.start,
+            end: run_info.end,
+            info: serde_json::value::to_raw_value(&run_info.info)
+                .map_err(Error::InvalidJobRunInfo)?,
+        });
+        Ok(self)
+    }
+}
+
+pub(crate) type HookFn = Arc<dyn Fn(JobHookEvent) -> BoxFuture<'static, ()> + Send + Sync>;
+
+pub(crate) fn hook_fn<F, Fut>(hook: F) -> HookFn
+where
+    F: Fn(JobHookEvent) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |event| hook(event).boxed())
+}
+
+/// Callbacks that run when a job changes state.
+#[derive(Clone, Default)]
+pub(crate) struct JobHooks {
+    pub on_start: Option<HookFn>,
+    pub on_success: Option<HookFn>,
+    pub on_retry_scheduled: Option<HookFn>,
+    pub on_permanent_failure: Option<HookFn>,
+    pub on_cancel: Option<HookFn>,
+}
+
+/// Run a hook, if it is set, in a separate task so that it doesn't hold up the job or the
+/// database writer. The event is only created if the hook is set.
+pub(crate) fn run_hook(hook: &Option<HookFn>, event: impl FnOnce() -> JobHookEvent) {
+    if let Some(hook) = hook {
+        tokio::spawn(hook(event()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        test_util::{TestContext, TestEnvironment},
+        Job, JobRunner, QueueOptions,
+    };
+
+    type Events = mpsc::UnboundedReceiver<(&'static str, JobHookEvent)>;
+
+    /// Set all the hooks to send their events to a channel.
+    fn record_hooks(options: QueueOptions) -> (QueueOptions, Events) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let record = |name: &'static str| {
+            let tx = tx.clone();
+            move |event| {
+                tx.send((name, event)).ok();
+                async {}
+            }
+        };
+
+        let options = options
+            .on_start(record("start"))
+            .on_success(record("success"))
+            .on_retry_scheduled(record("retry"))
+            .on_permanent_failure(record("failure"))
+            .on_cancel(record("cancel"));
+        (options, rx)
+    }
+
+    async fn next_event(events: &mut Events) -> (&'static str, JobHookEvent) {
+        tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("timed out waiting for hook")
+            .expect("hook channel closed")
+    }
+
+    #[tokio::test]
+    async fn success_hooks() {
+        let mut events = None;
+        let mut test = TestEnvironment::with_options(|options| {
+            let (options, rx) = record_hooks(options);
+            events = Some(rx);
+            options
+        })
+        .await;
+        let mut events = events.unwrap();
+
+        let job_def = JobRunner::builder("hooked", |_job, _context: Arc<TestContext>| async move {
+            Ok::<_, String>("done")
+        })
+        .build();
+        test.registry.add(&job_def);
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let job_id = Job::builder("hooked")
+            .name("a hooked job")
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+
+        let (hook, event) = next_event(&mut events).await;
+        assert_eq!(hook, "start");
+        assert_eq!(event.id, job_id);
+        assert_eq!(event.job_type, "hooked");
+        assert_eq!(event.name.as_deref(), Some("a hooked job"));
+        assert!(event.run_info.is_none());
+
+        let (hook, event) = next_event(&mut events).await;
+        assert_eq!(hook, "success");
+        assert_eq!(event.id, job_id);
+        let run_info = event.run_info.expect("run info");
+        assert!(run_info.success);
+        assert_eq!(run_info.info.get(), r#""done""#);
+    }
+
+    #[tokio::test]
+    async fn failure_hooks() {
+        let mut events = None;
+        let mut test = TestEnvironment::with_options(|options| {
+            let (options, rx) = record_hooks(options);
+            events = Some(rx);
+            options
+        })
+        .await;
+        let mut events = events.unwrap();
+
+        let job_def = JobRunner::builder("hooked", |_job, _context: Arc<TestContext>| async move {
+            Err::<(), _>("broken")
+        })
+        .build();
+        test.registry.add(&job_def);
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let job_id = Job::builder("hooked")
+            .max_retries(1)
+            .backoff_initial_interval(Duration::from_secs(0))
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            let (hook, event) = next_event(&mut events).await;
+            assert_eq!(event.id, job_id);
+            if hook == "retry" {
+                assert!(event.next_run_at.is_some());
+            }
+            if hook != "start" {
+                let run_info = event.run_info.expect("run info");
+                assert!(!run_info.success);
+                assert_eq!(run_info.info.get(), r#""broken""#);
+            }
+            seen.push((hook, event.current_try));
+        }
+
+        assert_eq!(
+            seen,
+            vec![("start", 0), ("retry", 0), ("start", 1), ("failure", 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel_hook() {
+        let mut events = None;
+        let test = TestEnvironment::with_options(|options| {
+            let (options, rx) = record_hooks(options);
+            events = Some(rx);
+            options
+        })
+        .await;
+        let mut events = events.unwrap();
+
+        let job_id = Job::builder("hooked")
+            .run_at(test.time.now() + Duration::from_secs(1000))
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        test.queue.cancel_job(job_id).await.unwrap();
+
+        let (hook, event) = next_event(&mut events).await;
+        assert_eq!(hook, "cancel");
+        assert_eq!(event.id, job_id);
+        assert_eq!(event.job_type, "hooked");
+        assert!(event.run_info.is_none());
+    }
+}
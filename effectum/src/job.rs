@@ -1,28 +1,48 @@
 use std::{
     fmt::{Debug, Display},
     ops::Deref,
-    sync::{atomic::AtomicI64, Arc},
+    sync::{atomic::AtomicI64, Arc, OnceLock},
+    time::Duration,
 };
 
-use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use time::{OffsetDateTime, PrimitiveDateTime};
 use tokio::sync::Mutex;
 use tracing::{event, instrument, Level, Span};
 use uuid::Uuid;
 
 use crate::{
+    blob_store::BlobPayload,
+    codec::{decode_with_codec, JsonCodec, PayloadCodec},
     db_writer::{
         complete::CompleteJobArgs,
         heartbeat::{WriteCheckpointArgs, WriteHeartbeatArgs},
         retry::RetryJobArgs,
+        snooze::SnoozeJobArgs,
         DbOperation, DbOperationType,
     },
-    job_status::RunInfo,
+    hooks::{run_hook, JobHookEvent},
+    job_status::{JobState, RunInfo},
+    payload_version::PayloadVersions,
+    queue_metrics::job_finished,
+    resources::Resources,
+    retry_policy::RetryDecision,
     shared_state::SharedState,
     worker::{log_error, WorkerId},
     Error, Result, SmartString,
 };
 
+/// How a run of a job ended, as seen by the worker's job monitor task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RunOutcome {
+    /// The job is still running, or ended without recording an outcome.
+    Running,
+    Succeeded,
+    Failed,
+    /// The job was snoozed or cancelled, which says nothing about how well it ran.
+    Other,
+}
+
 /// Information about a running job.
 #[derive(Debug, Clone)]
 pub struct RunningJob(pub Arc<RunningJobData>);
@@ -59,8 +79,24 @@ pub struct RunningJobData {
     pub priority: i32,
     /// How much this job counts against the worker's concurrency limit.
     pub weight: u16,
-    /// The payload of the job. JSON payloads can be parsed using the [RunningJobData::json_payload] function.
-    pub payload: Vec<u8>,
+    /// Named resources that this job holds on the worker while it runs.
+    pub resources: Resources,
+    /// The payload, if it is stored in the database. This is empty for payloads in the blob
+    /// store, so use [RunningJobData::payload_bytes] to read it.
+    pub(crate) payload: Vec<u8>,
+    /// The payload, if it is in the blob store.
+    pub(crate) blob_payload: Option<BlobPayload>,
+    /// The name of the [PayloadCodec] that the payload was encoded with. The payload can be
+    /// decoded with this codec using [RunningJobData::decode_payload].
+    pub payload_codec: String,
+    /// The version of the payload's format, from when the job was submitted or last
+    /// checkpointed. [RunningJobData::json_payload] and [RunningJobData::decode_payload] upgrade
+    /// older payloads to the runner's current version.
+    pub payload_version: u32,
+    /// The runner's payload versions, set before the job starts.
+    pub(crate) payload_versions: OnceLock<Arc<PayloadVersions>>,
+    /// The payload after it was upgraded to the current version, encoded as JSON.
+    pub(crate) upgraded_payload: OnceLock<Vec<u8>>,
     /// The timestamp, in seconds, when this job expires.
     pub expires: AtomicI64,
 
@@ -75,9 +111,15 @@ pub struct RunningJobData {
     /// The number of times this job can be retried before giving up permanently.
     pub max_retries: i32,
 
-    pub(crate) done: Mutex<Option<tokio::sync::watch::Sender<bool>>>,
+    pub(crate) done: Mutex<Option<tokio::sync::watch::Sender<RunOutcome>>>,
     pub(crate) queue: SharedState,
     pub(crate) orig_run_at: OffsetDateTime,
+    /// When this run of the job was scheduled to start.
+    pub(crate) run_at: OffsetDateTime,
+    /// The serialized trace context of the span that added the job.
+    pub(crate) trace_context: Option<String>,
+    /// True if the job was created by a recurring job's schedule.
+    pub(crate) scheduled_recurring: bool,
 }
 
 impl Debug for RunningJobData {
@@ -91,7 +133,10 @@ impl Debug for RunningJobData {
             .field("job_type", &self.job_type)
             .field("priority", &self.priority)
             .field("weight", &self.weight)
+            .field("resources", &self.resources)
             .field("payload", &self.payload)
+            .field("payload_codec", &self.payload_codec)
+            .field("payload_version", &self.payload_version)
             .field("expires", &self.expires)
             .field("start_time", &self.start_time)
             .field("backoff_multiplier", &self.backoff_multiplier)
@@ -100,6 +145,7 @@ impl Debug for RunningJobData {
             .field("current_try", &self.current_try)
             .field("max_retries", &self.max_retries)
             .field("orig_run_at", &self.orig_run_at)
+            .field("run_at", &self.run_at)
             .finish_non_exhaustive()
     }
 }
@@ -130,12 +176,26 @@ impl RunningJobData {
     /// Checkpoint the task, replacing the payload with the passed in value.
     #[instrument(level = "debug")]
     pub async fn checkpoint_blob(&self, new_payload: Vec<u8>) -> Result<OffsetDateTime> {
+        self.write_checkpoint(new_payload, None).await
+    }
+
+    async fn write_checkpoint(
+        &self,
+        new_payload: Vec<u8>,
+        codec: Option<&'static str>,
+    ) -> Result<OffsetDateTime> {
         // This counts as a heartbeat, so update the expiration.
         // Update the checkpoint_payload.
         let job_id = self.job_id;
         let worker_id = self.worker_id;
         let now = self.queue.time.now().unix_timestamp();
         let new_expiration = now + (self.heartbeat_increment as i64);
+        let (payload, storage) = self.queue.encode_payload(new_payload)?;
+        // The checkpoint is written by the current code, so it uses the current payload version.
+        let version = self
+            .payload_versions
+            .get()
+            .map_or(self.payload_version, |v| v.version);
 
         let (result_tx, result_rx) = tokio::sync::oneshot::channel();
         self.queue
@@ -146,7 +206,10 @@ impl RunningJobData {
                 operation: DbOperationType::WriteCheckpoint(WriteCheckpointArgs {
                     job_id,
                     new_expiration,
-                    payload: new_payload,
+                    payload,
+                    codec,
+                    version,
+                    storage,
                     result_tx,
                 }),
             })
@@ -167,7 +230,17 @@ impl RunningJobData {
     /// Checkpoint the task, replacing the payload with the passed in value.
     pub async fn checkpoint_json<T: Serialize>(&self, new_payload: T) -> Result<OffsetDateTime> {
         let blob = serde_json::to_vec(&new_payload).map_err(Error::PayloadError)?;
-        self.checkpoint_blob(blob).await
+        self.write_checkpoint(blob, Some(JsonCodec::NAME)).await
+    }
+
+    /// Checkpoint the task, replacing the payload with the passed in value encoded using `codec`.
+    pub async fn checkpoint_with<T: Serialize>(
+        &self,
+        codec: impl PayloadCodec,
+        new_payload: T,
+    ) -> Result<OffsetDateTime> {
+        let blob = codec.encode(&new_payload)?;
+        self.write_checkpoint(blob, Some(codec.name())).await
     }
 
     /// Tell the queue that the task is still running.
@@ -206,29 +279,92 @@ impl RunningJobData {
         now >= expired
     }
 
-    /// Deserialize a JSON payload into the requested type.
+    /// Get the job's payload. If the payload is in the blob store, it is read the first time this
+    /// is called.
+    pub fn payload_bytes(&self) -> Result<&[u8]> {
+        match &self.blob_payload {
+            Some(blob) => blob.get(&self.queue),
+            None => Ok(self.payload.as_slice()),
+        }
+    }
+
+    /// Get the payload upgraded to the runner's current version, along with the name of the codec
+    /// that it is encoded with. Upgraded payloads are always encoded as JSON.
+    fn current_payload(&self) -> Result<(&[u8], &str)> {
+        let payload = self.payload_bytes()?;
+        let Some(versions) = self.payload_versions.get() else {
+            return Ok((payload, self.payload_codec.as_str()));
+        };
+
+        if self.payload_version == versions.version {
+            return Ok((payload, self.payload_codec.as_str()));
+        }
+
+        if let Some(upgraded) = self.upgraded_payload.get() {
+            return Ok((upgraded, JsonCodec::NAME));
+        }
+
+        let value = decode_with_codec(&self.payload_codec, payload)?;
+        let value = versions.upgrade(self.payload_version, value)?;
+        let upgraded = serde_json::to_vec(&value).map_err(Error::PayloadError)?;
+        Ok((
+            self.upgraded_payload.get_or_init(|| upgraded),
+            JsonCodec::NAME,
+        ))
+    }
+
+    /// Deserialize a JSON payload into the requested type, upgrading it first if it was
+    /// submitted with an older payload version.
     pub fn json_payload<'a, T: Deserialize<'a>>(&'a self) -> Result<T> {
-        serde_json::from_slice(self.payload.as_slice()).map_err(Error::PayloadError)
+        serde_json::from_slice(self.current_payload()?.0).map_err(Error::PayloadError)
+    }
+
+    /// Deserialize the payload using the codec that it was encoded with, upgrading it first if
+    /// it was submitted with an older payload version.
+    pub fn decode_payload<T: DeserializeOwned>(&self) -> Result<T> {
+        let (payload, codec) = self.current_payload()?;
+        decode_with_codec(codec, payload)
     }
 
     #[instrument(level = "debug")]
     async fn mark_job_permanently_done<T: Serialize + Send + Debug>(
         &self,
         info: T,
-        success: bool,
+        status: JobState,
     ) -> Result<(), Error> {
         let mut done = self.done.lock().await;
-        let _chan = done.take().expect("Called complete after job finished");
+        let chan = done.take().expect("Called complete after job finished");
         drop(done);
+        set_outcome(
+            &chan,
+            match status {
+                JobState::Succeeded => RunOutcome::Succeeded,
+                JobState::Cancelled => RunOutcome::Other,
+                _ => RunOutcome::Failed,
+            },
+        );
 
         let info = RunInfo {
-            success,
+            success: status == JobState::Succeeded,
+            snoozed: false,
             start: self.start_time,
             end: self.queue.time.now(),
             info,
         };
 
-        let this_run_info = serde_json::to_string(&info).map_err(Error::InvalidJobRunInfo)?;
+        let hooks = &self.queue.hooks;
+        let hook = match status {
+            JobState::Succeeded => &hooks.on_success,
+            JobState::Cancelled => &hooks.on_cancel,
+            _ => &hooks.on_permanent_failure,
+        };
+        let hook_event = hook
+            .is_some()
+            .then(|| JobHookEvent::for_job(self).with_run_info(&info))
+            .transpose()?;
+
+        let duration: Duration = (info.end - info.start).try_into().unwrap_or_default();
+        let this_run_info = self.queue.serialize_run_info(info)?;
 
         let job_id = self.job_id;
         let worker_id = self.worker_id;
@@ -245,13 +381,18 @@ impl RunningJobData {
                     run_info: this_run_info,
                     now,
                     started_at,
-                    success,
+                    status,
                     result_tx,
                 }),
             })
             .await
             .map_err(|_| Error::QueueClosed)?;
         let next_time = result_rx.await.map_err(|_| Error::QueueClosed)??;
+        job_finished(&self.job_type, status, duration);
+        if let Some(event) = hook_event {
+            run_hook(hook, || event);
+        }
+
         if let Some(next_time) = next_time {
             log_error(
                 self.queue
@@ -270,7 +411,8 @@ impl RunningJobData {
     /// Mark the job as successful.
     #[instrument(skip(self), fields(self = %self))]
     pub async fn complete<T: Serialize + Send + Debug>(&self, info: T) -> Result<(), Error> {
-        self.mark_job_permanently_done(info, true).await
+        self.mark_job_permanently_done(info, JobState::Succeeded)
+            .await
     }
 
     /// Calculate the next run time, given the backoff.
@@ -288,22 +430,13 @@ impl RunningJobData {
         now.unix_timestamp() + (run_delta as i64)
     }
 
-    /// Mark the job as failed.
-    #[instrument(skip(self), fields(self = %self))]
-    pub async fn fail<T: Serialize + Send + Debug>(&self, info: T) -> Result<(), Error> {
-        // Remove task from running jobs, update job info, calculate new retry time, and stick the
-        // job back into pending.
-        // If there is a checkpointed payload, use that. Otherwise use the original payload from the
-        // job.
-
+    /// The retry behavior for jobs that don't have a [RetryPolicy](crate::RetryPolicy), using
+    /// the job's [Retries](crate::Retries) settings.
+    pub(crate) fn default_retry_decision(&self) -> RetryDecision {
         if self.current_try + 1 > self.max_retries {
-            return self.mark_job_permanently_done(info, false).await;
+            return RetryDecision::Fail;
         }
 
-        let mut done = self.done.lock().await;
-        let _chan = done.take().expect("Called fail after job finished");
-        drop(done);
-
         let now = self.queue.time.now();
         let next_time = Self::calculate_next_run_time(
             &now,
@@ -312,17 +445,66 @@ impl RunningJobData {
             self.backoff_multiplier,
             self.backoff_randomization,
         );
+        let delay = (next_time - now.unix_timestamp()).max(0) as u64;
+        RetryDecision::Retry(Duration::from_secs(delay))
+    }
+
+    /// Mark the job as failed, and retry it according to the job's retry settings.
+    #[instrument(skip(self), fields(self = %self))]
+    pub async fn fail<T: Serialize + Send + Debug>(&self, info: T) -> Result<(), Error> {
+        self.fail_with_decision(info, self.default_retry_decision())
+            .await
+    }
+
+    /// Mark the job as failed, and retry it or not according to `decision`.
+    #[instrument(skip(self), fields(self = %self))]
+    pub async fn fail_with_decision<T: Serialize + Send + Debug>(
+        &self,
+        info: T,
+        decision: RetryDecision,
+    ) -> Result<(), Error> {
+        let delay = match decision {
+            RetryDecision::Retry(delay) => delay,
+            RetryDecision::Fail => {
+                return self.mark_job_permanently_done(info, JobState::Failed).await
+            }
+            RetryDecision::Cancel => {
+                return self
+                    .mark_job_permanently_done(info, JobState::Cancelled)
+                    .await
+            }
+        };
+
+        // Remove task from running jobs, update job info, calculate new retry time, and stick the
+        // job back into pending.
+        // If there is a checkpointed payload, use that. Otherwise use the original payload from the
+        // job.
+        let mut done = self.done.lock().await;
+        let chan = done.take().expect("Called fail after job finished");
+        drop(done);
+        set_outcome(&chan, RunOutcome::Failed);
+
+        let now = self.queue.time.now();
+        let next_time = timestamp_after(now, delay);
         let job_id = self.job_id;
         let worker_id = self.worker_id;
 
         let info = RunInfo {
             success: false,
+            snoozed: false,
             start: self.start_time,
             end: now,
             info,
         };
 
-        let this_run_info = serde_json::to_string(&info).map_err(Error::InvalidJobRunInfo)?;
+        let hook = &self.queue.hooks.on_retry_scheduled;
+        let hook_event = hook
+            .is_some()
+            .then(|| JobHookEvent::for_job(self).with_run_info(&info))
+            .transpose()?;
+
+        let duration: Duration = (info.end - info.start).try_into().unwrap_or_default();
+        let this_run_info = self.queue.serialize_run_info(info)?;
 
         let (result_tx, result_rx) = tokio::sync::oneshot::channel();
         self.queue
@@ -340,6 +522,71 @@ impl RunningJobData {
             .await
             .map_err(|_| Error::QueueClosed)?;
         result_rx.await.map_err(|_| Error::QueueClosed)??;
+        job_finished(&self.job_type, JobState::Pending, duration);
+
+        if let Some(mut event) = hook_event {
+            event.next_run_at = OffsetDateTime::from_unix_timestamp(next_time).ok();
+            run_hook(hook, || event);
+        }
+
+        // Make sure that the pending job watcher knows about the rescheduled job.
+        log_error(
+            self.queue
+                .pending_jobs_tx
+                .send((SmartString::from(&self.job_type), next_time))
+                .await,
+        );
+
+        Ok(())
+    }
+
+    /// Put the job back in the queue to run again after `delay`, without counting this run as a
+    /// failure or using up a retry. This is useful when the job is waiting on an external
+    /// resource, or a downstream service asked it to try again later.
+    ///
+    /// Once the job has been snoozed `max_snoozes` times, this returns [Error::SnoozeLimit]
+    /// and the job keeps running, so that returning the error fails the job as usual.
+    #[instrument(skip(self), fields(self = %self))]
+    pub async fn snooze(&self, delay: Duration) -> Result<(), Error> {
+        let mut done = self.done.lock().await;
+        if done.is_none() {
+            return Err(Error::JobAlreadyConsumed);
+        }
+
+        let now = self.queue.time.now();
+        let next_time = timestamp_after(now, delay);
+
+        let info = RunInfo {
+            success: false,
+            snoozed: true,
+            start: self.start_time,
+            end: now,
+            info: format!("Snoozed for {} seconds", delay.as_secs()),
+        };
+        let this_run_info = self.queue.serialize_run_info(info)?;
+
+        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        self.queue
+            .db_write_tx
+            .send(DbOperation {
+                worker_id: self.worker_id,
+                span: Span::current(),
+                operation: DbOperationType::SnoozeJob(SnoozeJobArgs {
+                    job_id: self.job_id,
+                    run_info: this_run_info,
+                    next_time,
+                    result_tx,
+                }),
+            })
+            .await
+            .map_err(|_| Error::QueueClosed)?;
+        result_rx.await.map_err(|_| Error::QueueClosed)??;
+
+        let chan = done.take();
+        drop(done);
+        if let Some(chan) = &chan {
+            set_outcome(chan, RunOutcome::Other);
+        }
 
         // Make sure that the pending job watcher knows about the rescheduled job.
         log_error(
@@ -353,6 +600,25 @@ impl RunningJobData {
     }
 }
 
+/// The timestamp `delay` after `now`. Very long delays, such as a backoff policy with no maximum,
+/// are capped at the latest time that can be read back from the database.
+fn timestamp_after(now: OffsetDateTime, delay: Duration) -> i64 {
+    let latest = PrimitiveDateTime::MAX.assume_utc().unix_timestamp();
+    i64::try_from(delay.as_secs())
+        .map(|secs| now.unix_timestamp().saturating_add(secs))
+        .unwrap_or(i64::MAX)
+        .min(latest)
+}
+
+/// Record the outcome of the run without waking the monitor task. It wakes when the sender is
+/// dropped, after the result has been saved.
+fn set_outcome(chan: &tokio::sync::watch::Sender<RunOutcome>, outcome: RunOutcome) {
+    chan.send_if_modified(|value| {
+        *value = outcome;
+        false
+    });
+}
+
 pub(crate) async fn send_heartbeat(
     job_id: i64,
     worker_id: u64,
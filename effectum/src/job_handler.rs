@@ -0,0 +1,359 @@
+use std::fmt::{Debug, Display};
+
+use futures::{future::BoxFuture, Future, FutureExt};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    job_registry::JobRunnerBuilder, worker::log_error, Job, JobRegistry, JobRunner, Queue, Result,
+    RetryDecision, RunningJob,
+};
+
+/// A job type with a typed payload. This is an alternative to passing a closure to
+/// [JobRunner::builder], which lets the compiler check that jobs are submitted with the right
+/// payload type for the job.
+///
+/// ```
+/// # use effectum::*;
+/// # use std::sync::Arc;
+/// # use serde::{Deserialize, Serialize};
+/// #[derive(Debug)]
+/// pub struct JobContext {
+///   // database pool or other things here
+/// }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct RemindMePayload {
+///     email: String,
+///     message: String,
+/// }
+///
+/// struct RemindMe;
+///
+/// impl JobHandler for RemindMe {
+///     const NAME: &'static str = "remind_me";
+///     type Payload = RemindMePayload;
+///     type Context = Arc<JobContext>;
+///     type Output = ();
+///     type Error = Error;
+///
+///     async fn run(
+///         job: RunningJob,
+///         payload: RemindMePayload,
+///         context: Arc<JobContext>,
+///     ) -> Result<(), Error> {
+///         // send the reminder
+///         Ok(())
+///     }
+/// }
+///
+/// # async fn example(queue: Queue, context: Arc<JobContext>) -> Result<(), Error> {
+/// let worker = Worker::builder(&queue, context)
+///     .jobs([JobRunner::from_handler::<RemindMe>()])
+///     .build()
+///     .await?;
+///
+/// queue
+///     .enqueue::<RemindMe>(RemindMePayload {
+///         email: "me@example.com".to_string(),
+///         message: "Time to go!".to_string(),
+///     })
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait JobHandler: Send + Sync + 'static {
+    /// The job type, used to match submitted jobs with the handler.
+    const NAME: &'static str;
+    /// The payload passed to the job. Jobs submitted through [Queue::enqueue] encode it as
+    /// JSON, but payloads encoded with any enabled [PayloadCodec](crate::PayloadCodec) can be
+    /// decoded.
+    type Payload: Serialize + DeserializeOwned + Send + 'static;
+    /// The context object passed to the job. This must match the context of the [Worker](crate::Worker)
+    /// that runs the job.
+    type Context: Send + Sync + Debug + Clone + 'static;
+    /// Information about a successful run, which is saved in the job's run info.
+    type Output: Serialize + Send + Debug + 'static;
+    /// The error type returned from the job.
+    type Error: Send + Debug + Display + 'static;
+
+    /// The current version of the payload format. Increase this when [JobHandler::Payload]
+    /// changes in a way that older payloads can't be deserialized, and handle the older versions
+    /// in [JobHandler::upgrade_payload].
+    const PAYLOAD_VERSION: u32 = 0;
+
+    /// Upgrade a payload from `from_version` to `from_version + 1`. This is called for each
+    /// version between the job's payload version and [JobHandler::PAYLOAD_VERSION].
+    fn upgrade_payload(from_version: u32, payload: Value) -> Result<Value, String> {
+        let _ = payload;
+        Err(format!(
+            "{} does not support upgrading payload version {from_version}",
+            Self::NAME
+        ))
+    }
+
+    /// Run the job.
+    fn run(
+        job: RunningJob,
+        payload: Self::Payload,
+        context: Self::Context,
+    ) -> impl Future<Output = Result<Self::Output, Self::Error>> + Send;
+}
+
+type HandlerFuture<H> =
+    BoxFuture<'static, Result<Option<<H as JobHandler>::Output>, <H as JobHandler>::Error>>;
+type HandlerFn<H> = fn(RunningJob, <H as JobHandler>::Context) -> HandlerFuture<H>;
+
+/// A [JobRunnerBuilder] for a [JobHandler].
+pub type HandlerRunnerBuilder<H> = JobRunnerBuilder<
+    HandlerFn<H>,
+    HandlerFuture<H>,
+    Option<<H as JobHandler>::Output>,
+    <H as JobHandler>::Error,
+    <H as JobHandler>::Context,
+>;
+
+fn run_handler<H: JobHandler>(job: RunningJob, context: H::Context) -> HandlerFuture<H> {
+    async move {
+        let payload = match job.decode_payload::<H::Payload>() {
+            Ok(payload) => payload,
+            Err(e) => {
+                // Retrying won't fix a payload that can't be decoded.
+                log_error(
+                    job.fail_with_decision(e.to_string(), RetryDecision::Fail)
+                        .await,
+                );
+                return Ok(None);
+            }
+        };
+
+        H::run(job, payload, context).await.map(Some)
+    }
+    .boxed()
+}
+
+impl<CONTEXT> JobRunner<CONTEXT>
+where
+    CONTEXT: Send + Sync + Debug + Clone + 'static,
+{
+    /// Create a [JobRunner] for a [JobHandler].
+    pub fn from_handler<H: JobHandler<Context = CONTEXT>>() -> JobRunner<CONTEXT> {
+        Self::handler_builder::<H>().build()
+    }
+
+    /// Create a [JobRunnerBuilder] for a [JobHandler], to customize the job's options.
+    pub fn handler_builder<H: JobHandler<Context = CONTEXT>>() -> HandlerRunnerBuilder<H> {
+        (0..H::PAYLOAD_VERSION).fold(
+            JobRunnerBuilder::new(H::NAME, run_handler::<H> as HandlerFn<H>)
+                .payload_version(H::PAYLOAD_VERSION),
+            |builder, from| {
+                builder.upgrade_payload(from, move |payload| H::upgrade_payload(from, payload))
+            },
+        )
+    }
+}
+
+impl<CONTEXT> JobRegistry<CONTEXT>
+where
+    CONTEXT: Send + Sync + Debug + Clone + 'static,
+{
+    /// Add a [JobHandler] to an existing registry.
+    pub fn add_handler<H: JobHandler<Context = CONTEXT>>(&mut self) {
+        self.add(&JobRunner::from_handler::<H>());
+    }
+}
+
+impl Job {
+    /// Create a [JobBuilder](crate::JobBuilder) for a [JobHandler], with the given payload.
+    pub fn for_handler<H: JobHandler>(payload: &H::Payload) -> Result<crate::JobBuilder> {
+        Job::builder(H::NAME)
+            .payload_version(H::PAYLOAD_VERSION)
+            .json_payload(payload)
+    }
+}
+
+impl Queue {
+    /// Submit a job for a [JobHandler] with the default options. Use [Job::for_handler] to
+    /// customize the job before submitting it.
+    pub async fn enqueue<H: JobHandler>(&self, payload: H::Payload) -> Result<Uuid> {
+        Job::for_handler::<H>(&payload)?.add_to(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        test_util::{wait_for_job, wait_for_job_status, TestContext, TestEnvironment},
+        JobState,
+    };
+
+    struct PushValue;
+
+    impl JobHandler for PushValue {
+        const NAME: &'static str = "typed_push";
+        type Payload = Vec<String>;
+        type Context = Arc<TestContext>;
+        type Output = usize;
+        type Error = String;
+
+        async fn run(
+            _job: RunningJob,
+            payload: Vec<String>,
+            context: Arc<TestContext>,
+        ) -> Result<usize, String> {
+            let count = payload.len();
+            for value in payload {
+                context.push_str(value).await;
+            }
+            Ok(count)
+        }
+    }
+
+    #[tokio::test]
+    async fn typed_handler() {
+        let mut test = TestEnvironment::new().await;
+        test.registry.add_handler::<PushValue>();
+
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let job_id = test
+            .queue
+            .enqueue::<PushValue>(vec!["a".to_string(), "b".to_string()])
+            .await
+            .expect("failed to add job");
+
+        let status = wait_for_job("job to run", &test.queue, job_id).await;
+        assert_eq!(status.run_info[0].info.to_string(), "2");
+        assert_eq!(test.context.get_values().await, vec!["a", "b"]);
+    }
+
+    #[derive(Serialize, serde::Deserialize)]
+    struct Named {
+        name: String,
+    }
+
+    struct PushNamed;
+
+    impl JobHandler for PushNamed {
+        const NAME: &'static str = "push_named";
+        const PAYLOAD_VERSION: u32 = 1;
+        type Payload = Named;
+        type Context = Arc<TestContext>;
+        type Output = ();
+        type Error = String;
+
+        fn upgrade_payload(from_version: u32, payload: Value) -> Result<Value, String> {
+            match from_version {
+                // Version 0 payloads were just the name.
+                0 => Ok(serde_json::json!({ "name": payload })),
+                _ => Err(format!("unknown version {from_version}")),
+            }
+        }
+
+        async fn run(
+            _job: RunningJob,
+            payload: Named,
+            context: Arc<TestContext>,
+        ) -> Result<(), String> {
+            context.push_str(payload.name).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrade_handler_payload() {
+        let mut test = TestEnvironment::new().await;
+        test.registry.add_handler::<PushNamed>();
+
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let old_id = Job::builder(PushNamed::NAME)
+            .json_payload("old")
+            .unwrap()
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        wait_for_job("old job to run", &test.queue, old_id).await;
+
+        let new_id = test
+            .queue
+            .enqueue::<PushNamed>(Named {
+                name: "new".to_string(),
+            })
+            .await
+            .expect("failed to add job");
+        let status = wait_for_job("new job to run", &test.queue, new_id).await;
+        assert_eq!(status.payload_version, 1);
+
+        assert_eq!(test.context.get_values().await, vec!["old", "new"]);
+    }
+
+    #[cfg(feature = "macros")]
+    mod job_macro {
+        use std::time::Duration;
+
+        use super::*;
+
+        /// A job declared with the attribute macro.
+        #[crate::job(name = "macro_push", retries = 2, timeout = "10m", priority = 3)]
+        async fn push_value(
+            _job: RunningJob,
+            payload: String,
+            context: Arc<TestContext>,
+        ) -> Result<(), String> {
+            context.push_str(payload).await;
+            Ok(())
+        }
+
+        #[test]
+        fn builder_defaults() {
+            let job = push_value::builder(&"a".to_string())
+                .expect("creating builder")
+                .build();
+
+            assert_eq!(job.job_type, "macro_push");
+            assert_eq!(job.retries.max_retries, 2);
+            assert_eq!(job.timeout, Duration::from_secs(600));
+            assert_eq!(job.priority, 3);
+        }
+
+        #[tokio::test]
+        async fn run_macro_job() {
+            let mut test = TestEnvironment::new().await;
+            test.registry.add(&push_value::runner());
+
+            let _worker = test.worker().build().await.expect("failed to build worker");
+
+            let job_id = push_value::enqueue(&test.queue, "a".to_string())
+                .await
+                .expect("failed to add job");
+
+            wait_for_job("job to run", &test.queue, job_id).await;
+            assert_eq!(test.context.get_values().await, vec!["a"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_payload() {
+        let mut test = TestEnvironment::new().await;
+        test.registry.add_handler::<PushValue>();
+
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let job_id = Job::builder(PushValue::NAME)
+            .json_payload(&5)
+            .unwrap()
+            .max_retries(3)
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+
+        let status =
+            wait_for_job_status("job to fail", &test.queue, job_id, JobState::Failed).await;
+        assert_eq!(status.run_info.len(), 1);
+    }
+}
@@ -2,19 +2,30 @@ use std::{
     borrow::Borrow,
     fmt::{Debug, Display},
     marker::PhantomData,
-    panic::AssertUnwindSafe,
     sync::Arc,
 };
 
 use ahash::HashMap;
 use futures::{Future, FutureExt};
 use serde::Serialize;
-use tracing::{event, span, Instrument, Level};
-
-use crate::{job::RunningJob, worker::log_error, SmartString};
+use serde_json::Value;
+use tracing::{event, Level};
+
+use crate::{
+    job::RunningJob,
+    middleware::{JobMiddleware, JobOutcome, MiddlewareStack, Next},
+    payload_version::PayloadVersions,
+    retry_policy::{decide_retry, RetryDecision, RetryPolicy},
+    worker::log_error,
+    SmartString,
+};
 
-pub(crate) type JobFn<CONTEXT> =
-    Arc<dyn Fn(RunningJob, CONTEXT) -> tokio::task::JoinHandle<()> + Send + Sync + 'static>;
+pub(crate) type JobFn<CONTEXT> = Arc<
+    dyn Fn(RunningJob, CONTEXT, MiddlewareStack<CONTEXT>) -> tokio::task::JoinHandle<()>
+        + Send
+        + Sync
+        + 'static,
+>;
 
 /// A list of jobs that can be run by a worker.
 pub struct JobRegistry<CONTEXT>
@@ -22,6 +33,7 @@ where
     CONTEXT: Send + Sync + Debug + Clone + 'static,
 {
     pub(crate) jobs: HashMap<SmartString, JobRunner<CONTEXT>>,
+    pub(crate) middleware: Vec<Arc<dyn JobMiddleware<CONTEXT>>>,
 }
 
 impl<CONTEXT> JobRegistry<CONTEXT>
@@ -42,7 +54,10 @@ where
             })
             .collect();
 
-        JobRegistry { jobs }
+        JobRegistry {
+            jobs,
+            middleware: Vec::new(),
+        }
     }
 
     /// Add a [JobRunner] to an existing registry.
@@ -54,6 +69,13 @@ where
             })
             .or_insert_with(|| job.clone());
     }
+
+    /// Add a [JobMiddleware] that runs around every job from this registry. Middleware runs in
+    /// the order it was added, inside any middleware added to the
+    /// [WorkerBuilder](crate::WorkerBuilder).
+    pub fn add_middleware(&mut self, middleware: impl JobMiddleware<CONTEXT>) {
+        self.middleware.push(Arc::new(middleware));
+    }
 }
 
 /// A definition of a job, including the name of the job, the function that runs the job, and
@@ -96,14 +118,22 @@ where
     pub(crate) autoheartbeat: bool,
 }
 
-/// Options for a [JobRunner]
+/// Options for a [JobRunner]. New options may be added in the future, so this can't be created
+/// with a struct literal. Use [JobRunnerOptions::default] and then set the fields, or
+/// [JobRunner::builder].
 #[derive(Debug, Clone, Default)]
+#[non_exhaustive]
 pub struct JobRunnerOptions {
     /// If false (default), format failures with the [Display] implementation when storing the
     /// information in the database. If true, use the [Debug] implementation.
     pub format_failures_with_debug: bool,
     /// If true, automatically heartbeat the job when it's running.
     pub autoheartbeat: bool,
+    /// Decide what to do when the job fails. If not set, the job is retried according to its
+    /// [Retries](crate::Retries) settings.
+    pub retry_policy: Option<Arc<dyn RetryPolicy>>,
+    /// The current version of the job's payload format, and how to upgrade older payloads.
+    pub payload_versions: PayloadVersions,
 }
 
 impl<CONTEXT> JobRunner<CONTEXT>
@@ -127,6 +157,8 @@ where
         let options = JobRunnerOptions {
             format_failures_with_debug: false,
             autoheartbeat,
+            retry_policy: None,
+            payload_versions: PayloadVersions::default(),
         };
 
         Self::with_options(name, options, runner)
@@ -149,54 +181,76 @@ where
         let JobRunnerOptions {
             format_failures_with_debug,
             autoheartbeat,
+            retry_policy,
+            payload_versions,
         } = def;
-        let f = move |job: RunningJob, context: CONTEXT| {
+        let payload_versions = Arc::new(payload_versions);
+        let f = move |job: RunningJob, context: CONTEXT, middleware: MiddlewareStack<CONTEXT>| {
             let runner = runner.clone();
+            let retry_policy = retry_policy.clone();
+            let payload_versions = payload_versions.clone();
             tokio::spawn(async move {
-                let result = {
-                    let span = span!(Level::INFO, "run_job", %job);
-                    AssertUnwindSafe(runner(job.clone(), context).instrument(span))
-                        .catch_unwind()
-                        .await
+                if let Err(e) = payload_versions.check(job.payload_version) {
+                    // Don't run a job whose payload can't be read. Retrying would only hand it
+                    // back to a runner with the same versions, so fail it right away instead of
+                    // using up its retries.
+                    event!(Level::ERROR, %job, err = %e, "Unsupported payload version");
+                    log_error(
+                        job.fail_with_decision(e.to_string(), RetryDecision::Fail)
+                            .await,
+                    );
+                    return;
+                }
+                job.payload_versions.set(payload_versions).ok();
+
+                let run = {
+                    let job = job.clone();
+                    let context = context.clone();
+                    let retry_policy = retry_policy.clone();
+                    async move {
+                        match runner(job.clone(), context).await {
+                            Ok(info) => JobOutcome::succeeded(&info).unwrap_or_else(|e| {
+                                JobOutcome::Failed {
+                                    message: format!("Failed to serialize job result: {e}"),
+                                    decision: RetryDecision::Fail,
+                                }
+                            }),
+                            Err(e) => JobOutcome::Failed {
+                                decision: decide_retry(&job, &e, retry_policy.as_deref()),
+                                message: if format_failures_with_debug {
+                                    format!("{e:?}")
+                                } else {
+                                    e.to_string()
+                                },
+                            },
+                        }
+                    }
+                    .boxed()
                 };
 
+                let outcome = Next::new(&job, &context, &middleware, run).run().await;
+
                 let explicitly_finished = job.is_done().await;
                 event!(Level::DEBUG, ?job, %explicitly_finished, now=%job.queue.time.now(), "done");
-                match result {
-                    Err(e) => {
-                        let msg = if let Some(s) = e.downcast_ref::<&str>() {
-                            s.to_string()
-                        } else if let Some(s) = e.downcast_ref::<String>() {
-                            s.clone()
-                        } else {
-                            "Panic".to_string()
-                        };
-
+                match outcome {
+                    JobOutcome::Panicked(msg) => {
                         if explicitly_finished {
                             event!(Level::ERROR, %msg, "Job panicked after it was completed");
                         } else {
-                            log_error(job.fail(msg).await);
+                            let decision = decide_retry(&job, &msg, retry_policy.as_deref());
+                            log_error(job.fail_with_decision(msg, decision).await);
                         }
                     }
-                    Ok(Ok(info)) => {
+                    JobOutcome::Succeeded(info) => {
                         if !explicitly_finished {
                             log_error(job.complete(info).await);
                         }
                     }
-                    Ok(Err(e)) => {
+                    JobOutcome::Failed { message, decision } => {
                         if explicitly_finished {
-                            if format_failures_with_debug {
-                                event!(Level::ERROR, err = ?e, "Job returned error after it was completed");
-                            } else {
-                                event!(Level::ERROR, err = %e, "Job returned error after it was completed");
-                            }
+                            event!(Level::ERROR, err = %message, "Job returned error after it was completed");
                         } else {
-                            let msg = if format_failures_with_debug {
-                                format!("{e:?}")
-                            } else {
-                                e.to_string()
-                            };
-                            log_error(job.fail(msg).await);
+                            log_error(job.fail_with_decision(message, decision).await);
                         }
                     }
                 }
@@ -260,6 +314,8 @@ where
             def: JobRunnerOptions {
                 format_failures_with_debug: false,
                 autoheartbeat: false,
+                retry_policy: None,
+                payload_versions: PayloadVersions::default(),
             },
             _fut: PhantomData,
             _t: PhantomData,
@@ -282,6 +338,33 @@ where
         self
     }
 
+    /// Set the [RetryPolicy] that decides what happens when the job fails.
+    pub fn retry_policy(mut self, policy: impl RetryPolicy) -> Self {
+        self.def.retry_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Set the current version of the job's payload format. Jobs submitted with an older
+    /// [payload version](crate::JobBuilder::payload_version) are upgraded with the functions
+    /// registered through [JobRunnerBuilder::upgrade_payload] before their payload is
+    /// deserialized, and jobs with a newer version fail without running or being retried.
+    pub fn payload_version(mut self, version: u32) -> Self {
+        self.def.payload_versions.version = version;
+        self
+    }
+
+    /// Register a function that upgrades a JSON payload from `from_version` to
+    /// `from_version + 1`. There should be an upgrade for each version older than the current
+    /// [payload version](JobRunnerBuilder::payload_version) that may still be in the queue.
+    pub fn upgrade_payload<UE: Display>(
+        mut self,
+        from_version: u32,
+        upgrade: impl Fn(Value) -> Result<Value, UE> + Send + Sync + 'static,
+    ) -> Self {
+        self.def.payload_versions.add_upgrade(from_version, upgrade);
+        self
+    }
+
     /// Consume the builder, returning a [JobRunner].
     pub fn build(self) -> JobRunner<CONTEXT> {
         JobRunner::with_options(self.name, self.def, self.runner_fn)
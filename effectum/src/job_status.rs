@@ -9,13 +9,22 @@ use smallvec::SmallVec;
 use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
-use crate::{Error, Queue, Result};
+use crate::{
+    encryption::decrypt_run_info,
+    labels::{labels_from_json, Labels},
+    resources::{resources_from_json, Resources},
+    shared_state::SharedStateData,
+    Error, Queue, Result,
+};
 
 /// Information about the results of a job run.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RunInfo<T: Send + Debug> {
     /// If this run succeeded or not.
     pub success: bool,
+    /// True if the job was snoozed during this run, and so it neither succeeded nor failed.
+    #[serde(default)]
+    pub snoozed: bool,
     /// When this run started
     #[serde(with = "time::serde::timestamp")]
     pub start: OffsetDateTime,
@@ -39,6 +48,8 @@ pub enum JobState {
     Failed,
     /// The job was cancelled by the user.
     Cancelled,
+    /// The job did not start before its start deadline, and will not be run.
+    Expired,
     /// This job is a template for a recurring job. Other jobs will be created from this job but
     /// this instance will not be run.
     RecurringBase,
@@ -53,6 +64,7 @@ impl JobState {
             JobState::Succeeded => "succeeded",
             JobState::Failed => "failed",
             JobState::Cancelled => "cancelled",
+            JobState::Expired => "expired",
             JobState::RecurringBase => "recurring_base",
         }
     }
@@ -74,6 +86,7 @@ impl FromStr for JobState {
             "succeeded" => Ok(JobState::Succeeded),
             "failed" => Ok(JobState::Failed),
             "cancelled" => Ok(JobState::Cancelled),
+            "expired" => Ok(JobState::Expired),
             "recurring_base" => Ok(JobState::RecurringBase),
             _ => Err(Error::InvalidJobState(s.to_string())),
         }
@@ -95,12 +108,24 @@ pub struct JobStatus {
     pub priority: i32,
     /// Higher weight indicates a job counts more against a worker's concurrency.
     pub weight: u16,
+    /// Named resources that the job needs while it runs. See
+    /// [Job::resources](crate::Job::resources).
+    pub resources: Resources,
+    /// Labels that a worker must have to run the job. See
+    /// [Job::required_labels](crate::Job::required_labels).
+    pub required_labels: Labels,
     /// The original run_at time, before any retries.
     pub orig_run_at: OffsetDateTime,
     /// The current run_at time, if the job is pending.
     pub run_at: Option<OffsetDateTime>,
+    /// The time by which the job must start, if it has one.
+    pub start_deadline: Option<OffsetDateTime>,
     /// The job's payload
     pub payload: Vec<u8>,
+    /// The name of the [PayloadCodec](crate::PayloadCodec) used to encode the payload.
+    pub payload_codec: String,
+    /// The version of the payload's format. See [Job::payload_version](crate::Job::payload_version).
+    pub payload_version: u32,
     /// The current try count, if the job is running or pending.
     pub current_try: Option<i32>,
     /// The limit on the number of retries.
@@ -121,6 +146,9 @@ pub struct JobStatus {
     pub expires_at: Option<OffsetDateTime>,
     /// Information about each run of the job.
     pub run_info: SmallVec<[RunInfo<Box<RawValue>>; 4]>,
+    /// True if the job was created by [Queue::trigger_recurring_job] instead of the recurring
+    /// job's schedule.
+    pub manually_triggered: bool,
 }
 
 #[derive(Serialize)]
@@ -138,6 +166,7 @@ pub(crate) enum JobIdQuery {
 impl Queue {
     pub(crate) fn run_job_status_query(
         conn: &rusqlite::Connection,
+        state: &SharedStateData,
         id: JobIdQuery,
         limit: usize,
     ) -> Result<SmallVec<[JobStatus; 1]>, Error> {
@@ -162,7 +191,9 @@ impl Queue {
                     max_retries, backoff_multiplier, backoff_randomization, backoff_initial_interval,
                     added_at,
                     COALESCE(active_jobs.started_at, jobs.started_at) AS started_at,
-                    finished_at, expires_at, run_info, name
+                    finished_at, expires_at, run_info, name, manually_triggered,
+                    jobs.start_deadline, payload_codec, payload_compression, payload_key_id,
+                    payload_blob, payload_version, resources, required_labels
                 FROM jobs
                 LEFT JOIN active_jobs USING(job_id)
                 WHERE {}=?1
@@ -214,6 +245,15 @@ impl Queue {
                     }
                     None => SmallVec::new(),
                 };
+                let run_info = run_info
+                    .into_iter()
+                    .map(|run| {
+                        Ok(RunInfo {
+                            info: decrypt_run_info(state.encryption_keys.as_deref(), run.info)?,
+                            ..run
+                        })
+                    })
+                    .collect::<Result<SmallVec<_>>>()?;
 
                 let status = JobStatus {
                     id: row.get(0).map_err(|e| Error::ColumnType(e, "id"))?,
@@ -225,6 +265,16 @@ impl Queue {
                         .parse()?,
                     priority: row.get(3)?,
                     weight: row.get(4)?,
+                    resources: resources_from_json(
+                        row.get_ref(26)?
+                            .as_str_or_null()
+                            .map_err(|e| Error::ColumnType(e.into(), "resources"))?,
+                    )?,
+                    required_labels: labels_from_json(
+                        row.get_ref(27)?
+                            .as_str_or_null()
+                            .map_err(|e| Error::ColumnType(e.into(), "required_labels"))?,
+                    )?,
                     orig_run_at: OffsetDateTime::from_unix_timestamp(row.get(5)?)
                         .map_err(|_| Error::TimestampOutOfRange("orig_run_at"))?,
                     run_at: row
@@ -234,7 +284,31 @@ impl Queue {
                         .map(OffsetDateTime::from_unix_timestamp)
                         .transpose()
                         .map_err(|_| Error::TimestampOutOfRange("run_at"))?,
-                    payload: row.get(7)?,
+                    start_deadline: row
+                        .get_ref(20)?
+                        .as_i64_or_null()
+                        .map_err(|e| Error::ColumnType(e.into(), "start_deadline"))?
+                        .map(OffsetDateTime::from_unix_timestamp)
+                        .transpose()
+                        .map_err(|_| Error::TimestampOutOfRange("start_deadline"))?,
+                    payload: state.load_payload(
+                        row.get_ref(22)?
+                            .as_str_or_null()
+                            .map_err(|e| Error::ColumnType(e.into(), "payload_compression"))?,
+                        row.get_ref(23)?
+                            .as_str_or_null()
+                            .map_err(|e| Error::ColumnType(e.into(), "payload_key_id"))?,
+                        row.get_ref(24)?
+                            .as_str_or_null()
+                            .map_err(|e| Error::ColumnType(e.into(), "payload_blob"))?,
+                        row.get(7)?,
+                    )?,
+                    payload_codec: row
+                        .get(21)
+                        .map_err(|e| Error::ColumnType(e, "payload_codec"))?,
+                    payload_version: row
+                        .get(25)
+                        .map_err(|e| Error::ColumnType(e, "payload_version"))?,
                     current_try: row.get(8)?,
                     max_retries: row.get(9)?,
                     backoff_multiplier: row.get(10)?,
@@ -247,6 +321,9 @@ impl Queue {
                     expires_at,
                     run_info,
                     name: row.get(18).map_err(|e| Error::ColumnType(e, "name"))?,
+                    manually_triggered: row
+                        .get(19)
+                        .map_err(|e| Error::ColumnType(e, "manually_triggered"))?,
                 };
 
                 Ok::<_, Error>(status)
@@ -259,10 +336,11 @@ impl Queue {
     /// Return information about a job
     pub async fn get_job_status(&self, external_id: Uuid) -> Result<JobStatus> {
         let conn = self.state.read_conn_pool.get().await?;
+        let state = self.state.clone();
 
         let status = conn
             .interact(move |conn| {
-                Self::run_job_status_query(conn, JobIdQuery::ExternalId(external_id), 1)
+                Self::run_job_status_query(conn, &state, JobIdQuery::ExternalId(external_id), 1)
             })
             .await??;
 
@@ -272,9 +350,12 @@ impl Queue {
     /// Get jobs by their name, ordered by the most recently added.
     pub async fn get_jobs_by_name(&self, name: String, limit: usize) -> Result<Vec<JobStatus>> {
         let conn = self.state.read_conn_pool.get().await?;
+        let state = self.state.clone();
 
         let rows = conn
-            .interact(move |conn| Self::run_job_status_query(conn, JobIdQuery::Name(name), limit))
+            .interact(move |conn| {
+                Self::run_job_status_query(conn, &state, JobIdQuery::Name(name), limit)
+            })
             .await??;
 
         Ok(rows.into_vec())
@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+
+use crate::{Error, Result};
+
+/// Labels that describe a worker, such as its region or the volumes it has mounted.
+pub(crate) type Labels = BTreeMap<String, String>;
+
+/// Encode a job's required labels for the `required_labels` column. Jobs without any
+/// requirements are stored as NULL.
+pub(crate) fn labels_to_json(labels: &Labels) -> Option<String> {
+    if labels.is_empty() {
+        None
+    } else {
+        serde_json::to_string(labels).ok()
+    }
+}
+
+pub(crate) fn labels_from_json(value: Option<&str>) -> Result<Labels> {
+    match value {
+        Some(value) => serde_json::from_str(value).map_err(Error::InvalidLabels),
+        None => Ok(Labels::new()),
+    }
+}
+
+/// Check if a worker with `labels` can run a job that requires `required`. Every required label
+/// must be present on the worker with the same value.
+pub(crate) fn labels_match(required: &Labels, labels: &Labels) -> bool {
+    required
+        .iter()
+        .all(|(name, value)| labels.get(name) == Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(values: &[(&str, &str)]) -> Labels {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn match_labels() {
+        let worker = labels(&[("region", "eu"), ("gpu", "a100")]);
+
+        assert!(labels_match(&Labels::new(), &worker));
+        assert!(labels_match(&labels(&[("region", "eu")]), &worker));
+        assert!(labels_match(&worker, &worker));
+        assert!(!labels_match(&labels(&[("region", "us")]), &worker));
+        assert!(!labels_match(&labels(&[("model_cache", "llama")]), &worker));
+        assert!(!labels_match(&labels(&[("region", "eu")]), &Labels::new()));
+    }
+}
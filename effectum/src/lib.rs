@@ -63,33 +63,113 @@
 //!   Ok(())
 //! }
 //! ```
+//!
+//! # Metrics
+//!
+//! With the `metrics` feature, the queue records these metrics through the
+//! [metrics](https://docs.rs/metrics) facade. Enable the `prometheus` feature and call
+//! `install_prometheus_exporter` to serve them over HTTP in the Prometheus text format, or install
+//! any other `metrics` recorder.
+//!
+//! | Name | Type | Description |
+//! |------|------|-------------|
+//! | `effectum_jobs_enqueued_total` | counter | Jobs added to the queue |
+//! | `effectum_jobs_started_total` | counter | Job runs started |
+//! | `effectum_jobs_succeeded_total` | counter | Jobs that succeeded |
+//! | `effectum_jobs_failed_total` | counter | Jobs that failed and will not be retried |
+//! | `effectum_jobs_cancelled_total` | counter | Jobs cancelled by their retry policy |
+//! | `effectum_jobs_retried_total` | counter | Failed runs that were scheduled to retry |
+//! | `effectum_job_queue_wait_seconds` | histogram | Time between a job's `run_at` and when it started |
+//! | `effectum_job_duration_seconds` | histogram | How long each run took |
+//! | `effectum_jobs_pending` | gauge | Jobs waiting to run |
+//! | `effectum_jobs_running` | gauge | Jobs currently running |
+//! | `effectum_db_writer_batch_size` | histogram | Operations in each database write batch |
+//! | `effectum_db_writer_commit_seconds` | histogram | Time to commit each batch |
+//! | `effectum_worker_concurrency_limit` | gauge | Each worker's current concurrency limit |
+//!
+//! The job metrics have a `job_type` label, and the worker metrics have a `worker_id` label. The gauges are updated every
+//! `QueueOptions::metrics_interval`, which defaults to 15 seconds.
 
+// Allow the macros, which refer to `::effectum`, to be used within this crate.
+extern crate self as effectum;
+
+mod adaptive_concurrency;
 mod add_job;
+mod blob_store;
+mod codec;
+mod compression;
+mod encryption;
 mod error;
+mod hooks;
 mod job_status;
+mod middleware;
 mod migrations;
+mod payload_storage;
+mod payload_version;
+mod queue_metrics;
+mod queue_stats;
 mod shared_state;
+mod sweeper;
+mod trace_context;
 mod worker_list;
 
 mod db_writer;
 mod job;
+mod job_handler;
 mod job_registry;
+mod labels;
 mod local_queue;
 mod pending_jobs;
+mod priority_aging;
 mod recurring;
+mod resources;
+mod retry_policy;
 mod sqlite_functions;
 #[cfg(test)]
 mod test_util;
 mod worker;
 
-pub use add_job::{Job, JobBuilder, JobUpdate, JobUpdateBuilder, Retries};
+#[cfg(feature = "macros")]
+pub use effectum_macros::job;
+
+pub use adaptive_concurrency::AdaptiveConcurrency;
+pub use add_job::{Debounce, Job, JobBuilder, JobUpdate, JobUpdateBuilder, Retries};
+#[cfg(feature = "bincode")]
+pub use codec::BincodeCodec;
+#[cfg(feature = "cbor")]
+pub use codec::CborCodec;
+#[cfg(feature = "msgpack")]
+pub use codec::MessagePackCodec;
+pub use codec::{JsonCodec, PayloadCodec};
+pub use compression::{Compression, CompressionAlgorithm};
+pub use encryption::{EncryptionKey, KeyProvider, StaticKeyProvider};
 pub use error::{Error, Result};
+pub use hooks::JobHookEvent;
 pub use job::{RunningJob, RunningJobData};
+pub use job_handler::{HandlerRunnerBuilder, JobHandler};
 pub use job_registry::{JobRegistry, JobRunner, JobRunnerBuilder};
 pub use job_status::{JobState, JobStatus, RunInfo};
 pub use local_queue::*;
-pub use recurring::{RecurringJobInfo, RecurringJobSchedule};
+pub use middleware::{JobMiddleware, JobOutcome, Next};
+pub use payload_version::PayloadVersions;
+#[cfg(feature = "prometheus")]
+pub use queue_metrics::install_prometheus_exporter;
+pub use queue_stats::JobTypeStats;
+pub use recurring::{
+    RecurringJobHistory, RecurringJobHistoryCursor, RecurringJobInfo, RecurringJobSchedule,
+};
+pub use retry_policy::{
+    DecorrelatedJitter, ExponentialBackoff, FixedBackoff, LinearBackoff, Permanent, RetryContext,
+    RetryDecision, RetryPolicy,
+};
 pub use worker::{Worker, WorkerBuilder};
+pub use worker_list::{RunningJobInfo, WorkerInfo};
+
+#[doc(hidden)]
+pub mod __private {
+    //! Items used by the code generated from macros.
+    pub use uuid::Uuid;
+}
 
 pub(crate) type SmartString = smartstring::SmartString<smartstring::LazyCompact>;
 
@@ -1,4 +1,4 @@
-use std::{path::Path, sync::Arc, time::Duration};
+use std::{future::Future, path::Path, sync::Arc, time::Duration};
 
 use deadpool_sqlite::{Hook, HookError};
 use rusqlite::Connection;
@@ -6,20 +6,34 @@ use tokio::task::JoinHandle;
 use tracing::info;
 
 use crate::{
+    blob_store::BlobStore,
+    compression::Compression,
     db_writer::{db_writer_worker, handle_active_jobs_at_startup, DbOperation, DbOperationType},
+    encryption::KeyProvider,
     error::*,
+    hooks::{hook_fn, JobHooks},
     pending_jobs::monitor_pending_jobs,
+    priority_aging::{configure_priority_aging, PriorityAging},
     shared_state::{SharedState, SharedStateData},
     sqlite_functions::register_functions,
+    sweeper::start_sweeper,
     worker::log_error,
     worker_list::Workers,
-    JobRecoveryBehavior,
+    JobHookEvent, JobRecoveryBehavior,
 };
 
 /// Options used to configure a [Queue] instance.
 pub struct QueueOptions<'a> {
     path: &'a Path,
     job_recovery_behavior: JobRecoveryBehavior,
+    sweep_interval: Duration,
+    priority_aging: Option<Duration>,
+    compression: Option<Compression>,
+    encryption_keys: Option<Arc<dyn KeyProvider>>,
+    blob_threshold: Option<usize>,
+    hooks: JobHooks,
+    #[cfg(feature = "metrics")]
+    metrics_interval: Duration,
 }
 
 impl<'a> QueueOptions<'a> {
@@ -28,6 +42,14 @@ impl<'a> QueueOptions<'a> {
         QueueOptions {
             path,
             job_recovery_behavior: JobRecoveryBehavior::FailAndRetryImmediately,
+            sweep_interval: Duration::from_secs(60),
+            priority_aging: None,
+            compression: None,
+            encryption_keys: None,
+            blob_threshold: None,
+            hooks: JobHooks::default(),
+            #[cfg(feature = "metrics")]
+            metrics_interval: Duration::from_secs(15),
         }
     }
 
@@ -39,6 +61,115 @@ impl<'a> QueueOptions<'a> {
         self
     }
 
+    /// How often the [Queue] checks for pending jobs that have passed their start deadline.
+    /// Jobs are also checked whenever a worker looks for jobs to run. Defaults to 60 seconds, and
+    /// a zero interval turns off the periodic checks.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Prevent a steady stream of high-priority jobs from starving lower-priority jobs. When set,
+    /// a pending job's effective priority increases by one for every `interval` that it has been
+    /// ready to run, so a job with priority 1 that has been waiting for `interval` will run
+    /// before a job with priority 1 that just became ready, and tie with a new job of priority 2.
+    ///
+    /// The interval is rounded down to whole seconds, with a minimum of one second. By default,
+    /// jobs run in strict priority order.
+    pub fn priority_aging(mut self, interval: Duration) -> Self {
+        self.priority_aging = Some(interval);
+        self
+    }
+
+    /// Compress job payloads and checkpoints when they are written to the database. Payloads are
+    /// always decompressed when read, regardless of this setting. By default, payloads are not
+    /// compressed.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Encrypt job payloads, checkpoints, and the info returned from each run, using keys from
+    /// `keys`. Data written without encryption can still be read after this is turned on.
+    pub fn encryption(mut self, keys: impl KeyProvider) -> Self {
+        self.encryption_keys = Some(Arc::new(keys));
+        self
+    }
+
+    /// Store payloads of at least `threshold` bytes in files next to the database instead of in
+    /// the database itself. This keeps large payloads from slowing down queries on the jobs table.
+    /// Files are stored in a directory with the same name as the database file plus `-blobs`, and
+    /// are deleted once no job refers to them. By default, all payloads are stored in the database.
+    pub fn blob_threshold(mut self, threshold: usize) -> Self {
+        self.blob_threshold = Some(threshold);
+        self
+    }
+
+    /// How often to update the gauges for the number of pending and running jobs. The other
+    /// metrics are recorded as they happen. Defaults to 15 seconds, and a zero interval turns off
+    /// the gauges.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_interval(mut self, interval: Duration) -> Self {
+        self.metrics_interval = interval;
+        self
+    }
+
+    /// Run `hook` when a job starts running.
+    ///
+    /// Hooks run in their own task after the change has been saved to the database, so a slow
+    /// hook does not hold up the job or the queue. Hooks for the same job may run concurrently.
+    pub fn on_start<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(JobHookEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_start = Some(hook_fn(hook));
+        self
+    }
+
+    /// Run `hook` when a job succeeds. See [QueueOptions::on_start] for how hooks are run.
+    pub fn on_success<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(JobHookEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_success = Some(hook_fn(hook));
+        self
+    }
+
+    /// Run `hook` when a job fails and is scheduled to run again. See [QueueOptions::on_start]
+    /// for how hooks are run.
+    pub fn on_retry_scheduled<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(JobHookEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_retry_scheduled = Some(hook_fn(hook));
+        self
+    }
+
+    /// Run `hook` when a job fails and will not be retried. See [QueueOptions::on_start] for how
+    /// hooks are run.
+    pub fn on_permanent_failure<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(JobHookEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_permanent_failure = Some(hook_fn(hook));
+        self
+    }
+
+    /// Run `hook` when a job is cancelled, either by [Queue::cancel_job] or by a
+    /// [RetryPolicy](crate::RetryPolicy). See [QueueOptions::on_start] for how hooks are run.
+    pub fn on_cancel<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(JobHookEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_cancel = Some(hook_fn(hook));
+        self
+    }
+
     /// Build a [Queue] from this options object.
     pub async fn build(self) -> Result<Queue> {
         Queue::with_options(self).await
@@ -49,6 +180,9 @@ struct Tasks {
     close: tokio::sync::watch::Sender<()>,
     worker_count_rx: tokio::sync::watch::Receiver<usize>,
     _pending_jobs_monitor: JoinHandle<()>,
+    _sweeper: Option<JoinHandle<()>>,
+    #[cfg(feature = "metrics")]
+    _metrics_gauges: Option<JoinHandle<()>>,
     db_write_worker: std::thread::JoinHandle<()>,
 }
 
@@ -81,6 +215,9 @@ impl Queue {
         register_functions(&mut conn)?;
         crate::migrations::migrate(&mut conn)?;
 
+        let priority_aging = options.priority_aging.map(PriorityAging::new);
+        configure_priority_aging(&conn, priority_aging.as_ref())?;
+
         let (close_tx, close_rx) = tokio::sync::watch::channel(());
 
         let read_conn_pool = deadpool_sqlite::Config::new(options.path)
@@ -113,6 +250,12 @@ impl Queue {
             time: crate::shared_state::Time::new(),
             pending_jobs_tx,
             db_write_tx,
+            priority_aging,
+            fairness_cursor: std::sync::Mutex::new(String::new()),
+            compression: options.compression,
+            encryption_keys: options.encryption_keys,
+            blob_store: Arc::new(BlobStore::new(options.path, options.blob_threshold)),
+            hooks: options.hooks,
         }));
 
         // Handle any jobs that were not cleanly finished from a previous run.
@@ -130,6 +273,13 @@ impl Queue {
         let pending_jobs_monitor =
             monitor_pending_jobs(shared_state.clone(), pending_jobs_rx).await?;
 
+        let sweeper = (!options.sweep_interval.is_zero())
+            .then(|| start_sweeper(shared_state.clone(), options.sweep_interval));
+        #[cfg(feature = "metrics")]
+        let metrics_gauges = (!options.metrics_interval.is_zero()).then(|| {
+            crate::queue_metrics::start_gauge_task(shared_state.clone(), options.metrics_interval)
+        });
+
         // TODO Optional task to delete old jobs from `done_jobs`
 
         let q = Queue {
@@ -138,6 +288,9 @@ impl Queue {
                 close: close_tx,
                 worker_count_rx,
                 _pending_jobs_monitor: pending_jobs_monitor,
+                _sweeper: sweeper,
+                #[cfg(feature = "metrics")]
+                _metrics_gauges: metrics_gauges,
                 db_write_worker,
             })),
         };
@@ -230,7 +383,8 @@ mod tests {
             TestEnvironment,
         },
         worker::Worker,
-        Error, Job, JobBuilder, Queue,
+        DecorrelatedJitter, Error, FixedBackoff, Job, JobBuilder, LinearBackoff, Permanent, Queue,
+        RetryContext, RetryDecision, RetryPolicy, RunningJob,
     };
 
     #[tokio::test]
@@ -429,9 +583,68 @@ mod tests {
         assert_eq!(test.context.get_values().await, &["job 1", "job 2"]);
     }
 
+    mod expire {
+        use super::*;
+
+        #[tokio::test(start_paused = true)]
+        async fn expire_when_fetching_jobs() {
+            let test = TestEnvironment::new().await;
+            let _worker = test.worker().build().await.expect("failed to build worker");
+
+            let now = test.time.now();
+            let job_id = Job::builder("counter")
+                .run_at(now + Duration::from_secs(10))
+                .expire_if_not_started_by(now + Duration::from_secs(5))
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+
+            let status =
+                wait_for_job_status("job to expire", &test.queue, job_id, JobState::Expired).await;
+            assert_eq!(status.run_info.len(), 0);
+            assert!(status.started_at.is_none());
+            assert_eq!(
+                test.context
+                    .counter
+                    .load(std::sync::atomic::Ordering::Relaxed),
+                0
+            );
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn expire_in_sweep() {
+            let test = TestEnvironment::new().await;
+
+            let now = test.time.now();
+            let job_id = Job::builder("counter")
+                .expire_if_not_started_by(now + Duration::from_secs(5))
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+
+            // No worker is running, so only the periodic sweep will find the job.
+            tokio::time::sleep(Duration::from_secs(61)).await;
+            wait_for_job_status("job to expire", &test.queue, job_id, JobState::Expired).await;
+        }
+
+        #[tokio::test]
+        async fn run_before_deadline() {
+            let test = TestEnvironment::new().await;
+            let _worker = test.worker().build().await.expect("failed to build worker");
+
+            let job_id = Job::builder("counter")
+                .expire_if_not_started_by(test.time.now() + Duration::from_secs(600))
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+
+            wait_for_job("job to run", &test.queue, job_id).await;
+        }
+    }
+
     mod retry {
         use super::*;
-        use crate::test_util::wait_for_job_status;
+        use crate::test_util::{wait_for_job_fn, wait_for_job_status};
 
         #[tokio::test(start_paused = true)]
         async fn success_after_retry() {
@@ -497,6 +710,263 @@ mod tests {
         async fn backoff_times() {
             todo!();
         }
+
+        #[tokio::test(start_paused = true)]
+        async fn permanent_error() {
+            let mut test = TestEnvironment::new().await;
+
+            let permanent_job = JobRunner::builder(
+                "permanent_error",
+                |_job, _context: Arc<TestContext>| async move {
+                    Err::<(), _>(Permanent::new("bad payload"))
+                },
+            )
+            .build();
+            test.registry.add(&permanent_job);
+
+            let _worker = test.worker().build().await.expect("failed to build worker");
+
+            let job_id = Job::builder("permanent_error")
+                .max_retries(3)
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+
+            let status =
+                wait_for_job_status("job to fail", &test.queue, job_id, JobState::Failed).await;
+            assert_eq!(status.run_info.len(), 1);
+            assert_eq!(status.run_info[0].info.to_string(), "\"bad payload\"");
+        }
+
+        #[derive(Debug)]
+        struct CancelOnTry(i32);
+
+        impl RetryPolicy for CancelOnTry {
+            fn decide(&self, context: &RetryContext<'_>) -> RetryDecision {
+                if context.current_try >= self.0 {
+                    RetryDecision::Cancel
+                } else {
+                    RetryDecision::Retry(Duration::from_secs(5))
+                }
+            }
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn policy_cancels_job() {
+            let mut test = TestEnvironment::new().await;
+
+            let job = JobRunner::builder(
+                "always_fail",
+                |job: RunningJob, _context: Arc<TestContext>| async move {
+                    Err::<(), _>(format!("fail on try {}", job.current_try))
+                },
+            )
+            .retry_policy(CancelOnTry(1))
+            .build();
+            test.registry.add(&job);
+
+            let _worker = test.worker().build().await.expect("failed to build worker");
+
+            let job_id = Job::builder("always_fail")
+                .max_retries(5)
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+
+            let status = wait_for_job_status(
+                "job to be cancelled",
+                &test.queue,
+                job_id,
+                JobState::Cancelled,
+            )
+            .await;
+            assert_eq!(status.run_info.len(), 2);
+
+            let retry_time = status.run_info[1].start - status.run_info[0].end;
+            assert!(retry_time >= Duration::from_secs(5));
+            assert!(retry_time < Duration::from_secs(7));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn fixed_backoff_policy() {
+            let mut test = TestEnvironment::new().await;
+
+            let job = JobRunner::builder(
+                "fixed_backoff",
+                |job: RunningJob, _context: Arc<TestContext>| async move {
+                    Err::<(), _>(format!("fail on try {}", job.current_try))
+                },
+            )
+            .retry_policy(FixedBackoff::new(Duration::from_secs(3)))
+            .build();
+            test.registry.add(&job);
+
+            let _worker = test.worker().build().await.expect("failed to build worker");
+
+            let job_id = Job::builder("fixed_backoff")
+                .max_retries(2)
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+
+            let status =
+                wait_for_job_status("job to fail", &test.queue, job_id, JobState::Failed).await;
+            assert_eq!(status.run_info.len(), 3);
+            for runs in status.run_info.windows(2) {
+                let retry_time = runs[1].start - runs[0].end;
+                assert!(retry_time >= Duration::from_secs(3));
+                assert!(retry_time < Duration::from_secs(5));
+            }
+        }
+
+        // This uses real time, since paused time would jump ahead to the far-off retry.
+        #[tokio::test]
+        async fn unbounded_backoff() {
+            let mut test = TestEnvironment::new().await;
+
+            let linear = JobRunner::builder(
+                "linear_backoff",
+                |_job: RunningJob, _context: Arc<TestContext>| async move { Err::<(), _>("fail") },
+            )
+            .retry_policy(LinearBackoff::new(Duration::MAX, Duration::ZERO))
+            .build();
+            let jitter = JobRunner::builder(
+                "jitter_backoff",
+                |_job: RunningJob, _context: Arc<TestContext>| async move { Err::<(), _>("fail") },
+            )
+            .retry_policy(DecorrelatedJitter::new(Duration::MAX, Duration::MAX))
+            .build();
+            test.registry.add(&linear);
+            test.registry.add(&jitter);
+
+            let _worker = test.worker().build().await.expect("failed to build worker");
+
+            // Delays too long to represent push the job as far into the future as possible,
+            // instead of overflowing or panicking.
+            for job_type in ["linear_backoff", "jitter_backoff"] {
+                let job_id = Job::builder(job_type)
+                    .add_to(&test.queue)
+                    .await
+                    .expect("failed to add job");
+
+                let status = wait_for_job_fn("job to fail once", &test.queue, job_id, |status| {
+                    status.run_info.len() == 1
+                })
+                .await;
+                assert_eq!(status.state, JobState::Pending);
+                assert_eq!(status.run_at.expect("run_at").year(), 9999);
+            }
+        }
+    }
+
+    mod snooze {
+        use super::*;
+        use crate::test_util::wait_for_job_fn;
+
+        #[tokio::test(start_paused = true)]
+        async fn snooze_then_succeed() {
+            let mut test = TestEnvironment::new().await;
+
+            let snooze_job = JobRunner::builder(
+                "snooze_once",
+                |job: RunningJob, context: Arc<TestContext>| async move {
+                    let runs = context
+                        .counter
+                        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    if runs == 0 {
+                        job.snooze(Duration::from_secs(30)).await?;
+                    }
+
+                    Ok::<_, Error>(format!("try {}", job.current_try))
+                },
+            )
+            .build();
+            test.registry.add(&snooze_job);
+
+            let _worker = test.worker().build().await.expect("failed to build worker");
+
+            let job_id = Job::builder("snooze_once")
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+
+            let status = wait_for_job("job to run", &test.queue, job_id).await;
+            assert_eq!(status.run_info.len(), 2);
+            assert!(status.run_info[0].snoozed);
+            assert!(!status.run_info[0].success);
+            assert!(status.run_info[1].success);
+            // Snoozing doesn't use up a retry.
+            assert_eq!(status.run_info[1].info.to_string(), "\"try 0\"");
+            assert_eq!(status.current_try, Some(0));
+
+            let snooze_time = status.run_info[1].start - status.run_info[0].end;
+            assert!(snooze_time >= Duration::from_secs(30));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn snooze_limit() {
+            let mut test = TestEnvironment::new().await;
+
+            let snooze_job = JobRunner::builder(
+                "snooze_forever",
+                |job: RunningJob, _context: Arc<TestContext>| async move {
+                    job.snooze(Duration::from_secs(5)).await?;
+                    Ok::<_, Error>(())
+                },
+            )
+            .build();
+            test.registry.add(&snooze_job);
+
+            let _worker = test.worker().build().await.expect("failed to build worker");
+
+            let job_id = Job::builder("snooze_forever")
+                .max_snoozes(2)
+                .max_retries(0)
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+
+            let status =
+                wait_for_job_status("job to fail", &test.queue, job_id, JobState::Failed).await;
+            assert_eq!(status.run_info.len(), 3);
+            assert!(status.run_info[0].snoozed);
+            assert!(status.run_info[1].snoozed);
+            assert!(!status.run_info[2].snoozed);
+            assert_eq!(
+                status.run_info[2].info.to_string(),
+                "\"Job has reached its snooze limit\""
+            );
+        }
+
+        // This uses real time, since paused time would jump ahead to the far-off run time.
+        #[tokio::test]
+        async fn snooze_too_long() {
+            let mut test = TestEnvironment::new().await;
+
+            let snooze_job = JobRunner::builder(
+                "snooze_max",
+                |job: RunningJob, _context: Arc<TestContext>| async move {
+                    job.snooze(Duration::MAX).await?;
+                    Ok::<_, Error>(())
+                },
+            )
+            .build();
+            test.registry.add(&snooze_job);
+
+            let _worker = test.worker().build().await.expect("failed to build worker");
+
+            let job_id = Job::builder("snooze_max")
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+
+            let status = wait_for_job_fn("job to snooze", &test.queue, job_id, |status| {
+                status.run_info.len() == 1
+            })
+            .await;
+            assert_eq!(status.state, JobState::Pending);
+            assert_eq!(status.run_at.expect("run_at").year(), 9999);
+        }
     }
 
     #[tokio::test(start_paused = true)]
@@ -788,6 +1258,144 @@ mod tests {
         assert_eq!(test.context.get_values().await, vec!["high", "low"]);
     }
 
+    #[tokio::test]
+    async fn job_priority_aging() {
+        let test = TestEnvironment::with_options(|options| {
+            options.priority_aging(Duration::from_secs(10))
+        })
+        .await;
+
+        let now = test.time.now();
+
+        // The low priority job has been waiting long enough that its effective priority is 4,
+        // so it should run before the high priority job.
+        let low_prio = Job::builder("push_payload")
+            .payload(serde_json::to_vec("low").unwrap())
+            .priority(1)
+            .run_at(now - Duration::from_secs(30))
+            .add_to(&test.queue)
+            .await
+            .expect("adding low priority job");
+
+        let high_prio = Job::builder("push_payload")
+            .payload(serde_json::to_vec("high").unwrap())
+            .priority(2)
+            .run_at(now - Duration::from_secs(5))
+            .add_to(&test.queue)
+            .await
+            .expect("adding high priority job");
+
+        let _worker = test
+            .worker()
+            .max_concurrency(1)
+            .build()
+            .await
+            .expect("failed to build worker");
+
+        wait_for_job("low priority job to run", &test.queue, low_prio).await;
+        wait_for_job("high priority job to run", &test.queue, high_prio).await;
+
+        assert_eq!(test.context.get_values().await, vec!["low", "high"]);
+    }
+
+    #[tokio::test]
+    async fn fairness_key() {
+        let test = TestEnvironment::new().await;
+
+        let now = test.time.now();
+
+        // Tenant "a" has a large backlog that was enqueued first, but tenant "b" should still get
+        // a turn after each job from tenant "a".
+        let mut jobs = Vec::new();
+        for i in 0..5 {
+            jobs.push(
+                Job::builder("push_payload")
+                    .fairness_key("a")
+                    .payload(serde_json::to_vec(&format!("a{i}")).unwrap())
+                    .run_at(now - Duration::from_secs(10))
+                    .build(),
+            );
+        }
+
+        for i in 0..2 {
+            jobs.push(
+                Job::builder("push_payload")
+                    .fairness_key("b")
+                    .payload(serde_json::to_vec(&format!("b{i}")).unwrap())
+                    .run_at(now - Duration::from_secs(5))
+                    .build(),
+            );
+        }
+
+        let ids = test.queue.add_jobs(jobs).await.expect("adding jobs");
+
+        let _worker = test
+            .worker()
+            .max_concurrency(1)
+            .build()
+            .await
+            .expect("failed to build worker");
+
+        for id in ids {
+            wait_for_job("job to run", &test.queue, id).await;
+        }
+
+        assert_eq!(
+            test.context.get_values().await,
+            vec!["a0", "b0", "a1", "b1", "a2", "a3", "a4"]
+        );
+    }
+
+    #[tokio::test]
+    async fn fairness_key_skips_job_that_does_not_fit() {
+        let test = TestEnvironment::new().await;
+        let _worker = test
+            .worker()
+            .max_concurrency(2)
+            .build()
+            .await
+            .expect("failed to build worker");
+
+        let blocker = Job::builder("wait_for_watch")
+            .fairness_key("z")
+            .json_payload(&1)
+            .unwrap()
+            .add_to(&test.queue)
+            .await
+            .expect("adding job");
+        wait_for_job_status("blocker to start", &test.queue, blocker, JobState::Running).await;
+
+        // The job from tenant "a" is too heavy to run next to the blocker, but it shouldn't keep
+        // the job from tenant "b" waiting.
+        let ids = test
+            .queue
+            .add_jobs(vec![
+                Job::builder("push_payload")
+                    .fairness_key("a")
+                    .weight(2)
+                    .json_payload(&"a0")
+                    .unwrap()
+                    .build(),
+                Job::builder("push_payload")
+                    .fairness_key("b")
+                    .json_payload(&"b0")
+                    .unwrap()
+                    .build(),
+            ])
+            .await
+            .expect("adding jobs");
+
+        wait_for_job("tenant b job to run", &test.queue, ids[1]).await;
+        let status = test.queue.get_job_status(ids[0]).await.unwrap();
+        assert_eq!(status.state, JobState::Pending);
+
+        test.context.watch_tx.send(1).unwrap();
+        wait_for_job("blocker to finish", &test.queue, blocker).await;
+        wait_for_job("tenant a job to run", &test.queue, ids[0]).await;
+
+        assert_eq!(test.context.get_values().await, vec!["b0", "a0"]);
+    }
+
     #[tokio::test]
     async fn checkpoint() {
         let mut test = TestEnvironment::new().await;
@@ -955,6 +1563,135 @@ mod tests {
             assert_eq!(counts.finished, 10);
         }
 
+        #[tokio::test]
+        async fn resource_limits() {
+            let test = TestEnvironment::new().await;
+
+            let mut jobs = Vec::new();
+            for _ in 0..10 {
+                let job_id = Job::builder("max_count")
+                    .resource("cpu", 2)
+                    .resource("db", 1)
+                    .resource("mem_mb", 512)
+                    .add_to(&test.queue)
+                    .await
+                    .expect("Adding job");
+                jobs.push(job_id);
+            }
+
+            let too_big = Job::builder("max_count")
+                .resource("db", 3)
+                .add_to(&test.queue)
+                .await
+                .expect("Adding job");
+
+            let _worker = test
+                .worker()
+                .max_concurrency(10)
+                .resource_capacity("cpu", 8)
+                .resource_capacity("db", 2)
+                .build()
+                .await
+                .expect("failed to build worker");
+
+            for job_id in jobs {
+                wait_for_job("job to succeed", &test.queue, job_id).await;
+            }
+
+            // The jobs would fit four at a time by CPU, but there are only two database
+            // connections. Memory is not limited on this worker.
+            assert_eq!(test.context.max_count().await, 2);
+
+            // A job that needs more than the worker has never runs there.
+            let status = test.queue.get_job_status(too_big).await.unwrap();
+            assert_eq!(status.state, JobState::Pending);
+            assert_eq!(status.resources.get("db"), Some(&3));
+
+            let workers = test.queue.list_workers().await;
+            assert_eq!(workers[0].resource_capacity.get("db"), Some(&2));
+            assert!(workers[0].resources_in_use.values().all(|&used| used == 0));
+
+            test.queue.cancel_job(too_big).await.unwrap();
+        }
+
+        #[tokio::test]
+        async fn worker_labels() {
+            let test = TestEnvironment::new().await;
+
+            let eu_worker = test
+                .worker()
+                .max_concurrency(10)
+                .label("region", "eu")
+                .label("volume", "models")
+                .build()
+                .await
+                .expect("failed to build worker");
+            let us_worker = test
+                .worker()
+                .max_concurrency(10)
+                .label("region", "us")
+                .build()
+                .await
+                .expect("failed to build worker");
+
+            let mut eu_jobs = Vec::new();
+            for _ in 0..3 {
+                let job_id = Job::builder("counter")
+                    .require_label("region", "eu")
+                    .require_label("volume", "models")
+                    .add_to(&test.queue)
+                    .await
+                    .expect("Adding job");
+                eu_jobs.push(job_id);
+            }
+
+            let us_job = Job::builder("counter")
+                .require_label("region", "us")
+                .add_to(&test.queue)
+                .await
+                .expect("Adding job");
+            let any_job = Job::builder("counter")
+                .add_to(&test.queue)
+                .await
+                .expect("Adding job");
+            let gpu_job = Job::builder("counter")
+                .require_label("gpu", "a100")
+                .add_to(&test.queue)
+                .await
+                .expect("Adding job");
+
+            for job_id in eu_jobs.iter().chain([&us_job, &any_job]) {
+                wait_for_job("job to succeed", &test.queue, *job_id).await;
+            }
+
+            // The unlabeled job can run on either worker.
+            let eu_started = eu_worker.counts().started;
+            let us_started = us_worker.counts().started;
+            assert!(eu_started >= 3, "eu worker started {eu_started} jobs");
+            assert!(us_started >= 1, "us worker started {us_started} jobs");
+            assert_eq!(eu_started + us_started, 5);
+
+            // No worker has the label, so the job doesn't run.
+            let status = test.queue.get_job_status(gpu_job).await.unwrap();
+            assert_eq!(status.state, JobState::Pending);
+            assert_eq!(
+                status.required_labels.get("gpu").map(|v| v.as_str()),
+                Some("a100")
+            );
+
+            let workers = test.queue.list_workers().await;
+            assert_eq!(
+                workers[0].labels.get("region").map(|v| v.as_str()),
+                Some("eu")
+            );
+            assert_eq!(
+                workers[1].labels.get("region").map(|v| v.as_str()),
+                Some("us")
+            );
+
+            test.queue.cancel_job(gpu_job).await.unwrap();
+        }
+
         #[tokio::test]
         async fn fetches_again_at_min_concurrency() {
             let mut test = TestEnvironment::new().await;
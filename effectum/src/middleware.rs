@@ -0,0 +1,381 @@
+use std::{panic::AssertUnwindSafe, sync::Arc};
+
+use futures::{future::BoxFuture, FutureExt};
+use serde_json::value::RawValue;
+use tracing::{span, Instrument, Level};
+
+use crate::{trace_context::connect_span, RetryDecision, RunningJob};
+
+/// The result of running a job, as seen by a [JobMiddleware].
+#[derive(Debug)]
+pub enum JobOutcome {
+    /// The job succeeded. This contains the JSON-encoded information to store in the job's
+    /// [RunInfo](crate::RunInfo).
+    Succeeded(Box<RawValue>),
+    /// The job returned an error.
+    Failed {
+        /// The error message to store in the job's run info.
+        message: String,
+        /// What to do with the job, as decided by its [RetryPolicy](crate::RetryPolicy).
+        decision: RetryDecision,
+    },
+    /// The job panicked. This contains the panic message.
+    Panicked(String),
+}
+
+impl JobOutcome {
+    /// Create a successful outcome, encoding `info` as JSON.
+    pub fn succeeded<T: serde::Serialize>(info: &T) -> Result<Self, serde_json::Error> {
+        serde_json::value::to_raw_value(info).map(Self::Succeeded)
+    }
+}
+
+/// Code that runs around every job that a worker runs, for setup and teardown that would
+/// otherwise be repeated in each job, such as timing, error reporting, or setting up a
+/// per-job scope.
+///
+/// A middleware calls [Next::run] to run the rest of the chain and the job itself, and can
+/// inspect or replace the [JobOutcome] that it returns. It can also return an outcome without
+/// calling [Next::run], in which case the job does not run.
+///
+/// Middleware is added with [JobRegistry::add_middleware](crate::JobRegistry::add_middleware)
+/// or [WorkerBuilder::middleware](crate::WorkerBuilder::middleware). The chain always runs in
+/// the same order, from the outermost middleware to the innermost:
+///
+/// 1. The built-in tracing middleware, which runs the rest of the chain inside a `run_job` span.
+/// 2. The built-in panic middleware, which turns a panic in any middleware below it into
+///    [JobOutcome::Panicked], so that the job fails instead of waiting to expire.
+/// 3. Middleware added to the [WorkerBuilder](crate::WorkerBuilder), in the order it was added.
+/// 4. Middleware added to the [JobRegistry](crate::JobRegistry), in the order it was added.
+/// 5. The built-in panic middleware again, which turns a panic in the job itself into
+///    [JobOutcome::Panicked] so that the middleware above can see it.
+///
+/// ```
+/// # use effectum::*;
+/// # use futures::future::BoxFuture;
+/// # use std::sync::Arc;
+/// #[derive(Debug)]
+/// pub struct JobContext {
+///   // database pool or other things here
+/// }
+///
+/// struct Timing;
+///
+/// impl JobMiddleware<Arc<JobContext>> for Timing {
+///     fn call<'a>(
+///         &'a self,
+///         job: &'a RunningJob,
+///         _context: &'a Arc<JobContext>,
+///         next: Next<'a, Arc<JobContext>>,
+///     ) -> BoxFuture<'a, JobOutcome> {
+///         Box::pin(async move {
+///             let start = std::time::Instant::now();
+///             let outcome = next.run().await;
+///             println!("{} took {:?}", job.job_type, start.elapsed());
+///             outcome
+///         })
+///     }
+/// }
+///
+/// let mut registry = JobRegistry::<Arc<JobContext>>::new(Vec::<JobRunner<_>>::new());
+/// registry.add_middleware(Timing);
+/// ```
+pub trait JobMiddleware<CONTEXT>: Send + Sync + 'static {
+    /// Run the middleware for a job.
+    fn call<'a>(
+        &'a self,
+        job: &'a RunningJob,
+        context: &'a CONTEXT,
+        next: Next<'a, CONTEXT>,
+    ) -> BoxFuture<'a, JobOutcome>;
+}
+
+pub(crate) type MiddlewareStack<CONTEXT> = Arc<[Arc<dyn JobMiddleware<CONTEXT>>]>;
+
+/// The rest of the middleware chain, ending with the job itself.
+pub struct Next<'a, CONTEXT> {
+    job: &'a RunningJob,
+    context: &'a CONTEXT,
+    middleware: &'a [Arc<dyn JobMiddleware<CONTEXT>>],
+    runner: BoxFuture<'a, JobOutcome>,
+}
+
+impl<'a, CONTEXT: 'static> Next<'a, CONTEXT> {
+    pub(crate) fn new(
+        job: &'a RunningJob,
+        context: &'a CONTEXT,
+        middleware: &'a [Arc<dyn JobMiddleware<CONTEXT>>],
+        runner: BoxFuture<'a, JobOutcome>,
+    ) -> Self {
+        Self {
+            job,
+            context,
+            middleware,
+            runner,
+        }
+    }
+
+    /// Run the rest of the chain and return the job's outcome.
+    pub async fn run(self) -> JobOutcome {
+        match self.middleware.split_first() {
+            Some((first, rest)) => {
+                let next = Next {
+                    middleware: rest,
+                    ..self
+                };
+                first.call(next.job, next.context, next).await
+            }
+            None => self.runner.await,
+        }
+    }
+}
+
+/// Runs the rest of the chain inside a `run_job` span.
+pub(crate) struct TracingMiddleware;
+
+impl<CONTEXT: Send + Sync + 'static> JobMiddleware<CONTEXT> for TracingMiddleware {
+    fn call<'a>(
+        &'a self,
+        job: &'a RunningJob,
+        _context: &'a CONTEXT,
+        next: Next<'a, CONTEXT>,
+    ) -> BoxFuture<'a, JobOutcome> {
+        let span = span!(Level::INFO, "run_job", %job);
+        connect_span(&span, job);
+        next.run().instrument(span).boxed()
+    }
+}
+
+/// Turns a panic in the job into [JobOutcome::Panicked].
+pub(crate) struct CatchPanicMiddleware;
+
+impl<CONTEXT: Send + Sync + 'static> JobMiddleware<CONTEXT> for CatchPanicMiddleware {
+    fn call<'a>(
+        &'a self,
+        _job: &'a RunningJob,
+        _context: &'a CONTEXT,
+        next: Next<'a, CONTEXT>,
+    ) -> BoxFuture<'a, JobOutcome> {
+        async move {
+            match AssertUnwindSafe(next.run()).catch_unwind().await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    let msg = if let Some(s) = e.downcast_ref::<&str>() {
+                        s.to_string()
+                    } else if let Some(s) = e.downcast_ref::<String>() {
+                        s.clone()
+                    } else {
+                        "Panic".to_string()
+                    };
+                    JobOutcome::Panicked(msg)
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Build the full middleware chain for a worker, including the built-in middleware.
+pub(crate) fn middleware_stack<CONTEXT: Send + Sync + 'static>(
+    worker: &[Arc<dyn JobMiddleware<CONTEXT>>],
+    registry: &[Arc<dyn JobMiddleware<CONTEXT>>],
+) -> MiddlewareStack<CONTEXT> {
+    let catch_panic = Arc::new(CatchPanicMiddleware) as Arc<dyn JobMiddleware<CONTEXT>>;
+    std::iter::once(Arc::new(TracingMiddleware) as Arc<dyn JobMiddleware<CONTEXT>>)
+        .chain(std::iter::once(catch_panic.clone()))
+        .chain(worker.iter().cloned())
+        .chain(registry.iter().cloned())
+        .chain(std::iter::once(catch_panic))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{wait_for_job, wait_for_job_status, TestContext, TestEnvironment},
+        Job, JobRunner, JobState,
+    };
+
+    /// Records when it runs, and what the outcome was.
+    struct Record(&'static str);
+
+    impl JobMiddleware<Arc<TestContext>> for Record {
+        fn call<'a>(
+            &'a self,
+            _job: &'a RunningJob,
+            context: &'a Arc<TestContext>,
+            next: Next<'a, Arc<TestContext>>,
+        ) -> BoxFuture<'a, JobOutcome> {
+            async move {
+                context.push_str(format!("{} before", self.0)).await;
+                let outcome = next.run().await;
+                let result = match &outcome {
+                    JobOutcome::Succeeded(_) => "succeeded",
+                    JobOutcome::Failed { .. } => "failed",
+                    JobOutcome::Panicked(_) => "panicked",
+                };
+                context.push_str(format!("{} {result}", self.0)).await;
+                outcome
+            }
+            .boxed()
+        }
+    }
+
+    /// Cancels jobs without running them.
+    struct Skip;
+
+    impl JobMiddleware<Arc<TestContext>> for Skip {
+        fn call<'a>(
+            &'a self,
+            _job: &'a RunningJob,
+            _context: &'a Arc<TestContext>,
+            _next: Next<'a, Arc<TestContext>>,
+        ) -> BoxFuture<'a, JobOutcome> {
+            async move {
+                JobOutcome::Failed {
+                    message: "skipped".to_string(),
+                    decision: RetryDecision::Cancel,
+                }
+            }
+            .boxed()
+        }
+    }
+
+    /// Panics before running the job.
+    struct PanicBefore;
+
+    impl JobMiddleware<Arc<TestContext>> for PanicBefore {
+        fn call<'a>(
+            &'a self,
+            _job: &'a RunningJob,
+            _context: &'a Arc<TestContext>,
+            _next: Next<'a, Arc<TestContext>>,
+        ) -> BoxFuture<'a, JobOutcome> {
+            async move { panic!("middleware panicked") }.boxed()
+        }
+    }
+
+    fn push_job() -> JobRunner<Arc<TestContext>> {
+        JobRunner::builder("mw_push", |_job, context: Arc<TestContext>| async move {
+            context.push_str("job").await;
+            Ok::<_, String>(())
+        })
+        .build()
+    }
+
+    #[tokio::test]
+    async fn middleware_order() {
+        let mut test = TestEnvironment::new().await;
+        test.registry.add(&push_job());
+        test.registry.add_middleware(Record("registry 1"));
+        test.registry.add_middleware(Record("registry 2"));
+
+        let _worker = test
+            .worker()
+            .middleware(Record("worker"))
+            .build()
+            .await
+            .expect("failed to build worker");
+
+        let job_id = Job::builder("mw_push")
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        wait_for_job("job to run", &test.queue, job_id).await;
+
+        assert_eq!(
+            test.context.get_values().await,
+            vec![
+                "worker before",
+                "registry 1 before",
+                "registry 2 before",
+                "job",
+                "registry 2 succeeded",
+                "registry 1 succeeded",
+                "worker succeeded",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn short_circuit() {
+        let mut test = TestEnvironment::new().await;
+        test.registry.add(&push_job());
+        test.registry.add_middleware(Skip);
+
+        let _worker = test
+            .worker()
+            .middleware(Record("worker"))
+            .build()
+            .await
+            .expect("failed to build worker");
+
+        let job_id = Job::builder("mw_push")
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        let status = wait_for_job_status(
+            "job to be cancelled",
+            &test.queue,
+            job_id,
+            JobState::Cancelled,
+        )
+        .await;
+        assert_eq!(status.run_info[0].info.get(), r#""skipped""#);
+        assert_eq!(
+            test.context.get_values().await,
+            vec!["worker before", "worker failed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn middleware_sees_panic() {
+        let mut test = TestEnvironment::new().await;
+        test.registry.add(
+            &JobRunner::builder("mw_panic", |_job, _context: Arc<TestContext>| async move {
+                if true {
+                    panic!("oh no");
+                }
+                Ok::<_, String>(())
+            })
+            .build(),
+        );
+        test.registry.add_middleware(Record("registry"));
+
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let job_id = Job::builder("mw_panic")
+            .max_retries(0)
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        let status =
+            wait_for_job_status("job to fail", &test.queue, job_id, JobState::Failed).await;
+        assert_eq!(status.run_info[0].info.get(), r#""oh no""#);
+        assert_eq!(
+            test.context.get_values().await,
+            vec!["registry before", "registry panicked"]
+        );
+    }
+
+    #[tokio::test]
+    async fn panic_in_middleware() {
+        let mut test = TestEnvironment::new().await;
+        test.registry.add(&push_job());
+        test.registry.add_middleware(PanicBefore);
+
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        // The job fails right away, instead of staying running until it expires.
+        let job_id = Job::builder("mw_push")
+            .max_retries(0)
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        let status =
+            wait_for_job_status("job to fail", &test.queue, job_id, JobState::Failed).await;
+        assert_eq!(status.run_info[0].info.get(), r#""middleware panicked""#);
+        assert!(test.context.get_values().await.is_empty());
+    }
+}
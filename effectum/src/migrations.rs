@@ -3,10 +3,25 @@ use rusqlite_migration::{Migrations, M};
 
 use crate::Result;
 
-const MIGRATIONS: [&str; 3] = [
+const MIGRATIONS: [&str; 18] = [
     include_str!("../migrations/00001-init.sql"),
     include_str!("../migrations/00002-rename-column.sql"),
     include_str!("../migrations/00003-job-name-column.sql"),
+    include_str!("../migrations/00004-manually-triggered.sql"),
+    include_str!("../migrations/00005-from-base-job-index.sql"),
+    include_str!("../migrations/00006-debounce-key.sql"),
+    include_str!("../migrations/00007-start-deadline.sql"),
+    include_str!("../migrations/00008-fairness-key.sql"),
+    include_str!("../migrations/00009-snooze.sql"),
+    include_str!("../migrations/00010-payload-codec.sql"),
+    include_str!("../migrations/00011-payload-compression.sql"),
+    include_str!("../migrations/00012-encryption.sql"),
+    include_str!("../migrations/00013-payload-blobs.sql"),
+    include_str!("../migrations/00014-payload-version.sql"),
+    include_str!("../migrations/00015-trace-context.sql"),
+    include_str!("../migrations/00016-ready-at.sql"),
+    include_str!("../migrations/00017-resources.sql"),
+    include_str!("../migrations/00018-required-labels.sql"),
 ];
 
 fn create_migrations() -> Migrations<'static> {
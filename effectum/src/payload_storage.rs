@@ -0,0 +1,116 @@
+use std::{borrow::Cow, fmt::Debug, sync::Arc};
+
+use serde::Serialize;
+
+use crate::{
+    blob_store::BlobHold,
+    compression::decompress,
+    encryption::{decrypt, encrypt, encrypt_run_info, KeyProvider},
+    job_status::RunInfo,
+    shared_state::SharedStateData,
+    Error, Result,
+};
+
+/// How a payload was transformed when it was written to the database. Payloads are compressed
+/// first and then encrypted, so they are decrypted and then decompressed when read.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PayloadStorage {
+    /// The compression algorithm, if the payload is compressed.
+    pub compression: Option<Cow<'static, str>>,
+    /// The ID of the encryption key, if the payload is encrypted.
+    pub key_id: Option<String>,
+    /// The hash of the payload in the blob store, if it was too large to store in the database.
+    pub blob: Option<String>,
+    /// Keeps a newly-written blob from being garbage collected before the job that references it
+    /// is saved.
+    pub blob_hold: Option<Arc<BlobHold>>,
+}
+
+impl SharedStateData {
+    /// Compress and encrypt a job's payload, and move it to the blob store if it is large. If it
+    /// is moved, the returned payload is empty.
+    pub fn store_payload(&self, payload: Vec<u8>) -> Result<(Vec<u8>, PayloadStorage)> {
+        let (payload, mut storage) = self.encode_payload(payload)?;
+        if !self.blob_store.should_store(payload.len()) {
+            return Ok((payload, storage));
+        }
+
+        let (hash, hold) = self.blob_store.write(&payload)?;
+        storage.blob = Some(hash);
+        storage.blob_hold = Some(Arc::new(hold));
+        Ok((Vec::new(), storage))
+    }
+
+    /// Compress and encrypt a payload according to the queue's settings.
+    pub fn encode_payload(&self, payload: Vec<u8>) -> Result<(Vec<u8>, PayloadStorage)> {
+        let (payload, compression) = match &self.compression {
+            Some(compression) => compression.compress(payload)?,
+            None => (payload, None),
+        };
+
+        let (payload, key_id) = match &self.encryption_keys {
+            Some(keys) => {
+                let (payload, key_id) = encrypt(keys.as_ref(), &payload)?;
+                (payload, Some(key_id))
+            }
+            None => (payload, None),
+        };
+
+        Ok((
+            payload,
+            PayloadStorage {
+                compression: compression.map(Cow::Borrowed),
+                key_id,
+                ..Default::default()
+            },
+        ))
+    }
+
+    /// Serialize the info for a run, encrypting the `info` field if encryption is enabled.
+    pub fn serialize_run_info<T: Serialize + Send + Debug>(
+        &self,
+        run_info: RunInfo<T>,
+    ) -> Result<String> {
+        let json = match &self.encryption_keys {
+            Some(keys) => {
+                let encrypted = encrypt_run_info(keys.as_ref(), run_info)?;
+                serde_json::to_string(&encrypted)
+            }
+            None => serde_json::to_string(&run_info),
+        };
+        json.map_err(Error::InvalidJobRunInfo)
+    }
+
+    /// Read a payload from the database, loading it from the blob store if needed, and decrypt
+    /// and decompress it.
+    pub fn load_payload(
+        &self,
+        compression: Option<&str>,
+        key_id: Option<&str>,
+        blob: Option<&str>,
+        payload: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let payload = match blob {
+            Some(hash) => self.blob_store.read(hash)?,
+            None => payload,
+        };
+
+        decode_payload(
+            self.encryption_keys.as_deref(),
+            compression,
+            key_id,
+            payload,
+        )
+    }
+}
+
+/// Decrypt and decompress a payload.
+pub(crate) fn decode_payload(
+    keys: Option<&dyn KeyProvider>,
+    compression: Option<&str>,
+    key_id: Option<&str>,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let payload = decrypt(keys, key_id, payload)?;
+    decompress(compression, payload)
+}
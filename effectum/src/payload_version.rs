@@ -0,0 +1,233 @@
+use std::{collections::BTreeMap, fmt::Display, sync::Arc};
+
+use serde_json::Value;
+
+use crate::{Error, Result};
+
+type UpgradeFn = Arc<dyn Fn(Value) -> Result<Value, String> + Send + Sync>;
+
+/// The current version of a job type's payload format, and the functions that upgrade payloads
+/// submitted with older versions. This is usually configured through
+/// [JobRunnerBuilder::payload_version](crate::JobRunnerBuilder::payload_version) and
+/// [JobRunnerBuilder::upgrade_payload](crate::JobRunnerBuilder::upgrade_payload).
+///
+/// Upgrades operate on the payload as a [serde_json::Value], so payloads encoded with other codecs
+/// must be decodable into a `Value` to be upgraded.
+#[derive(Clone, Default)]
+pub struct PayloadVersions {
+    /// The current version of the payload format. Jobs with older payloads are upgraded to this
+    /// version before they are deserialized.
+    pub version: u32,
+    upgrades: BTreeMap<u32, UpgradeFn>,
+}
+
+impl std::fmt::Debug for PayloadVersions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PayloadVersions")
+            .field("version", &self.version)
+            .field("upgrades", &self.upgrades.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl PayloadVersions {
+    /// Create a [PayloadVersions] with the given current version and no upgrades.
+    pub fn new(version: u32) -> Self {
+        Self {
+            version,
+            upgrades: BTreeMap::new(),
+        }
+    }
+
+    /// Register a function that upgrades a payload from `from_version` to `from_version + 1`.
+    /// A payload several versions old passes through each upgrade in turn.
+    pub fn add_upgrade<E: Display>(
+        &mut self,
+        from_version: u32,
+        upgrade: impl Fn(Value) -> Result<Value, E> + Send + Sync + 'static,
+    ) {
+        self.upgrades.insert(
+            from_version,
+            Arc::new(move |value| upgrade(value).map_err(|e| e.to_string())),
+        );
+    }
+
+    /// Return an error if a payload with this version can't be handled.
+    pub(crate) fn check(&self, version: u32) -> Result<()> {
+        if version > self.version {
+            return Err(Error::PayloadVersionTooNew {
+                version,
+                supported: self.version,
+            });
+        }
+
+        (version..self.version).try_for_each(|from| {
+            if self.upgrades.contains_key(&from) {
+                Ok(())
+            } else {
+                Err(Error::MissingPayloadUpgrade(from))
+            }
+        })
+    }
+
+    /// Upgrade a payload from `version` to the current version.
+    pub(crate) fn upgrade(&self, version: u32, mut payload: Value) -> Result<Value> {
+        self.check(version)?;
+        for from in version..self.version {
+            let upgrade = &self.upgrades[&from];
+            payload = upgrade(payload).map_err(|e| Error::PayloadUpgrade(from, e))?;
+        }
+
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        test_util::{wait_for_job, wait_for_job_status, TestContext, TestEnvironment},
+        Job, JobRunner, JobState,
+    };
+
+    fn versions() -> PayloadVersions {
+        let mut versions = PayloadVersions::new(2);
+        versions.add_upgrade(0, |v: Value| {
+            Ok::<_, String>(json!({ "name": v.as_str().ok_or("not a string")? }))
+        });
+        versions.add_upgrade(1, |mut v: Value| {
+            v["count"] = json!(1);
+            Ok::<_, String>(v)
+        });
+        versions
+    }
+
+    #[test]
+    fn upgrade_chain() {
+        let versions = versions();
+        assert_eq!(
+            versions.upgrade(0, json!("a")).unwrap(),
+            json!({ "name": "a", "count": 1 })
+        );
+        assert_eq!(
+            versions.upgrade(1, json!({ "name": "b" })).unwrap(),
+            json!({ "name": "b", "count": 1 })
+        );
+        assert_eq!(
+            versions
+                .upgrade(2, json!({ "name": "c", "count": 5 }))
+                .unwrap(),
+            json!({ "name": "c", "count": 5 })
+        );
+
+        assert!(matches!(
+            versions.upgrade(0, json!(5)),
+            Err(Error::PayloadUpgrade(0, _))
+        ));
+        assert!(matches!(
+            versions.upgrade(3, json!({})),
+            Err(Error::PayloadVersionTooNew {
+                version: 3,
+                supported: 2
+            })
+        ));
+
+        let mut missing = PayloadVersions::new(2);
+        missing.add_upgrade(1, |v: Value| Ok::<_, String>(v));
+        assert!(matches!(
+            missing.upgrade(0, json!({})),
+            Err(Error::MissingPayloadUpgrade(0))
+        ));
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Payload {
+        name: String,
+        count: u32,
+    }
+
+    #[tokio::test]
+    async fn upgrade_old_payloads() {
+        let mut test = TestEnvironment::new().await;
+        let job_def =
+            JobRunner::builder("versioned", |job, context: Arc<TestContext>| async move {
+                let payload = job.json_payload::<Payload>().unwrap();
+                context
+                    .push_str(format!("{} {}", payload.name, payload.count))
+                    .await;
+                Ok::<_, String>(())
+            })
+            .payload_version(2)
+            .upgrade_payload(0, |v: Value| {
+                Ok::<_, String>(json!({ "name": v.as_str().ok_or("not a string")? }))
+            })
+            .upgrade_payload(1, |mut v: Value| {
+                v["count"] = json!(1);
+                Ok::<_, String>(v)
+            })
+            .build();
+        test.registry.add(&job_def);
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let old_id = Job::builder("versioned")
+            .json_payload("old")
+            .unwrap()
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        let status = wait_for_job("old job to run", &test.queue, old_id).await;
+        assert_eq!(status.payload_version, 0);
+
+        let current_id = Job::builder("versioned")
+            .json_payload(&Payload {
+                name: "current".to_string(),
+                count: 3,
+            })
+            .unwrap()
+            .payload_version(2)
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        wait_for_job("current job to run", &test.queue, current_id).await;
+
+        assert_eq!(test.context.get_values().await, vec!["old 1", "current 3"]);
+    }
+
+    #[tokio::test]
+    async fn reject_future_version() {
+        let mut test = TestEnvironment::new().await;
+        let job_def =
+            JobRunner::builder("versioned", |_job, context: Arc<TestContext>| async move {
+                context.push_str("ran").await;
+                Ok::<_, String>(())
+            })
+            .payload_version(1)
+            .upgrade_payload(0, |v: Value| Ok::<_, String>(v))
+            .build();
+        test.registry.add(&job_def);
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let job_id = Job::builder("versioned")
+            .json_payload(&"new")
+            .unwrap()
+            .payload_version(2)
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+
+        // The job fails on the first try even though it has retries left.
+        let status =
+            wait_for_job_status("job to fail", &test.queue, job_id, JobState::Failed).await;
+        assert_eq!(status.run_info.len(), 1);
+        assert_eq!(
+            status.run_info[0].info.get(),
+            r#""Payload version 2 is newer than the supported version 1""#
+        );
+        assert!(test.context.get_values().await.is_empty());
+    }
+}
@@ -6,7 +6,7 @@ use time::OffsetDateTime;
 use tokio::{sync::mpsc, task::JoinHandle};
 use tracing::{event, instrument, Level};
 
-use crate::{error::Result, shared_state::SharedState, Error, SmartString};
+use crate::{error::Result, labels::Labels, shared_state::SharedState, Error, SmartString};
 
 pub(crate) type ScheduledJobType = (SmartString, i64);
 
@@ -152,7 +152,10 @@ async fn pending_jobs_task(
                 for job_type in &job_types {
                     event!(Level::DEBUG, %job_type, "Notifying pending jobs");
                     next_times.remove(job_type);
-                    workers.new_job_available(job_type.as_str());
+                    // Only the next run time of each job type is tracked here, not which labels
+                    // its jobs need, so wake every worker for the type. Workers without the
+                    // labels just find nothing to run.
+                    workers.new_job_available(job_type.as_str(), &Labels::new());
                 }
                 drop(workers);
 
@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use rusqlite::Connection;
+
+use crate::Result;
+
+/// The ordering used when priority aging is disabled.
+pub(crate) const DEFAULT_READY_ORDER: &str = "active_jobs.priority DESC, run_at";
+
+const AGING_INDEX_PREFIX: &str = "active_jobs_aging_";
+
+/// Raises the effective priority of a pending job by one for every `interval` that it has been
+/// ready to run.
+///
+/// The effective priority is `priority + (now - run_at) / interval`. Since `now` is the same for
+/// every job in a single query, ordering by it is equivalent to ordering by
+/// `run_at - priority * interval`, which doesn't depend on the current time and so can be
+/// served from an expression index.
+#[derive(Debug, Clone)]
+pub(crate) struct PriorityAging {
+    interval: i64,
+    order_by: String,
+}
+
+impl PriorityAging {
+    pub fn new(interval: Duration) -> Self {
+        // Timestamps are stored with second resolution.
+        let interval = interval.as_secs().max(1) as i64;
+        PriorityAging {
+            interval,
+            order_by: format!("active_jobs.run_at - active_jobs.priority * {interval}"),
+        }
+    }
+
+    /// The ORDER BY clause for the ready jobs query.
+    pub fn order_by(&self) -> &str {
+        &self.order_by
+    }
+
+    fn index_name(&self) -> String {
+        format!("{AGING_INDEX_PREFIX}{}", self.interval)
+    }
+}
+
+/// Create the index needed for the configured aging policy, and remove any indexes left over
+/// from a different policy so that they don't slow down writes.
+pub(crate) fn configure_priority_aging(
+    conn: &Connection,
+    aging: Option<&PriorityAging>,
+) -> Result<()> {
+    let keep = aging.map(|a| a.index_name());
+
+    let mut stmt = conn.prepare(
+        r##"SELECT name FROM sqlite_master
+        WHERE type = 'index' AND tbl_name = 'active_jobs' AND name LIKE ?1"##,
+    )?;
+    let existing = stmt
+        .query_map([format!("{AGING_INDEX_PREFIX}%")], |row| {
+            row.get::<_, String>(0)
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for name in existing {
+        if keep.as_deref() != Some(name.as_str()) {
+            conn.execute(&format!("DROP INDEX IF EXISTS {name}"), [])?;
+        }
+    }
+
+    if let Some(aging) = aging {
+        // The expression here must match the one in `order_by` for SQLite to use the index.
+        conn.execute(
+            &format!(
+                "CREATE INDEX IF NOT EXISTS {} ON active_jobs(fairness_key, run_at - priority * {}) WHERE active_worker_id IS NULL",
+                aging.index_name(),
+                aging.interval
+            ),
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The plan for the query that `do_get_ready_jobs` runs for each fairness key.
+    fn query_plan(conn: &Connection, order_by: &str) -> String {
+        let mut stmt = conn
+            .prepare(&format!(
+                "EXPLAIN QUERY PLAN {}",
+                crate::db_writer::ready_jobs::candidates_query(order_by)
+            ))
+            .unwrap();
+
+        let job_types = std::rc::Rc::new(vec![rusqlite::types::Value::from("a".to_string())]);
+        stmt.query_map(
+            rusqlite::named_params! {
+                "$fairness_key": "",
+                "$now": 100,
+                "$job_types": job_types,
+                "$max_concurrency": 10,
+                "$resource_capacity": "{}",
+                "$labels": "{}",
+                "$limit": 10,
+            },
+            |row| row.get::<_, String>(3),
+        )
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap()
+        .join("\n")
+    }
+
+    #[test]
+    fn ordering_uses_index() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::sqlite_functions::register_functions(&mut conn).unwrap();
+        crate::migrations::migrate(&mut conn).unwrap();
+
+        let plan = query_plan(&conn, DEFAULT_READY_ORDER);
+        assert!(plan.contains("active_fairness_key"), "plan: {plan}");
+        assert!(!plan.contains("TEMP B-TREE"), "plan: {plan}");
+
+        let aging = PriorityAging::new(Duration::from_secs(30));
+        configure_priority_aging(&conn, Some(&aging)).unwrap();
+
+        let plan = query_plan(&conn, aging.order_by());
+        assert!(plan.contains("active_jobs_aging_30"), "plan: {plan}");
+        assert!(!plan.contains("TEMP B-TREE"), "plan: {plan}");
+    }
+
+    #[test]
+    fn replaces_old_index() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migrations::migrate(&mut conn).unwrap();
+
+        configure_priority_aging(&conn, Some(&PriorityAging::new(Duration::from_secs(30))))
+            .unwrap();
+        configure_priority_aging(&conn, Some(&PriorityAging::new(Duration::from_secs(60))))
+            .unwrap();
+
+        let count_index = |name: &str| {
+            conn.query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type='index' AND name=?1",
+                [name],
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(count_index("active_jobs_aging_30"), 0);
+        assert_eq!(count_index("active_jobs_aging_60"), 1);
+
+        configure_priority_aging(&conn, None).unwrap();
+        assert_eq!(count_index("active_jobs_aging_60"), 0);
+    }
+}
@@ -0,0 +1,291 @@
+//! Metrics recorded through the [metrics](https://docs.rs/metrics) facade when the `metrics`
+//! feature is enabled. Without the feature, these functions do nothing. The metrics are listed
+//! in the crate documentation.
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+use std::time::Duration;
+
+use crate::JobState;
+
+/// Record that `count` jobs of a type were added to the queue.
+pub(crate) fn jobs_enqueued(job_type: &str, count: u64) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("effectum_jobs_enqueued_total", "job_type" => job_type.to_string())
+        .increment(count);
+}
+
+/// Record that a job started, after waiting `wait` past the time it was scheduled to run.
+pub(crate) fn job_started(job_type: &str, wait: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        let job_type = job_type.to_string();
+        metrics::counter!("effectum_jobs_started_total", "job_type" => job_type.clone())
+            .increment(1);
+        metrics::histogram!("effectum_job_queue_wait_seconds", "job_type" => job_type).record(wait);
+    }
+}
+
+/// Record that a run of a job finished. `status` is the job's new state, and is
+/// [JobState::Pending] if the job will be retried.
+pub(crate) fn job_finished(job_type: &str, status: JobState, duration: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        let name = match status {
+            JobState::Succeeded => "effectum_jobs_succeeded_total",
+            JobState::Cancelled => "effectum_jobs_cancelled_total",
+            JobState::Pending => "effectum_jobs_retried_total",
+            _ => "effectum_jobs_failed_total",
+        };
+
+        let job_type = job_type.to_string();
+        metrics::counter!(name, "job_type" => job_type.clone()).increment(1);
+        metrics::histogram!("effectum_job_duration_seconds", "job_type" => job_type)
+            .record(duration);
+    }
+}
+
+/// Record a worker's current concurrency limit.
+pub(crate) fn worker_concurrency_limit(worker_id: u64, limit: u16) {
+    #[cfg(feature = "metrics")]
+    metrics::gauge!("effectum_worker_concurrency_limit", "worker_id" => worker_id.to_string())
+        .set(limit as f64);
+}
+
+/// Record a batch of operations committed by the database writer.
+pub(crate) fn db_writer_batch(size: usize, commit_time: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::histogram!("effectum_db_writer_batch_size").record(size as f64);
+        metrics::histogram!("effectum_db_writer_commit_seconds").record(commit_time);
+    }
+}
+
+/// Install a Prometheus recorder as the global [metrics](https://docs.rs/metrics) recorder, and
+/// serve the metrics in the Prometheus text format over HTTP at `addr`. This must be called from
+/// within a Tokio runtime, before the [Queue](crate::Queue) is created.
+///
+/// The returned handle can also be used to render the metrics, for applications that would
+/// rather serve them from their own HTTP server.
+#[cfg(feature = "prometheus")]
+pub fn install_prometheus_exporter(
+    addr: impl Into<std::net::SocketAddr>,
+) -> crate::Result<metrics_exporter_prometheus::PrometheusHandle> {
+    use crate::Error;
+
+    let (recorder, exporter) = metrics_exporter_prometheus::PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .build()
+        .map_err(|e| Error::MetricsExporter(e.to_string()))?;
+    let handle = recorder.handle();
+    metrics::set_global_recorder(recorder).map_err(|e| Error::MetricsExporter(e.to_string()))?;
+
+    tokio::spawn(async move {
+        if let Err(e) = exporter.await {
+            tracing::event!(tracing::Level::ERROR, err = ?e, "Metrics exporter failed");
+        }
+    });
+
+    Ok(handle)
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) use gauges::start_gauge_task;
+
+#[cfg(feature = "metrics")]
+mod gauges {
+    use std::{
+        collections::{HashMap, HashSet},
+        time::Duration,
+    };
+
+    use tokio::{task::JoinHandle, time::MissedTickBehavior};
+    use tracing::{event, Level};
+
+    use crate::{shared_state::SharedState, Result};
+
+    /// Start the task that periodically updates the pending and running job gauges.
+    pub(crate) fn start_gauge_task(queue: SharedState, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(gauge_task(queue, interval))
+    }
+
+    async fn gauge_task(queue: SharedState, interval: Duration) {
+        let mut global_close_rx = queue.close.clone();
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // Job types that have had a gauge set, so that they can be reset to zero once they have no
+        // more jobs.
+        let mut seen = HashSet::new();
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = update_gauges(&queue, &mut seen).await {
+                        event!(Level::ERROR, err = %e, "Failed to update job gauges");
+                    }
+                }
+                _ = global_close_rx.changed() => {
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn update_gauges(queue: &SharedState, seen: &mut HashSet<String>) -> Result<()> {
+        let conn = queue.read_conn_pool.get().await?;
+        let counts = conn
+            .interact(|db| {
+                let mut stmt = db.prepare_cached(
+                    r##"SELECT job_type,
+                        SUM(active_worker_id IS NULL),
+                        SUM(active_worker_id IS NOT NULL)
+                    FROM active_jobs
+                    JOIN jobs USING(job_id)
+                    GROUP BY job_type"##,
+                )?;
+
+                let rows = stmt.query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        (row.get::<_, i64>(1)?, row.get::<_, i64>(2)?),
+                    ))
+                })?;
+                rows.collect::<Result<HashMap<_, _>, _>>()
+            })
+            .await??;
+
+        seen.extend(counts.keys().cloned());
+        for job_type in seen.iter() {
+            let (pending, running) = counts.get(job_type).copied().unwrap_or_default();
+            metrics::gauge!("effectum_jobs_pending", "job_type" => job_type.clone())
+                .set(pending as f64);
+            metrics::gauge!("effectum_jobs_running", "job_type" => job_type.clone())
+                .set(running as f64);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, OnceLock},
+        time::Duration,
+    };
+
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+
+    use crate::{
+        test_util::{wait_for_job, wait_for_job_status, TestContext, TestEnvironment},
+        Job, JobRunner, JobState,
+    };
+
+    fn snapshotter() -> &'static Snapshotter {
+        static SNAPSHOTTER: OnceLock<Snapshotter> = OnceLock::new();
+        SNAPSHOTTER.get_or_init(|| {
+            let recorder = DebuggingRecorder::new();
+            let snapshotter = recorder.snapshotter();
+            recorder.install().expect("failed to install recorder");
+            snapshotter
+        })
+    }
+
+    /// Get the values of the metrics with the given job type label, and of the unlabeled metrics.
+    fn metrics_for(job_type: &str) -> HashMap<String, DebugValue> {
+        snapshotter()
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .filter(|(key, ..)| {
+                let label = key.key().labels().find(|l| l.key() == "job_type");
+                label.is_none_or(|l| l.value() == job_type)
+            })
+            .map(|(key, _, _, value)| (key.key().name().to_string(), value))
+            .collect()
+    }
+
+    fn counter(metrics: &HashMap<String, DebugValue>, name: &str) -> u64 {
+        match metrics.get(name) {
+            Some(DebugValue::Counter(count)) => *count,
+            _ => 0,
+        }
+    }
+
+    fn histogram_len(metrics: &HashMap<String, DebugValue>, name: &str) -> usize {
+        match metrics.get(name) {
+            Some(DebugValue::Histogram(values)) => values.len(),
+            _ => 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn job_metrics() {
+        snapshotter();
+        let mut test = TestEnvironment::with_options(|options| {
+            options.metrics_interval(Duration::from_millis(10))
+        })
+        .await;
+
+        let job_def = JobRunner::builder(
+            "metrics_job",
+            |job, _context: Arc<TestContext>| async move {
+                if job.json_payload::<bool>().unwrap() {
+                    Ok(())
+                } else {
+                    Err("failed")
+                }
+            },
+        )
+        .build();
+        test.registry.add(&job_def);
+
+        let waiting_id = Job::builder("metrics_job")
+            .json_payload(&true)
+            .unwrap()
+            .run_at(test.time.now() + Duration::from_secs(1000))
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let success_id = Job::builder("metrics_job")
+            .json_payload(&true)
+            .unwrap()
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        let fail_id = Job::builder("metrics_job")
+            .json_payload(&false)
+            .unwrap()
+            .max_retries(1)
+            .backoff_initial_interval(Duration::from_secs(0))
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+
+        wait_for_job("job to succeed", &test.queue, success_id).await;
+        wait_for_job_status("job to fail", &test.queue, fail_id, JobState::Failed).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let metrics = metrics_for("metrics_job");
+        assert_eq!(counter(&metrics, "effectum_jobs_enqueued_total"), 3);
+        assert_eq!(counter(&metrics, "effectum_jobs_started_total"), 3);
+        assert_eq!(counter(&metrics, "effectum_jobs_succeeded_total"), 1);
+        assert_eq!(counter(&metrics, "effectum_jobs_retried_total"), 1);
+        assert_eq!(counter(&metrics, "effectum_jobs_failed_total"), 1);
+        assert_eq!(
+            histogram_len(&metrics, "effectum_job_queue_wait_seconds"),
+            3
+        );
+        assert_eq!(histogram_len(&metrics, "effectum_job_duration_seconds"), 3);
+        assert!(histogram_len(&metrics, "effectum_db_writer_batch_size") > 0);
+        assert!(histogram_len(&metrics, "effectum_db_writer_commit_seconds") > 0);
+        assert_eq!(
+            metrics.get("effectum_jobs_pending"),
+            Some(&DebugValue::Gauge(1.0.into()))
+        );
+
+        test.queue.cancel_job(waiting_id).await.unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use rusqlite::{CachedStatement, OptionalExtension};
+use serde::Serialize;
+
+use crate::{Queue, Result};
+
+/// Statistics about the jobs of one type, returned from [Queue::stats].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobTypeStats {
+    /// The job type.
+    pub job_type: String,
+    /// Jobs that are ready to run but have not started.
+    pub pending: u64,
+    /// Jobs that are waiting for a future run time, including failed jobs waiting to retry.
+    pub scheduled: u64,
+    /// Jobs that are running now.
+    pub running: u64,
+    /// Jobs that succeeded within the window.
+    pub succeeded: u64,
+    /// Jobs that failed permanently within the window.
+    pub failed: u64,
+    /// Jobs that were cancelled within the window.
+    pub cancelled: u64,
+    /// Jobs that expired within the window because they did not start by their deadline.
+    pub expired: u64,
+    /// How long the oldest pending job has been ready to run.
+    pub oldest_ready_age: Option<Duration>,
+    /// The median time between when a job was ready to run and when its final run started, for
+    /// jobs that succeeded or failed within the window.
+    pub wait_p50: Option<Duration>,
+    /// The 95th percentile of the wait time.
+    pub wait_p95: Option<Duration>,
+    /// The median duration of the final run of jobs that succeeded or failed within the window.
+    pub run_p50: Option<Duration>,
+    /// The 95th percentile of the run duration.
+    pub run_p95: Option<Duration>,
+    /// Jobs that succeeded or failed within the window, per second.
+    pub throughput: f64,
+}
+
+/// Find the first job type with finished jobs. This and [NEXT_FINISHED_TYPE_QUERY] each take
+/// one seek in the index on job type and finish time, so the job types can be listed without
+/// reading every finished job.
+const FIRST_FINISHED_TYPE_QUERY: &str =
+    "SELECT MIN(job_type) FROM jobs WHERE finished_at IS NOT NULL";
+
+/// Find the next job type with finished jobs, after the given one.
+const NEXT_FINISHED_TYPE_QUERY: &str =
+    "SELECT MIN(job_type) FROM jobs WHERE finished_at IS NOT NULL AND job_type > ?1";
+
+/// Count the jobs of a type that finished within the window. Jobs that finished before the
+/// ready time was recorded have no wait time, so the wait times are counted separately from
+/// the run times.
+const FINISHED_QUERY: &str = r##"SELECT COUNT(*),
+        SUM(status = 'succeeded'),
+        SUM(status = 'failed'),
+        SUM(status = 'cancelled'),
+        SUM(status = 'expired'),
+        SUM(status IN ('succeeded', 'failed') AND started_at IS NOT NULL AND ready_at IS NOT NULL),
+        SUM(status IN ('succeeded', 'failed') AND started_at IS NOT NULL)
+    FROM jobs
+    WHERE job_type = ?1 AND finished_at >= ?2"##;
+
+/// Find the wait time at the given offset in the sorted wait times of a job type.
+const WAIT_QUERY: &str = r##"SELECT started_at - ready_at AS wait
+    FROM jobs
+    WHERE job_type = ?1
+        AND finished_at >= ?2
+        AND status IN ('succeeded', 'failed')
+        AND started_at IS NOT NULL
+        AND ready_at IS NOT NULL
+    ORDER BY wait
+    LIMIT 1 OFFSET ?3"##;
+
+/// Find the run duration at the given offset in the sorted run durations of a job type.
+const RUN_QUERY: &str = r##"SELECT finished_at - started_at AS run
+    FROM jobs
+    WHERE job_type = ?1
+        AND finished_at >= ?2
+        AND status IN ('succeeded', 'failed')
+        AND started_at IS NOT NULL
+    ORDER BY run
+    LIMIT 1 OFFSET ?3"##;
+
+/// Return the index of the given percentile in a sorted list of `count` values.
+fn percentile_index(count: u64, p: f64) -> Option<u64> {
+    if count == 0 {
+        return None;
+    }
+
+    Some(((count - 1) as f64 * p).round() as u64)
+}
+
+/// Run a query that returns one value, in seconds, from the sorted values of a job type, given
+/// the job type, the start of the window, and the offset into the values.
+fn percentile(
+    stmt: &mut CachedStatement,
+    job_type: &str,
+    since: i64,
+    count: u64,
+    p: f64,
+) -> rusqlite::Result<Option<Duration>> {
+    let Some(index) = percentile_index(count, p) else {
+        return Ok(None);
+    };
+
+    let value = stmt
+        .query_row(rusqlite::params![job_type, since, index], |row| {
+            row.get::<_, i64>(0)
+        })
+        .optional()?;
+    Ok(value.map(|v| Duration::from_secs(v.max(0) as u64)))
+}
+
+impl Queue {
+    /// Return statistics about each job type. Counts of finished jobs, and the wait and run times,
+    /// cover the jobs that finished within the last `window`.
+    ///
+    /// Times are recorded in whole seconds, so shorter waits and runs are reported as zero.
+    ///
+    /// The percentiles are calculated in the database, using the index on job type and finish
+    /// time to find the jobs in the window. Each one still sorts the jobs of that type that
+    /// finished within the window, so very large windows on busy queues can make this slow.
+    pub async fn stats(&self, window: Duration) -> Result<Vec<JobTypeStats>> {
+        let now = self.state.time.now().unix_timestamp();
+        let since = now - window.as_secs() as i64;
+
+        let conn = self.state.read_conn_pool.get().await?;
+        let stats = conn
+            .interact(move |db| {
+                let mut stats: BTreeMap<String, JobTypeStats> = BTreeMap::new();
+
+                let mut active_stmt = db.prepare_cached(
+                    r##"SELECT job_type,
+                        SUM(active_worker_id IS NULL AND run_at <= ?1),
+                        SUM(active_worker_id IS NULL AND run_at > ?1),
+                        COUNT(active_worker_id),
+                        MIN(CASE WHEN active_worker_id IS NULL AND run_at <= ?1 THEN run_at END)
+                    FROM active_jobs
+                    JOIN jobs USING(job_id)
+                    GROUP BY job_type"##,
+                )?;
+                let mut rows = active_stmt.query([now])?;
+                while let Some(row) = rows.next()? {
+                    let entry = stats.entry(row.get(0)?).or_default();
+                    entry.pending = row.get(1)?;
+                    entry.scheduled = row.get(2)?;
+                    entry.running = row.get(3)?;
+                    entry.oldest_ready_age = row
+                        .get::<_, Option<i64>>(4)?
+                        .map(|run_at| Duration::from_secs((now - run_at).max(0) as u64));
+                }
+
+                let mut first_type_stmt = db.prepare_cached(FIRST_FINISHED_TYPE_QUERY)?;
+                let mut next_type_stmt = db.prepare_cached(NEXT_FINISHED_TYPE_QUERY)?;
+                let mut finished_stmt = db.prepare_cached(FINISHED_QUERY)?;
+                let mut wait_stmt = db.prepare_cached(WAIT_QUERY)?;
+                let mut run_stmt = db.prepare_cached(RUN_QUERY)?;
+
+                let mut next_type: Option<String> =
+                    first_type_stmt.query_row([], |row| row.get(0))?;
+                while let Some(job_type) = next_type {
+                    let counts =
+                        finished_stmt.query_row(rusqlite::params![job_type, since], |row| {
+                            let mut counts = [0u64; 7];
+                            for (i, count) in counts.iter_mut().enumerate() {
+                                *count = row.get::<_, Option<u64>>(i)?.unwrap_or(0);
+                            }
+                            Ok(counts)
+                        })?;
+                    next_type = next_type_stmt.query_row([&job_type], |row| row.get(0))?;
+
+                    let [total, succeeded, failed, cancelled, expired, wait_count, run_count] =
+                        counts;
+                    if total == 0 {
+                        continue;
+                    }
+
+                    let entry = stats.entry(job_type.clone()).or_default();
+                    entry.succeeded = succeeded;
+                    entry.failed = failed;
+                    entry.cancelled = cancelled;
+                    entry.expired = expired;
+
+                    entry.wait_p50 = percentile(&mut wait_stmt, &job_type, since, wait_count, 0.5)?;
+                    entry.wait_p95 =
+                        percentile(&mut wait_stmt, &job_type, since, wait_count, 0.95)?;
+                    entry.run_p50 = percentile(&mut run_stmt, &job_type, since, run_count, 0.5)?;
+                    entry.run_p95 = percentile(&mut run_stmt, &job_type, since, run_count, 0.95)?;
+                }
+
+                Ok::<_, rusqlite::Error>(stats)
+            })
+            .await??;
+
+        let window_secs = window.as_secs_f64();
+        Ok(stats
+            .into_iter()
+            .map(|(job_type, mut entry)| {
+                entry.job_type = job_type;
+                if window_secs > 0.0 {
+                    entry.throughput = (entry.succeeded + entry.failed) as f64 / window_secs;
+                }
+                entry
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        test_util::{wait_for_job_status, TestContext, TestEnvironment},
+        Job, JobRunner, JobState,
+    };
+
+    fn query_plan(conn: &rusqlite::Connection, query: &str) -> String {
+        let mut stmt = conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {query}"))
+            .unwrap();
+        let params = (1..=stmt.parameter_count())
+            .map(|_| rusqlite::types::Value::Integer(0))
+            .collect::<Vec<_>>();
+
+        stmt.query_map(rusqlite::params_from_iter(params), |row| {
+            row.get::<_, String>(3)
+        })
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap()
+        .join("\n")
+    }
+
+    #[test]
+    fn finished_jobs_use_index() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        crate::migrations::migrate(&mut conn).unwrap();
+
+        for query in [FIRST_FINISHED_TYPE_QUERY, NEXT_FINISHED_TYPE_QUERY] {
+            let plan = query_plan(&conn, query);
+            assert!(
+                plan.contains("SEARCH jobs USING COVERING INDEX done_jobs_job_type_and_time"),
+                "plan: {plan}"
+            );
+        }
+
+        // Only the percentiles need to sort the jobs in the window.
+        for query in [FINISHED_QUERY, WAIT_QUERY, RUN_QUERY] {
+            let plan = query_plan(&conn, query);
+            assert!(
+                plan.contains("done_jobs_job_type_and_time (job_type=? AND finished_at>?)"),
+                "plan: {plan}"
+            );
+        }
+        assert!(!query_plan(&conn, FINISHED_QUERY).contains("TEMP B-TREE"));
+    }
+
+    #[test]
+    fn percentiles() {
+        assert_eq!(percentile_index(0, 0.5), None);
+        assert_eq!(percentile_index(100, 0.5), Some(50));
+        assert_eq!(percentile_index(100, 0.95), Some(94));
+        assert_eq!(percentile_index(1, 0.95), Some(0));
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let mut test = TestEnvironment::new().await;
+        let job_def =
+            JobRunner::builder("stats_job", |job, _context: Arc<TestContext>| async move {
+                if job.json_payload::<bool>().unwrap() {
+                    Ok(())
+                } else {
+                    Err("failed")
+                }
+            })
+            .build();
+        test.registry.add(&job_def);
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        for succeed in [true, true, false] {
+            let job_id = Job::builder("stats_job")
+                .json_payload(&succeed)
+                .unwrap()
+                .max_retries(0)
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+            let state = if succeed {
+                JobState::Succeeded
+            } else {
+                JobState::Failed
+            };
+            wait_for_job_status("job to finish", &test.queue, job_id, state).await;
+        }
+
+        Job::builder("stats_job")
+            .json_payload(&true)
+            .unwrap()
+            .run_at(test.time.now() + Duration::from_secs(1000))
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        let cancel_id = Job::builder("stats_job")
+            .run_at(test.time.now() + Duration::from_secs(1000))
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        test.queue.cancel_job(cancel_id).await.unwrap();
+
+        // No worker runs this type, so the job stays pending.
+        Job::builder("unhandled")
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+
+        let stats = test.queue.stats(Duration::from_secs(60)).await.unwrap();
+        assert_eq!(stats.len(), 2);
+
+        let job = &stats[0];
+        assert_eq!(job.job_type, "stats_job");
+        assert_eq!(job.pending, 0);
+        assert_eq!(job.scheduled, 1);
+        assert_eq!(job.running, 0);
+        assert_eq!(job.succeeded, 2);
+        assert_eq!(job.failed, 1);
+        assert_eq!(job.cancelled, 1);
+        assert_eq!(job.expired, 0);
+        assert_eq!(job.oldest_ready_age, None);
+        assert!(job.wait_p50.is_some());
+        assert!(job.run_p95.is_some());
+        assert_eq!(job.throughput, 3.0 / 60.0);
+
+        let unhandled = &stats[1];
+        assert_eq!(unhandled.job_type, "unhandled");
+        assert_eq!(unhandled.pending, 1);
+        assert_eq!(unhandled.scheduled, 0);
+        assert!(unhandled.oldest_ready_age.is_some());
+        assert_eq!(unhandled.run_p50, None);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{rc::Rc, str::FromStr, time::Duration};
+use std::{borrow::Cow, rc::Rc, str::FromStr, time::Duration};
 
 use rusqlite::{params, OptionalExtension};
 use serde::{Deserialize, Serialize};
@@ -11,6 +11,9 @@ use crate::{
         recurring::{AddRecurringJobArgs, DeleteRecurringJobArgs},
         DbOperation, UpsertMode,
     },
+    labels::labels_from_json,
+    payload_storage::PayloadStorage,
+    resources::resources_from_json,
     shared_state::SharedState,
     Error, Job, JobBuilder, JobStatus, Queue,
 };
@@ -24,7 +27,9 @@ pub(crate) fn create_job_from_recurring_template(
     let query = r##"SELECT job_id,
                 job_type, priority, weight, payload, max_retries,
                 backoff_multiplier, backoff_randomization, backoff_initial_interval,
-                default_timeout, heartbeat_increment, schedule, name
+                default_timeout, heartbeat_increment, schedule, name, fairness_key,
+                payload_codec, payload_compression, payload_key_id, payload_blob,
+                payload_version, trace_context, resources, required_labels
             FROM jobs
             JOIN recurring ON job_id = base_job_id
             WHERE status = 'recurring_base' AND job_id IN rarray(?)
@@ -76,12 +81,56 @@ pub(crate) fn create_job_from_recurring_template(
                 .map_err(|e| Error::ColumnType(e.into(), "name"))?
                 .map(|s| s.to_string());
 
+            let fairness_key = row
+                .get_ref(13)?
+                .as_str()
+                .map_err(|e| Error::ColumnType(e.into(), "fairness_key"))?;
+            let fairness_key = (!fairness_key.is_empty()).then(|| fairness_key.to_string());
+
+            let payload_codec = row
+                .get(14)
+                .map_err(|e| Error::ColumnType(e, "payload_codec"))?;
+            let payload_storage = PayloadStorage {
+                compression: row
+                    .get::<_, Option<String>>(15)
+                    .map_err(|e| Error::ColumnType(e, "payload_compression"))?
+                    .map(Cow::Owned),
+                key_id: row
+                    .get(16)
+                    .map_err(|e| Error::ColumnType(e, "payload_key_id"))?,
+                blob: row
+                    .get(17)
+                    .map_err(|e| Error::ColumnType(e, "payload_blob"))?,
+                blob_hold: None,
+            };
+            let payload_version = row
+                .get(18)
+                .map_err(|e| Error::ColumnType(e, "payload_version"))?;
+            let trace_context = row
+                .get(19)
+                .map_err(|e| Error::ColumnType(e, "trace_context"))?;
+            let resources = resources_from_json(
+                row.get_ref(20)?
+                    .as_str_or_null()
+                    .map_err(|e| Error::ColumnType(e.into(), "resources"))?,
+            )?;
+            let required_labels = labels_from_json(
+                row.get_ref(21)?
+                    .as_str_or_null()
+                    .map_err(|e| Error::ColumnType(e.into(), "required_labels"))?,
+            )?;
+
             let next_job_time = schedule.find_next_job_time(now, from_time)?;
             let job = JobBuilder::new(job_type)
                 .name_opt(name)
+                .fairness_key_opt(fairness_key)
                 .priority(priority)
                 .weight(weight)
-                .payload(payload)
+                .resources(resources)
+                .required_labels(required_labels)
+                .stored_payload(payload, payload_storage)
+                .payload_codec_name(payload_codec)
+                .payload_version(payload_version)
                 .max_retries(max_retries)
                 .backoff_multiplier(backoff_multiplier)
                 .backoff_randomization(backoff_randomization)
@@ -89,6 +138,7 @@ pub(crate) fn create_job_from_recurring_template(
                 .timeout(Duration::from_secs(default_timeout))
                 .heartbeat_increment(Duration::from_secs(heartbeat_increment))
                 .from_recurring(job_id)
+                .trace_context(trace_context)
                 .run_at(next_job_time)
                 .build();
 
@@ -150,6 +200,31 @@ pub struct RecurringJobInfo {
     pub next_run: Option<(Uuid, OffsetDateTime)>,
 }
 
+/// A position in a recurring job's run history, used to fetch the next page of results from
+/// [Queue::get_recurring_job_history].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RecurringJobHistoryCursor(i64);
+
+#[derive(Debug)]
+/// Past runs of a recurring job, along with summary statistics over all of its finished runs.
+pub struct RecurringJobHistory {
+    /// The finished runs in this page, most recent first.
+    pub runs: Vec<JobStatus>,
+    /// Pass this to [Queue::get_recurring_job_history] to fetch the next page of older runs.
+    /// This is `None` when there are no more runs to fetch.
+    pub next_cursor: Option<RecurringJobHistoryCursor>,
+    /// The number of runs that succeeded.
+    pub succeeded: u64,
+    /// The number of runs that failed permanently.
+    pub failed: u64,
+    /// The number of runs that were cancelled before they started.
+    pub cancelled: u64,
+    /// The number of runs that expired because they did not start before their deadline.
+    pub expired: u64,
+    /// The average time from start to finish of the runs that started.
+    pub average_duration: Option<Duration>,
+}
+
 impl RecurringJobSchedule {
     /// Create a RecurringJobSchedule from a cron-style schedule string.
     pub fn from_cron_string(spec: String) -> Result<Self, Error> {
@@ -251,12 +326,15 @@ impl Queue {
         upsert_mode: UpsertMode,
         id: String,
         schedule: RecurringJobSchedule,
-        job: Job,
+        mut job: Job,
         run_immediately_on_insert: bool,
     ) -> Result<(), Error> {
+        job.capture_trace_context();
+        self.state.store_job_payload(&mut job)?;
         let (result_tx, result_rx) = tokio::sync::oneshot::channel();
         let now = self.state.time.now();
         let job_type = job.job_type.to_string();
+        let required_labels = job.required_labels.clone();
         self.state
             .db_write_tx
             .send(DbOperation {
@@ -279,7 +357,9 @@ impl Queue {
         let add_result = result_rx.await.map_err(|_| Error::QueueClosed)??;
         if let Some(run_at) = add_result.new_run_at {
             event!(Level::DEBUG, ?run_at, "Setting up job notify");
-            self.state.notify_for_job_type(now, run_at, &job_type).await;
+            self.state
+                .notify_for_job_type(now, run_at, &job_type, &required_labels)
+                .await;
         }
 
         Ok(())
@@ -303,9 +383,52 @@ impl Queue {
         Ok(())
     }
 
+    /// Run a recurring job right away, outside of its normal schedule. The new job is created
+    /// from the recurring job's template, with `payload_override` replacing the template's payload
+    /// if it is set. The override should be encoded with the same codec as the template's payload.
+    /// The next scheduled run of the recurring job is not affected.
+    pub async fn trigger_recurring_job(
+        &self,
+        id: String,
+        payload_override: Option<Vec<u8>>,
+    ) -> Result<Uuid, Error> {
+        let conn = self.state.read_conn_pool.get().await?;
+        let now = self.state.time.now();
+        let mut job = conn
+            .interact(move |db| {
+                let mut base_job_stmt =
+                    db.prepare_cached("SELECT base_job_id FROM recurring WHERE external_id = ?")?;
+                let base_job_id = base_job_stmt
+                    .query_row([id], |row| row.get::<_, i64>(0))
+                    .optional()?
+                    .ok_or(Error::NotFound)?;
+
+                let ids = vec![rusqlite::types::Value::from(base_job_id)];
+                create_job_from_recurring_template(db, now, now, ids)?
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::NotFound)
+            })
+            .await??;
+        drop(conn);
+
+        job.run_at = Some(now);
+        job.manually_triggered = true;
+        // Connect the run to the trace that triggered it, rather than the one that added the
+        // recurring job.
+        job.trace_context = None;
+        if let Some(payload) = payload_override {
+            job.payload = payload;
+            job.payload_storage = None;
+        }
+
+        self.state.add_job(job).await
+    }
+
     /// Return information about a recurring job and its latest execution
     pub async fn get_recurring_job_info(&self, id: String) -> Result<RecurringJobInfo, Error> {
         let conn = self.state.read_conn_pool.get().await?;
+        let state = self.state.clone();
         let recurring_info = conn
             .interact(move |db| {
                 let mut base_info_stmt = db.prepare_cached(
@@ -327,6 +450,7 @@ impl Queue {
 
                 let base_job_info = Self::run_job_status_query(
                     db,
+                    &state,
                     crate::job_status::JobIdQuery::Id(base_job_id),
                     1,
                 )?
@@ -349,6 +473,7 @@ impl Queue {
                 let last_run = if let Some(last_run_id) = last_run_id {
                     Self::run_job_status_query(
                         db,
+                        &state,
                         crate::job_status::JobIdQuery::Id(last_run_id),
                         1,
                     )?
@@ -361,7 +486,8 @@ impl Queue {
                 let mut next_run_stmt = db.prepare_cached(
                     r##"SELECT external_id, orig_run_at
                     FROM jobs
-                    WHERE from_base_job = ? AND started_at IS NULL
+                    WHERE from_base_job = ? AND status = 'pending' AND started_at IS NULL
+                        AND NOT manually_triggered
                     LIMIT 1"##,
                 )?;
 
@@ -389,6 +515,100 @@ impl Queue {
         Ok(recurring_info)
     }
 
+    /// Return the finished runs of a recurring job, most recent first, along with counts and
+    /// timing across all of its finished runs. Pass the returned
+    /// [next_cursor](RecurringJobHistory::next_cursor) back in as `cursor` to page through older
+    /// runs.
+    pub async fn get_recurring_job_history(
+        &self,
+        id: String,
+        limit: usize,
+        cursor: Option<RecurringJobHistoryCursor>,
+    ) -> Result<RecurringJobHistory, Error> {
+        let conn = self.state.read_conn_pool.get().await?;
+        let state = self.state.clone();
+        let history = conn
+            .interact(move |db| {
+                let mut base_job_stmt =
+                    db.prepare_cached("SELECT base_job_id FROM recurring WHERE external_id = ?")?;
+                let base_job_id = base_job_stmt
+                    .query_row([id], |row| row.get::<_, i64>(0))
+                    .optional()?
+                    .ok_or(Error::NotFound)?;
+
+                let mut runs_stmt = db.prepare_cached(
+                    r##"SELECT job_id
+                    FROM jobs
+                    WHERE from_base_job = ?1 AND finished_at IS NOT NULL AND job_id < ?2
+                    ORDER BY job_id DESC
+                    LIMIT ?3"##,
+                )?;
+
+                let before_id = cursor.map(|c| c.0).unwrap_or(i64::MAX);
+                let run_ids = runs_stmt
+                    .query_map(params![base_job_id, before_id, limit as i64], |row| {
+                        row.get::<_, i64>(0)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let next_cursor = if run_ids.len() == limit {
+                    run_ids.last().copied().map(RecurringJobHistoryCursor)
+                } else {
+                    None
+                };
+
+                let mut runs = Vec::with_capacity(run_ids.len());
+                for run_id in run_ids {
+                    let status = Self::run_job_status_query(
+                        db,
+                        &state,
+                        crate::job_status::JobIdQuery::Id(run_id),
+                        1,
+                    )?
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::NotFound)?;
+                    runs.push(status);
+                }
+
+                let mut stats_stmt = db.prepare_cached(
+                    r##"SELECT
+                        COUNT(*) FILTER (WHERE status = 'succeeded'),
+                        COUNT(*) FILTER (WHERE status = 'failed'),
+                        COUNT(*) FILTER (WHERE status = 'cancelled'),
+                        COUNT(*) FILTER (WHERE status = 'expired'),
+                        AVG(finished_at - started_at)
+                    FROM jobs
+                    WHERE from_base_job = ? AND finished_at IS NOT NULL"##,
+                )?;
+
+                let (succeeded, failed, cancelled, expired, average_duration) = stats_stmt
+                    .query_row([base_job_id], |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, i64>(1)?,
+                            row.get::<_, i64>(2)?,
+                            row.get::<_, i64>(3)?,
+                            row.get::<_, Option<f64>>(4)?,
+                        ))
+                    })?;
+
+                Ok::<_, Error>(RecurringJobHistory {
+                    runs,
+                    next_cursor,
+                    succeeded: succeeded as u64,
+                    failed: failed as u64,
+                    cancelled: cancelled as u64,
+                    expired: expired as u64,
+                    average_duration: average_duration
+                        .map(|secs| Duration::from_secs_f64(secs.max(0.0))),
+                })
+            })
+            .await??;
+
+        Ok(history)
+    }
+
     /// Return the IDs of all recurring jobs with the given prefix
     pub async fn list_recurring_jobs_with_prefix(
         &self,
@@ -664,6 +884,129 @@ mod tests {
         assert_eq!(second_run_time, first_run_at + Duration::from_secs(400));
     }
 
+    #[tokio::test]
+    async fn trigger_manually() {
+        let test = TestEnvironment::new().await;
+        let _worker = test.worker().build().await.expect("Failed to build worker");
+        let job = JobBuilder::new("counter")
+            .json_payload(&serde_json::json!(1))
+            .expect("json_payload")
+            .build();
+
+        let schedule = RecurringJobSchedule::RepeatEvery {
+            interval: Duration::from_secs(100),
+        };
+        test.queue
+            .add_recurring_job("job_id".to_string(), schedule, job, false)
+            .await
+            .expect("add_recurring_job");
+        let job_status = test
+            .queue
+            .get_recurring_job_info("job_id".to_string())
+            .await
+            .expect("Retrieving job status");
+        let next_run = job_status.next_run.expect("next_run");
+
+        let triggered_id = test
+            .queue
+            .trigger_recurring_job("job_id".to_string(), Some(serde_json::to_vec(&5).unwrap()))
+            .await
+            .expect("trigger_recurring_job");
+        assert_ne!(triggered_id, next_run.0);
+
+        let result = wait_for_job("triggered run", &test.queue, triggered_id).await;
+        assert!(result.manually_triggered);
+        assert_eq!(
+            test.context
+                .counter
+                .load(std::sync::atomic::Ordering::Relaxed),
+            5,
+            "triggered job should use the payload override"
+        );
+
+        let job_status = test
+            .queue
+            .get_recurring_job_info("job_id".to_string())
+            .await
+            .expect("Retrieving job status");
+        assert_eq!(job_status.next_run, Some(next_run));
+        assert_eq!(
+            job_status.last_run.expect("last_run").id,
+            triggered_id,
+            "last_run should include manually triggered runs"
+        );
+    }
+
+    #[tokio::test]
+    async fn trigger_nonexistent() {
+        let test = TestEnvironment::new().await;
+        let err = test
+            .queue
+            .trigger_recurring_job("job_id".to_string(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_history() {
+        let test = TestEnvironment::new().await;
+        let _worker = test.worker().build().await.expect("Failed to build worker");
+        let job = JobBuilder::new("counter")
+            .json_payload(&serde_json::json!(1))
+            .expect("json_payload")
+            .build();
+
+        let schedule = RecurringJobSchedule::RepeatEvery {
+            interval: Duration::from_secs(10),
+        };
+        test.queue
+            .add_recurring_job("job_id".to_string(), schedule, job, false)
+            .await
+            .expect("add_recurring_job");
+
+        let mut run_ids = Vec::new();
+        for i in 0..3 {
+            let job_status = test
+                .queue
+                .get_recurring_job_info("job_id".to_string())
+                .await
+                .expect("Retrieving job status");
+            let (run_id, run_at) = job_status.next_run.expect("next_run");
+            tokio::time::sleep_until(test.time.instant_for_timestamp(run_at.unix_timestamp()))
+                .await;
+            wait_for_job(format!("run {i}"), &test.queue, run_id).await;
+            run_ids.push(run_id);
+        }
+
+        let history = test
+            .queue
+            .get_recurring_job_history("job_id".to_string(), 2, None)
+            .await
+            .expect("get_recurring_job_history");
+        assert_eq!(
+            history.runs.iter().map(|r| r.id).collect::<Vec<_>>(),
+            vec![run_ids[2], run_ids[1]]
+        );
+        assert_eq!(history.succeeded, 3);
+        assert_eq!(history.failed, 0);
+        assert_eq!(history.cancelled, 0);
+        assert_eq!(history.expired, 0);
+        assert!(history.average_duration.is_some());
+
+        let cursor = history.next_cursor.expect("next_cursor");
+        let history = test
+            .queue
+            .get_recurring_job_history("job_id".to_string(), 2, Some(cursor))
+            .await
+            .expect("get_recurring_job_history second page");
+        assert_eq!(
+            history.runs.iter().map(|r| r.id).collect::<Vec<_>>(),
+            vec![run_ids[0]]
+        );
+        assert!(history.next_cursor.is_none());
+    }
+
     #[tokio::test]
     async fn add_with_bad_schedule() {
         let test = TestEnvironment::new().await;
@@ -1238,9 +1581,53 @@ mod tests {
     }
 
     #[tokio::test(start_paused = true)]
-    async fn restart() {
+    async fn expired_run_schedules_next_run() {
         let test = TestEnvironment::new().await;
         let _worker = test.worker().build().await.expect("Failed to build worker");
+
+        // The first run becomes ready after its deadline has passed, so it expires.
+        let start = test.time.now().replace_nanosecond(0).unwrap();
+        let job = JobBuilder::new("counter")
+            .expire_if_not_started_by(start + Duration::from_secs(5))
+            .build();
+        let schedule = RecurringJobSchedule::RepeatEvery {
+            interval: Duration::from_secs(10),
+        };
+        test.queue
+            .add_recurring_job("job_id".to_string(), schedule, job, false)
+            .await
+            .expect("add_recurring_job");
+
+        let job_status = test
+            .queue
+            .get_recurring_job_info("job_id".to_string())
+            .await
+            .expect("Retrieving job status");
+        let (first_job_id, _) = job_status.next_run.expect("next_run");
+        wait_for_job_status("first run", &test.queue, first_job_id, JobState::Expired).await;
+
+        let job_status = test
+            .queue
+            .get_recurring_job_info("job_id".to_string())
+            .await
+            .expect("Retrieving job status");
+        let (second_job_id, second_run_at) = job_status.next_run.expect("next_run after expiry");
+        assert_ne!(first_job_id, second_job_id);
+        assert_eq!(second_run_at, start + Duration::from_secs(20));
+
+        wait_for_job("second run", &test.queue, second_job_id).await;
+        assert_eq!(
+            test.context
+                .counter
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restart() {
+        let test = TestEnvironment::new().await;
+        let worker = test.worker().build().await.expect("Failed to build worker");
         let job = JobBuilder::new("counter")
             .json_payload(&serde_json::json!(1))
             .expect("json_payload")
@@ -1275,6 +1662,10 @@ mod tests {
             .await
             .expect("Retrieving job status");
         let second_run_at = next_job_status.next_run.expect("next_run_at").1;
+        // Stop the worker before closing the queue. Otherwise the queue's close timeout can fire
+        // while the worker waits on the database, since paused time jumps ahead whenever the
+        // runtime is idle.
+        worker.unregister(None).await.expect("unregister worker");
         let dir = test.queue.close_and_persist().await;
         event!(Level::INFO, "Closed Queue");
 
@@ -0,0 +1,126 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{Error, Result};
+
+/// Amounts of named resources, such as CPU cores, memory, or database connections.
+pub(crate) type Resources = BTreeMap<String, u32>;
+
+/// Encode a job's resources for the `resources` column. Jobs without any resources are stored as
+/// NULL.
+pub(crate) fn resources_to_json(resources: &Resources) -> Option<String> {
+    if resources.is_empty() {
+        None
+    } else {
+        serde_json::to_string(resources).ok()
+    }
+}
+
+pub(crate) fn resources_from_json(value: Option<&str>) -> Result<Resources> {
+    match value {
+        Some(value) => serde_json::from_str(value).map_err(Error::InvalidResources),
+        None => Ok(Resources::new()),
+    }
+}
+
+/// The resources that a worker has, and how much of them its running jobs are using. Resources
+/// that the worker doesn't declare are unlimited.
+#[derive(Debug, Default)]
+pub(crate) struct ResourcePool {
+    capacity: Resources,
+    /// The capacity encoded as JSON, for the ready jobs query.
+    capacity_json: String,
+    in_use: Mutex<Resources>,
+}
+
+impl ResourcePool {
+    pub fn new(capacity: Resources) -> Self {
+        let capacity_json = serde_json::to_string(&capacity).unwrap_or_default();
+        Self {
+            capacity,
+            capacity_json,
+            in_use: Mutex::new(Resources::new()),
+        }
+    }
+
+    pub fn capacity(&self) -> &Resources {
+        &self.capacity
+    }
+
+    pub fn capacity_json(&self) -> &str {
+        &self.capacity_json
+    }
+
+    pub fn in_use(&self) -> Resources {
+        self.in_use.lock().unwrap().clone()
+    }
+
+    /// Check if a job that needs `resources` fits in what is left over.
+    pub fn fits(&self, resources: &Resources) -> bool {
+        let in_use = self.in_use.lock().unwrap();
+        resources.iter().all(|(name, amount)| {
+            self.capacity
+                .get(name)
+                .is_none_or(|capacity| in_use.get(name).copied().unwrap_or(0) + amount <= *capacity)
+        })
+    }
+
+    pub fn claim(&self, resources: &Resources) {
+        let mut in_use = self.in_use.lock().unwrap();
+        for (name, amount) in resources {
+            if self.capacity.contains_key(name) {
+                *in_use.entry(name.clone()).or_default() += amount;
+            }
+        }
+    }
+
+    pub fn release(&self, resources: &Resources) {
+        let mut in_use = self.in_use.lock().unwrap();
+        for (name, amount) in resources {
+            if let Some(used) = in_use.get_mut(name) {
+                *used = used.saturating_sub(*amount);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resources(values: &[(&str, u32)]) -> Resources {
+        values
+            .iter()
+            .map(|(name, amount)| (name.to_string(), *amount))
+            .collect()
+    }
+
+    #[test]
+    fn claim_and_release() {
+        let pool = ResourcePool::new(resources(&[("cpu", 4), ("db", 1)]));
+        let job = resources(&[("cpu", 2), ("db", 1)]);
+
+        assert!(pool.fits(&job));
+        pool.claim(&job);
+        assert_eq!(pool.in_use(), job);
+
+        // The CPU would fit, but the database connection is taken.
+        assert!(!pool.fits(&resources(&[("cpu", 1), ("db", 1)])));
+        assert!(pool.fits(&resources(&[("cpu", 2)])));
+        assert!(!pool.fits(&resources(&[("cpu", 3)])));
+
+        pool.release(&job);
+        assert_eq!(pool.in_use(), resources(&[("cpu", 0), ("db", 0)]));
+        assert!(pool.fits(&resources(&[("cpu", 3), ("db", 1)])));
+    }
+
+    #[test]
+    fn undeclared_resources_are_unlimited() {
+        let pool = ResourcePool::new(resources(&[("cpu", 1)]));
+        let job = resources(&[("mem_mb", 100_000)]);
+
+        assert!(pool.fits(&job));
+        pool.claim(&job);
+        assert!(pool.in_use().is_empty());
+        assert!(pool.fits(&job));
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    any::Any,
+    fmt::{Debug, Display},
+    time::Duration,
+};
+
+use crate::RunningJob;
+
+/// What to do with a job after it fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Run the job again after the given delay.
+    Retry(Duration),
+    /// Mark the job as failed without retrying it.
+    Fail,
+    /// Mark the job as cancelled without retrying it.
+    Cancel,
+}
+
+/// Information about a failed job, passed to a [RetryPolicy].
+pub struct RetryContext<'a> {
+    /// The job that failed.
+    pub job: &'a RunningJob,
+    /// How many times the job had been tried before the run that just failed. On the first run,
+    /// this will be 0.
+    pub current_try: i32,
+    /// The error returned from the job.
+    pub error: &'a dyn Display,
+    error_any: &'a dyn Any,
+}
+
+impl<'a> RetryContext<'a> {
+    pub(crate) fn new<E: Display + 'static>(job: &'a RunningJob, error: &'a E) -> Self {
+        RetryContext {
+            job,
+            current_try: job.current_try,
+            error,
+            error_any: error,
+        }
+    }
+
+    /// Get the error as its original type, if it is a `T`.
+    pub fn downcast_error<T: 'static>(&self) -> Option<&T> {
+        self.error_any.downcast_ref::<T>()
+    }
+
+    /// Returns true if the job has used all the retries allowed by its `max_retries` setting.
+    pub fn retries_exhausted(&self) -> bool {
+        self.current_try + 1 > self.job.max_retries
+    }
+
+    /// Retry after `delay`, unless the job has no retries left.
+    fn retry_after(&self, delay: Duration) -> RetryDecision {
+        if self.retries_exhausted() {
+            RetryDecision::Fail
+        } else {
+            RetryDecision::Retry(delay)
+        }
+    }
+}
+
+/// Decides what happens to a job when it fails. A policy can be set for a job type using
+/// [JobRunnerBuilder::retry_policy](crate::JobRunnerBuilder::retry_policy). Jobs without a policy
+/// use the backoff settings from [Retries](crate::Retries).
+///
+/// Errors wrapped in [Permanent] always fail the job without consulting the policy.
+pub trait RetryPolicy: Debug + Send + Sync + 'static {
+    /// Decide what to do with a job that just failed.
+    fn decide(&self, context: &RetryContext<'_>) -> RetryDecision;
+}
+
+/// Retry after the same delay every time, up to the job's `max_retries`.
+#[derive(Debug, Clone)]
+pub struct FixedBackoff {
+    /// The delay before each retry.
+    pub delay: Duration,
+}
+
+impl FixedBackoff {
+    /// Create a new [FixedBackoff] policy.
+    pub fn new(delay: Duration) -> Self {
+        Self { delay }
+    }
+}
+
+impl RetryPolicy for FixedBackoff {
+    fn decide(&self, context: &RetryContext<'_>) -> RetryDecision {
+        context.retry_after(self.delay)
+    }
+}
+
+/// Increase the delay by the same amount on each retry, up to the job's `max_retries`.
+#[derive(Debug, Clone)]
+pub struct LinearBackoff {
+    /// The delay before the first retry.
+    pub initial: Duration,
+    /// How much to add to the delay on each subsequent retry.
+    pub increment: Duration,
+    /// The longest delay allowed.
+    pub max_delay: Duration,
+}
+
+impl LinearBackoff {
+    /// Create a new [LinearBackoff] policy with no maximum delay.
+    pub fn new(initial: Duration, increment: Duration) -> Self {
+        Self {
+            initial,
+            increment,
+            max_delay: Duration::MAX,
+        }
+    }
+
+    /// Set the longest delay allowed.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+}
+
+impl RetryPolicy for LinearBackoff {
+    fn decide(&self, context: &RetryContext<'_>) -> RetryDecision {
+        let tries = context.current_try.max(0) as u32;
+        let delay = self
+            .increment
+            .checked_mul(tries)
+            .and_then(|d| d.checked_add(self.initial))
+            .unwrap_or(Duration::MAX)
+            .min(self.max_delay);
+        context.retry_after(delay)
+    }
+}
+
+/// Multiply the delay on each retry, up to a maximum delay and the job's `max_retries`.
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    /// The delay before the first retry.
+    pub initial: Duration,
+    /// How much to multiply the delay by on each subsequent retry.
+    pub multiplier: f64,
+    /// The longest delay allowed.
+    pub max_delay: Duration,
+    /// Add a random amount of up to this fraction of the delay, to avoid many jobs retrying at
+    /// the same moment.
+    pub randomization: f64,
+}
+
+impl ExponentialBackoff {
+    /// Create a new [ExponentialBackoff] policy that doubles the delay on each retry, with no
+    /// randomization.
+    pub fn new(initial: Duration, max_delay: Duration) -> Self {
+        Self {
+            initial,
+            multiplier: 2.0,
+            max_delay,
+            randomization: 0.0,
+        }
+    }
+
+    /// Set the multiplier applied to the delay on each retry.
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Set the randomization factor.
+    pub fn randomization(mut self, randomization: f64) -> Self {
+        self.randomization = randomization;
+        self
+    }
+}
+
+impl RetryPolicy for ExponentialBackoff {
+    fn decide(&self, context: &RetryContext<'_>) -> RetryDecision {
+        let delay = self.initial.as_secs_f64()
+            * self.multiplier.powi(context.current_try)
+            * (1.0 + rand::random::<f64>() * self.randomization);
+        let delay = Duration::try_from_secs_f64(delay)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        context.retry_after(delay)
+    }
+}
+
+/// "Decorrelated jitter" backoff, which picks a random delay between `base` and three times the
+/// previous delay, capped at `max_delay`. This spreads out retries from jobs that failed at the
+/// same time better than plain exponential backoff.
+///
+/// The actual previous delay isn't stored, so the upper bound grows from the upper bound
+/// of the previous retry instead.
+#[derive(Debug, Clone)]
+pub struct DecorrelatedJitter {
+    /// The shortest delay.
+    pub base: Duration,
+    /// The longest delay allowed.
+    pub max_delay: Duration,
+}
+
+impl DecorrelatedJitter {
+    /// Create a new [DecorrelatedJitter] policy.
+    pub fn new(base: Duration, max_delay: Duration) -> Self {
+        Self { base, max_delay }
+    }
+}
+
+impl RetryPolicy for DecorrelatedJitter {
+    fn decide(&self, context: &RetryContext<'_>) -> RetryDecision {
+        let base = self.base.as_secs_f64();
+        let max_delay = self.max_delay.as_secs_f64();
+        let upper = (base * 3f64.powi(context.current_try + 1)).min(max_delay);
+        let delay = if upper > base {
+            base + rand::random::<f64>() * (upper - base)
+        } else {
+            upper
+        };
+        let delay = Duration::try_from_secs_f64(delay).unwrap_or(self.max_delay);
+        context.retry_after(delay)
+    }
+}
+
+/// Wrap an error in this type to fail the job immediately, without any further retries.
+///
+/// This is detected when a job returns it directly, or when it appears in the source chain of a
+/// `Box<dyn std::error::Error>` or [eyre::Report] returned by the job.
+///
+/// ```
+/// # use effectum::*;
+/// async fn a_job(job: RunningJob, _context: ()) -> Result<(), Permanent> {
+///     let _payload: String = job.json_payload().map_err(Permanent::new)?;
+///     Ok(())
+/// }
+/// ```
+pub struct Permanent(pub Box<dyn std::error::Error + Send + Sync + 'static>);
+
+impl Permanent {
+    /// Wrap an error to mark it as permanent.
+    pub fn new(error: impl Into<Box<dyn std::error::Error + Send + Sync + 'static>>) -> Self {
+        Self(error.into())
+    }
+}
+
+impl Debug for Permanent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+impl Display for Permanent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl std::error::Error for Permanent {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+fn chain_has_permanent(error: &(dyn std::error::Error + 'static)) -> bool {
+    std::iter::successors(Some(error), |e| e.source()).any(|e| e.is::<Permanent>())
+}
+
+/// Check if an error returned from a job was marked as [Permanent].
+pub(crate) fn is_permanent<E: 'static>(error: &E) -> bool {
+    let error = error as &dyn Any;
+    if error.is::<Permanent>() {
+        true
+    } else if let Some(e) = error.downcast_ref::<Box<dyn std::error::Error + Send + Sync>>() {
+        chain_has_permanent(e.as_ref())
+    } else if let Some(e) = error.downcast_ref::<Box<dyn std::error::Error + Send>>() {
+        chain_has_permanent(e.as_ref())
+    } else if let Some(e) = error.downcast_ref::<Box<dyn std::error::Error>>() {
+        chain_has_permanent(e.as_ref())
+    } else if let Some(e) = error.downcast_ref::<eyre::Report>() {
+        e.chain().any(|e| e.is::<Permanent>())
+    } else {
+        false
+    }
+}
+
+/// Figure out what to do with a job that returned an error.
+pub(crate) fn decide_retry<E: Display + 'static>(
+    job: &RunningJob,
+    error: &E,
+    policy: Option<&dyn RetryPolicy>,
+) -> RetryDecision {
+    if is_permanent(error) {
+        return RetryDecision::Fail;
+    }
+
+    match policy {
+        Some(policy) => policy.decide(&RetryContext::new(job, error)),
+        None => job.default_retry_decision(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("outer")]
+    struct Outer(#[source] Permanent);
+
+    #[test]
+    fn detect_permanent() {
+        assert!(is_permanent(&Permanent::new("bad")));
+
+        let boxed: Box<dyn std::error::Error + Send + Sync> = Box::new(Permanent::new("bad"));
+        assert!(is_permanent(&boxed));
+
+        let nested: Box<dyn std::error::Error + Send + Sync> =
+            Box::new(Outer(Permanent::new("bad")));
+        assert!(is_permanent(&nested));
+
+        let report = eyre::Report::new(Permanent::new("bad"));
+        assert!(is_permanent(&report));
+
+        assert!(!is_permanent(&"bad".to_string()));
+        let boxed: Box<dyn std::error::Error + Send + Sync> = "bad".into();
+        assert!(!is_permanent(&boxed));
+    }
+}
@@ -6,8 +6,13 @@ use time::OffsetDateTime;
 use tokio::sync::mpsc;
 use tokio::time::Instant;
 
+use crate::blob_store::BlobStore;
+use crate::compression::Compression;
 use crate::db_writer::DbOperation;
+use crate::encryption::KeyProvider;
+use crate::hooks::JobHooks;
 use crate::pending_jobs::ScheduledJobType;
+use crate::priority_aging::PriorityAging;
 use crate::worker_list::Workers;
 
 pub(crate) struct SharedStateData {
@@ -18,6 +23,13 @@ pub(crate) struct SharedStateData {
     pub close: tokio::sync::watch::Receiver<()>,
     pub time: Time,
     pub pending_jobs_tx: tokio::sync::mpsc::Sender<ScheduledJobType>,
+    pub priority_aging: Option<PriorityAging>,
+    /// The fairness key of the most recently started job, used to round-robin between keys.
+    pub fairness_cursor: std::sync::Mutex<String>,
+    pub compression: Option<Compression>,
+    pub encryption_keys: Option<Arc<dyn KeyProvider>>,
+    pub blob_store: Arc<BlobStore>,
+    pub hooks: JobHooks,
 }
 
 #[derive(Clone)]
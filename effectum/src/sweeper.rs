@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+use tracing::{event, instrument, Level, Span};
+
+use crate::{
+    db_writer::{expire::ExpireJobsArgs, DbOperation, DbOperationType},
+    shared_state::SharedState,
+    Error, Result,
+};
+
+/// Start the task that periodically cleans up jobs in the queue.
+pub(crate) fn start_sweeper(queue: SharedState, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(sweeper_task(queue, interval))
+}
+
+async fn sweeper_task(queue: SharedState, interval: Duration) {
+    let mut global_close_rx = queue.close.clone();
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                match expire_jobs(&queue).await {
+                    Ok(0) => {}
+                    Ok(count) => event!(Level::DEBUG, %count, "Expired jobs that did not start in time"),
+                    Err(e) => event!(Level::ERROR, err = %e, "Failed to expire jobs"),
+                }
+            }
+            _ = global_close_rx.changed() => {
+                break;
+            }
+        }
+    }
+}
+
+#[instrument(level = "debug", skip(queue))]
+async fn expire_jobs(queue: &SharedState) -> Result<usize> {
+    let now = queue.time.now();
+    let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+    queue
+        .db_write_tx
+        .send(DbOperation {
+            worker_id: 0,
+            span: Span::current(),
+            operation: DbOperationType::ExpireJobs(ExpireJobsArgs { now, result_tx }),
+        })
+        .await
+        .map_err(|_| Error::QueueClosed)?;
+    result_rx.await.map_err(|_| Error::QueueClosed)?
+}
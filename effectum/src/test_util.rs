@@ -19,7 +19,7 @@ use crate::{
     job_status::JobStatus,
     shared_state::Time,
     worker::{Worker, WorkerBuilder},
-    JobState, Queue,
+    JobState, Queue, QueueOptions,
 };
 
 #[derive(Debug)]
@@ -102,8 +102,20 @@ pub fn queue_db_path(dir: &TempDir) -> PathBuf {
 }
 
 pub async fn create_test_queue(dir: TempDir) -> TestQueue {
+    create_test_queue_with_options(dir, |options| options).await
+}
+
+pub async fn create_test_queue_with_options(
+    dir: TempDir,
+    configure: impl FnOnce(QueueOptions) -> QueueOptions,
+) -> TestQueue {
     let path = queue_db_path(&dir);
-    let queue = crate::Queue::new(&path).await.unwrap();
+    let options = crate::Queue::builder(&path);
+    // With paused time, the clock jumps to the gauge task's next tick whenever the test waits on
+    // the database, so leave it off unless a test needs it.
+    #[cfg(feature = "metrics")]
+    let options = options.metrics_interval(Duration::ZERO);
+    let queue = configure(options).build().await.unwrap();
 
     TestQueue { queue, path, dir }
 }
@@ -202,8 +214,16 @@ impl TestEnvironment {
 
     pub async fn from_path(dir: TempDir) -> Self {
         Lazy::force(&TRACING);
-        let queue = create_test_queue(dir).await;
+        Self::from_queue(create_test_queue(dir).await)
+    }
+
+    pub async fn with_options(configure: impl FnOnce(QueueOptions) -> QueueOptions) -> Self {
+        Lazy::force(&TRACING);
+        let dir = TempDir::new().unwrap();
+        Self::from_queue(create_test_queue_with_options(dir, configure).await)
+    }
 
+    fn from_queue(queue: TestQueue) -> Self {
         let registry = JobRegistry::new(job_list());
 
         TestEnvironment {
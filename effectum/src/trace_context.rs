@@ -0,0 +1,166 @@
+#![cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+
+use tracing::Span;
+
+use crate::RunningJobData;
+
+/// Serialize the OpenTelemetry context of the current span, so that the job's runs can be
+/// connected to the trace that added it. The context is encoded with the global text map
+/// propagator, so nothing is captured unless one has been set with
+/// `opentelemetry::global::set_text_map_propagator`.
+///
+/// Without the `opentelemetry` feature, this always returns `None`.
+pub(crate) fn current_trace_context() -> Option<String> {
+    #[cfg(feature = "opentelemetry")]
+    {
+        use std::collections::HashMap;
+
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let context = Span::current().context();
+        let mut fields = HashMap::new();
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut fields)
+        });
+
+        if fields.is_empty() {
+            return None;
+        }
+        serde_json::to_string(&fields).ok()
+    }
+
+    #[cfg(not(feature = "opentelemetry"))]
+    None
+}
+
+/// Connect the span for a run of a job to the trace that the job was added from. The first run
+/// of a job becomes a child of the span that added it. Retries and scheduled runs of recurring
+/// jobs link to that span instead, so that they don't keep extending the original trace.
+pub(crate) fn connect_span(span: &Span, job: &RunningJobData) {
+    let Some(trace_context) = job.trace_context.as_deref() else {
+        return;
+    };
+    let link = job.current_try > 0 || job.scheduled_recurring;
+
+    #[cfg(feature = "opentelemetry")]
+    {
+        use std::collections::HashMap;
+
+        use opentelemetry::trace::TraceContextExt;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let Ok(fields) = serde_json::from_str::<HashMap<String, String>>(trace_context) else {
+            return;
+        };
+        let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&fields)
+        });
+
+        if link {
+            span.add_link(context.span().span_context().clone());
+        } else {
+            span.set_parent(context).ok();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "opentelemetry"))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator,
+        trace::{InMemorySpanExporter, SdkTracerProvider, SpanData},
+    };
+    use tracing::Instrument;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::{
+        test_util::{wait_for_job, wait_for_job_status, TestContext, TestEnvironment},
+        Job, JobRunner, JobState,
+    };
+
+    /// The `run_job` spans from the tracing middleware, leaving out the worker's own debug-level
+    /// span with the same name.
+    fn run_spans(exporter: &InMemorySpanExporter) -> Vec<SpanData> {
+        exporter
+            .get_finished_spans()
+            .unwrap()
+            .into_iter()
+            .filter(|span| {
+                span.name == "run_job"
+                    && span.attributes.iter().any(|kv| {
+                        kv.key.as_str() == "target" && kv.value.as_str() == "effectum::middleware"
+                    })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn propagate_trace_context() {
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("effectum")));
+        // The test runtime runs everything on this thread, so a thread-local subscriber is enough.
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut test = TestEnvironment::new().await;
+        let job_def = JobRunner::builder("traced", |job, _context: Arc<TestContext>| async move {
+            if job.current_try == 0 {
+                Err("try again")
+            } else {
+                Ok(())
+            }
+        })
+        .build();
+        test.registry.add(&job_def);
+        let _worker = test.worker().build().await.expect("failed to build worker");
+
+        let enqueue_span = tracing::info_span!("enqueue");
+        let job_id = Job::builder("traced")
+            .backoff_initial_interval(Duration::from_secs(0))
+            .add_to(&test.queue)
+            .instrument(enqueue_span)
+            .await
+            .expect("failed to add job");
+        wait_for_job_status("job to succeed", &test.queue, job_id, JobState::Succeeded).await;
+
+        let enqueue = exporter
+            .get_finished_spans()
+            .unwrap()
+            .into_iter()
+            .find(|span| span.name == "enqueue")
+            .expect("enqueue span");
+        let enqueue_context = enqueue.span_context;
+
+        let runs = run_spans(&exporter);
+        assert_eq!(runs.len(), 2);
+
+        // The first run is part of the trace that added the job.
+        assert_eq!(runs[0].span_context.trace_id(), enqueue_context.trace_id());
+        assert_eq!(runs[0].parent_span_id, enqueue_context.span_id());
+
+        // The retry links back to it.
+        assert_ne!(runs[1].span_context.trace_id(), enqueue_context.trace_id());
+        assert_eq!(runs[1].links.links.len(), 1);
+        let link = &runs[1].links.links[0].span_context;
+        assert_eq!(link.trace_id(), enqueue_context.trace_id());
+        assert_eq!(link.span_id(), enqueue_context.span_id());
+
+        // Jobs added outside of a span have no trace context.
+        let untraced_id = Job::builder("traced")
+            .backoff_initial_interval(Duration::from_secs(0))
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        wait_for_job("untraced job to succeed", &test.queue, untraced_id).await;
+        let runs = run_spans(&exporter);
+        assert_eq!(runs.len(), 4);
+        assert!(runs[2..].iter().all(|span| span.links.links.is_empty()));
+    }
+}
@@ -7,6 +7,7 @@ use std::{
 };
 
 use ahash::HashMap;
+use time::OffsetDateTime;
 use tokio::{
     sync::{oneshot, Notify},
     task::JoinHandle,
@@ -15,14 +16,21 @@ use tokio::{
 use tracing::{event, instrument, Level, Span};
 
 use crate::{
+    adaptive_concurrency::{AdaptiveConcurrency, ConcurrencyController, JobSignal},
     db_writer::{
         ready_jobs::{GetReadyJobsArgs, ReadyJob},
         DbOperation, DbOperationType,
     },
+    hooks::{run_hook, JobHookEvent},
+    job::RunOutcome,
     job_registry::{JobRegistry, JobRunner},
+    labels::Labels,
+    middleware::{middleware_stack, JobMiddleware, MiddlewareStack},
+    queue_metrics::job_started,
+    resources::{ResourcePool, Resources},
     shared_state::{SharedState, Time},
     worker_list::ListeningWorker,
-    Error, Queue, Result, SmartString,
+    Error, Queue, Result, RunningJob, SmartString,
 };
 
 /// The internal ID for a worker.
@@ -40,6 +48,10 @@ pub struct Worker {
     /// The worker's internal ID.
     pub id: WorkerId,
     counts: Arc<RunningJobs>,
+    listener: Arc<ListeningWorker>,
+    queue: SharedState,
+    /// The job types that this worker has runners for.
+    supported_job_types: Vec<SmartString>,
     worker_list_task: Option<CancellableTask>,
 }
 
@@ -81,6 +93,57 @@ impl Worker {
             finished: self.counts.finished.load(Ordering::Relaxed),
         }
     }
+
+    /// Change the worker's concurrency limits. See [WorkerBuilder::min_concurrency] and
+    /// [WorkerBuilder::max_concurrency]. `min_concurrency` is capped at `max_concurrency`.
+    ///
+    /// If the worker is running more jobs than the new maximum allows, the running jobs are
+    /// allowed to finish, and the worker won't fetch more until it drops below the new minimum.
+    /// With adaptive concurrency, the current limit is moved inside the new range.
+    pub fn set_concurrency(&self, min_concurrency: u16, max_concurrency: u16) {
+        assert!(min_concurrency > 0);
+        assert!(max_concurrency > 0);
+        let min_concurrency = min_concurrency.min(max_concurrency);
+
+        event!(
+            Level::INFO,
+            worker_id = %self.id,
+            min_concurrency,
+            max_concurrency,
+            "Changing worker concurrency"
+        );
+        self.listener
+            .set_concurrency(min_concurrency, max_concurrency);
+    }
+
+    /// Change the job types that this worker runs. The worker must have a runner for each job
+    /// type, from its registry or the runners given to [WorkerBuilder::jobs]. Jobs that it is
+    /// already running will finish even if their type is no longer in the list. An empty list
+    /// stops the worker from taking new jobs, without unregistering it.
+    pub async fn set_job_types(&self, job_types: &[impl AsRef<str>]) -> Result<()> {
+        let job_types = job_types
+            .iter()
+            .map(|job_type| {
+                let job_type = job_type.as_ref();
+                if self.supported_job_types.iter().any(|s| s == job_type) {
+                    Ok(SmartString::from(job_type))
+                } else {
+                    Err(Error::UnsupportedJobType(job_type.to_string()))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        event!(Level::INFO, worker_id = %self.id, ?job_types, "Changing worker job types");
+        let mut workers = self.queue.workers.write().await;
+        workers.set_job_types(self.id, &job_types)
+    }
+
+    /// The current limit on the weighted load of the worker's running jobs. This is the same as
+    /// `max_concurrency` unless the worker uses
+    /// [adaptive concurrency](WorkerBuilder::adaptive_concurrency).
+    pub fn concurrency_limit(&self) -> u16 {
+        self.listener.concurrency_limit()
+    }
 }
 
 impl Drop for Worker {
@@ -113,6 +176,14 @@ where
     /// The maximum number of jobs that can be run concurrently. Defaults to 1, but you will
     /// usually want to set this to a higher number.
     max_concurrency: Option<u16>,
+    /// Middleware to run around each job, outside of the registry's middleware.
+    middleware: Vec<Arc<dyn JobMiddleware<CONTEXT>>>,
+    /// Adjust the concurrency limit based on how the worker's jobs are doing.
+    adaptive_concurrency: Option<AdaptiveConcurrency>,
+    /// How much of each named resource the worker's running jobs can use in total.
+    resource_capacity: Resources,
+    /// Labels that jobs can require the worker to have.
+    labels: Labels,
 }
 
 impl<'a, CONTEXT> WorkerBuilder<'a, CONTEXT>
@@ -129,6 +200,10 @@ where
             jobs: Vec::new(),
             min_concurrency: None,
             max_concurrency: None,
+            adaptive_concurrency: None,
+            resource_capacity: Resources::new(),
+            labels: Labels::new(),
+            middleware: Vec::new(),
         }
     }
 
@@ -196,72 +271,127 @@ where
         self
     }
 
+    /// Adjust the worker's concurrency limit between `min_concurrency` and `max_concurrency`
+    /// based on the run time, errors, and timeouts of its jobs. See [AdaptiveConcurrency] for
+    /// details. The limit starts at `min_concurrency`, which defaults to 1 in this mode, and the
+    /// worker fetches more jobs whenever it is running fewer than the current limit.
+    ///
+    /// The current limit is available from [Worker::concurrency_limit] and
+    /// [Queue::list_workers].
+    pub fn adaptive_concurrency(mut self, settings: AdaptiveConcurrency) -> Self {
+        self.adaptive_concurrency = Some(settings);
+        self
+    }
+
+    /// Set how much of a named resource this worker has. The worker only starts a job if the
+    /// job's [resources](crate::Job::resources) fit in what its running jobs leave over, in
+    /// addition to the `max_concurrency` limit on their weight. Jobs that need more of a
+    /// resource than the worker has in total are left for other workers. Resources that are not
+    /// set here are unlimited.
+    pub fn resource_capacity(mut self, name: impl ToString, capacity: u32) -> Self {
+        self.resource_capacity.insert(name.to_string(), capacity);
+        self
+    }
+
+    /// Set a label on this worker, such as `label("region", "eu")`. Jobs that
+    /// [require a label](crate::JobBuilder::require_label) only run on workers that have the
+    /// label set to the same value, and other jobs can run on any worker.
+    pub fn label(mut self, name: impl ToString, value: impl ToString) -> Self {
+        self.labels.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Add a [JobMiddleware] that runs around every job on this worker. Middleware runs in the
+    /// order it was added, outside of any middleware added to the [JobRegistry].
+    pub fn middleware(mut self, middleware: impl JobMiddleware<CONTEXT>) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
     /// Consume this [WorkerBuilder] and create a new [Worker]. The Worker must be stored, as it
     /// will automatically disconnect from the Queue when it is dropped.
     pub async fn build(self) -> Result<Worker> {
+        // Keep every runner, not just the ones in `self.jobs`, so that the job types can be
+        // changed later with `Worker::set_job_types`.
         let job_defs: HashMap<SmartString, JobRunner<CONTEXT>> =
             if let Some(job_defs) = self.job_defs {
                 job_defs
                     .into_iter()
-                    .filter(|job| self.jobs.is_empty() || self.jobs.contains(&job.name))
                     .map(|job| (job.name.clone(), job))
                     .collect()
             } else if let Some(registry) = self.registry {
-                let job_list = if self.jobs.is_empty() {
-                    registry.jobs.keys().cloned().collect()
-                } else {
-                    self.jobs
-                };
-
-                job_list
-                    .iter()
-                    .filter_map(|job| {
-                        registry
-                            .jobs
-                            .get(job)
-                            .map(|job_def| (job.clone(), job_def.clone()))
-                    })
-                    .collect()
+                registry.jobs.clone()
             } else {
                 panic!("Must set either registry or jobs");
             };
 
+        let registry_middleware = self
+            .registry
+            .map(|registry| registry.middleware.as_slice())
+            .unwrap_or_default();
+        let middleware = middleware_stack(&self.middleware, registry_middleware);
+
         let max_concurrency = self.max_concurrency.unwrap_or(1).max(1);
-        let min_concurrency = self.min_concurrency.unwrap_or(max_concurrency).max(1);
+        let adaptive = self.adaptive_concurrency.map(ConcurrencyController::new);
+        let min_concurrency = match (self.min_concurrency, &adaptive) {
+            (Some(min_concurrency), Some(_)) => min_concurrency.min(max_concurrency),
+            (Some(min_concurrency), None) => min_concurrency,
+            (None, Some(_)) => 1,
+            (None, None) => max_concurrency,
+        }
+        .max(1);
 
-        let job_list = job_defs.keys().cloned().collect::<Vec<_>>();
+        let supported_job_types = job_defs.keys().cloned().collect::<Vec<_>>();
+        let job_list = if self.jobs.is_empty() {
+            supported_job_types.clone()
+        } else {
+            self.jobs
+                .into_iter()
+                .filter(|job| job_defs.contains_key(job))
+                .collect::<Vec<_>>()
+        };
 
         event!(
             Level::INFO,
             ?job_list,
             min_concurrency,
             max_concurrency,
+            resource_capacity = ?self.resource_capacity,
+            labels = ?self.labels,
             "Starting worker",
         );
 
         let (close_tx, close_rx) = oneshot::channel();
 
-        let mut workers = self.queue.state.workers.write().await;
-        let listener = workers.add_worker(&job_list);
-        drop(workers);
-
         let counts = Arc::new(RunningJobs {
             started: AtomicU64::new(0),
             finished: AtomicU64::new(0),
             current_weighted: AtomicU32::new(0),
             job_finished: Notify::new(),
+            jobs: std::sync::Mutex::new(HashMap::default()),
+            last_fetched: std::sync::Mutex::new(None),
+            resources: ResourcePool::new(self.resource_capacity),
         });
 
+        let mut workers = self.queue.state.workers.write().await;
+        let listener = workers.add_worker(
+            &job_list,
+            min_concurrency,
+            max_concurrency,
+            adaptive,
+            counts.clone(),
+            self.labels,
+        );
+        drop(workers);
+
         let worker_id = listener.id;
         let worker_internal = WorkerInternal {
-            listener,
+            listener: listener.clone(),
             running_jobs: counts.clone(),
-            job_list: job_list.into_iter().map(String::from).collect(),
             job_defs: Arc::new(job_defs),
             queue: self.queue.state.clone(),
             context: self.context,
-            min_concurrency,
-            max_concurrency,
+            middleware,
         };
 
         let join_handle = tokio::spawn(worker_internal.run(close_rx));
@@ -269,6 +399,9 @@ where
         Ok(Worker {
             id: worker_id,
             counts,
+            listener,
+            queue: self.queue.state.clone(),
+            supported_job_types,
             worker_list_task: Some(CancellableTask {
                 close_tx,
                 join_handle,
@@ -282,6 +415,12 @@ pub(crate) struct RunningJobs {
     pub finished: AtomicU64,
     pub current_weighted: AtomicU32,
     pub job_finished: Notify,
+    /// The jobs that are running now, by ID.
+    pub jobs: std::sync::Mutex<HashMap<uuid::Uuid, RunningJob>>,
+    /// When the worker last fetched jobs from the queue.
+    pub last_fetched: std::sync::Mutex<Option<OffsetDateTime>>,
+    /// The worker's resource capacity, and how much of it the running jobs are using.
+    pub resources: ResourcePool,
 }
 
 struct WorkerInternal<CONTEXT>
@@ -290,12 +429,10 @@ where
 {
     listener: Arc<ListeningWorker>,
     queue: SharedState,
-    job_list: Vec<String>,
     job_defs: Arc<HashMap<SmartString, JobRunner<CONTEXT>>>,
     running_jobs: Arc<RunningJobs>,
     context: CONTEXT,
-    min_concurrency: u16,
-    max_concurrency: u16,
+    middleware: MiddlewareStack<CONTEXT>,
 }
 
 pub(crate) fn log_error<T, E>(result: Result<T, E>)
@@ -316,8 +453,8 @@ where
         let mut global_close_rx = self.queue.close.clone();
         loop {
             let mut running_jobs = self.running_jobs.current_weighted.load(Ordering::Relaxed);
-            let min_concurrency = self.min_concurrency as u32;
-            println!("Running jobs = {} (and needs to be <) Min concurrency = {}", running_jobs, min_concurrency);
+            let min_concurrency = self.listener.fetch_threshold() as u32;
+            event!(Level::TRACE, %running_jobs, %min_concurrency, "Checking whether to fetch jobs");
             if running_jobs < min_concurrency {
                 log_error(self.run_ready_jobs().await);
                 running_jobs = self.running_jobs.current_weighted.load(Ordering::Relaxed);
@@ -328,21 +465,22 @@ where
             tokio::select! {
                 biased;
                 _ = &mut close_rx => {
-                    println!("close_rx => SHUTDOWN");
+                    event!(Level::TRACE, "Worker closed, shutting down");
                     log_error(self.shutdown().await);
                     break;
                 }
                 _ = global_close_rx.changed() => {
-                    println!("global_close_rx => SHUTDOWN");
+                    event!(Level::TRACE, "Queue closed, shutting down");
                     log_error(self.shutdown().await);
                     break;
                 }
                 _ = self.listener.notify_task_ready.notified(), if grab_new_jobs  => {
-                    println!("New task ready");
+                    event!(Level::TRACE, "New job ready");
                 }
                 _ = self.running_jobs.job_finished.notified() => {
-                    println!("Job finished");
+                    event!(Level::TRACE, "Job finished");
                 }
+                _ = self.listener.settings_changed.notified() => {}
             }
         }
     }
@@ -360,20 +498,19 @@ where
 
     async fn run_ready_jobs(&self) -> Result<()> {
         let running_count = self.running_jobs.current_weighted.load(Ordering::Relaxed);
-        let max_concurrency = self.max_concurrency as u32;
-        let max_jobs = max_concurrency - running_count;
+        let max_concurrency = self.listener.concurrency_limit() as u32;
+        let max_jobs = max_concurrency.saturating_sub(running_count);
         let job_types = self
-            .job_list
+            .listener
+            .job_types()
             .iter()
-            .map(|s| rusqlite::types::Value::from(s.clone()))
+            .map(|s| rusqlite::types::Value::from(s.to_string()))
             .collect::<Vec<_>>();
 
         let running_jobs = self.running_jobs.clone();
         let worker_id = self.listener.id;
         let now = self.queue.time.now();
-        event!(Level::TRACE, %now, current_running = %running_count, %max_concurrency, "Checking ready jobs");
-
-        println!("Currently Running = {}, Max Concurrency = {}, Max Jobs = {}", running_count, max_concurrency, max_jobs);
+        event!(Level::TRACE, %now, current_running = %running_count, %max_concurrency, %max_jobs, "Checking ready jobs");
 
         let (result_tx, result_rx) = oneshot::channel();
         self.queue
@@ -386,6 +523,7 @@ where
                     max_jobs,
                     max_concurrency,
                     running_jobs,
+                    labels: self.listener.labels_json.clone(),
                     now,
                     result_tx,
                 }),
@@ -394,8 +532,13 @@ where
             .map_err(|_| Error::QueueClosed)?;
 
         let ready_jobs = result_rx.await.map_err(|_| Error::QueueClosed)??;
+        *self.running_jobs.last_fetched.lock().unwrap() = Some(now);
 
-        println!("Ready jobs = {}", ready_jobs.len());
+        event!(
+            Level::TRACE,
+            ready_jobs = ready_jobs.len(),
+            "Fetched ready jobs"
+        );
 
         for job in ready_jobs {
             self.run_job(job).await?;
@@ -412,23 +555,33 @@ where
             done_rx: mut done,
         }: ReadyJob,
     ) -> Result<()> {
-        println!("Running job as worker: {}", self.listener.id);
-
         let job_def = self
             .job_defs
             .get(job.job_type.as_str())
             .expect("Got job for unsupported type");
 
         let worker_id = self.listener.id;
+        let listener = self.listener.clone();
         let running = self.running_jobs.clone();
         let autoheartbeat = job_def.autoheartbeat;
         let time = job.queue.time.clone();
 
-        (job_def.runner)(job.clone(), self.context.clone());
+        let queue_wait: std::time::Duration =
+            (job.start_time - job.run_at).try_into().unwrap_or_default();
+        job_started(&job.job_type, queue_wait);
+        self.running_jobs
+            .jobs
+            .lock()
+            .unwrap()
+            .insert(job.id, job.clone());
+        run_hook(&self.queue.hooks.on_start, || JobHookEvent::for_job(&job));
+        (job_def.runner)(job.clone(), self.context.clone(), self.middleware.clone());
 
         tokio::spawn(async move {
             let use_autohearbeat = autoheartbeat && job.heartbeat_increment > 0;
             event!(Level::DEBUG, ?job, "Starting job monitor task");
+            let started = Instant::now();
+            let mut timed_out = false;
             loop {
                 let expires = job.expires.load(Ordering::Relaxed);
                 let expires_instant = time.instant_for_timestamp(expires);
@@ -449,6 +602,7 @@ where
                         let now_expires = job.expires.load(Ordering::Relaxed);
                         if now_expires == expires {
                             if !job.is_done().await {
+                                timed_out = true;
                                 log_error(job.fail("Job expired").await);
                             }
                             break;
@@ -462,6 +616,18 @@ where
 
             // Do this in a separate task from the job runner so that even if something goes horribly wrong
             // we'll still be able to update the internal counts.
+            let signal = match *done.borrow() {
+                _ if timed_out => Some(JobSignal::TimedOut),
+                RunOutcome::Succeeded => Some(JobSignal::Succeeded(started.elapsed())),
+                RunOutcome::Failed => Some(JobSignal::Failed(started.elapsed())),
+                RunOutcome::Running | RunOutcome::Other => None,
+            };
+            if let Some(signal) = signal {
+                listener.record_job(signal);
+            }
+
+            running.jobs.lock().unwrap().remove(&job.id);
+            running.resources.release(&job.resources);
             running
                 .current_weighted
                 .fetch_sub(job.weight as u32, Ordering::Relaxed);
@@ -487,7 +653,16 @@ async fn wait_for_next_autoheartbeat(time: &Time, expires: i64, heartbeat_increm
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_util::TestEnvironment;
+    use crate::{
+        test_util::{wait_for, wait_for_job, TestEnvironment},
+        Job,
+    };
+
+    async fn running_count(queue: &Queue, worker_id: WorkerId) -> usize {
+        let workers = queue.list_workers().await;
+        let worker = workers.iter().find(|w| w.id == worker_id).unwrap();
+        worker.running_jobs.len()
+    }
 
     #[tokio::test]
     #[should_panic]
@@ -498,4 +673,143 @@ mod tests {
             .await
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn set_concurrency() {
+        let test = TestEnvironment::new().await;
+        let worker = test
+            .worker()
+            .max_concurrency(1)
+            .build()
+            .await
+            .expect("failed to build worker");
+
+        let mut job_ids = Vec::new();
+        for _ in 0..4 {
+            let job_id = Job::builder("wait_for_watch")
+                .json_payload(&1)
+                .unwrap()
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+            job_ids.push(job_id);
+        }
+
+        wait_for("first job to start", || async {
+            match running_count(&test.queue, worker.id).await {
+                1 => Ok(()),
+                n => Err(format!("{n} jobs running")),
+            }
+        })
+        .await;
+
+        worker.set_concurrency(3, 3);
+        wait_for("more jobs to start", || async {
+            match running_count(&test.queue, worker.id).await {
+                3 => Ok(()),
+                n => Err(format!("{n} jobs running")),
+            }
+        })
+        .await;
+
+        // Shrinking leaves the running jobs alone.
+        worker.set_concurrency(1, 1);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(running_count(&test.queue, worker.id).await, 3);
+        let workers = test.queue.list_workers().await;
+        assert_eq!(workers[0].min_concurrency, 1);
+        assert_eq!(workers[0].max_concurrency, 1);
+
+        test.context.watch_tx.send_replace(1);
+        for job_id in job_ids {
+            wait_for_job("job to finish", &test.queue, job_id).await;
+        }
+    }
+
+    async fn wait_for_limit(queue: &Queue, worker: &Worker, expected: u16) {
+        wait_for(format!("concurrency limit {expected}"), || async {
+            let info_limit = queue.list_workers().await[0].concurrency_limit;
+            match worker.concurrency_limit() {
+                limit if limit == expected && info_limit == expected => Ok(()),
+                limit => Err(format!("limit is {limit}")),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn adaptive_concurrency() {
+        let test = TestEnvironment::new().await;
+        let worker = test
+            .worker()
+            .max_concurrency(4)
+            .adaptive_concurrency(AdaptiveConcurrency::new())
+            .build()
+            .await
+            .expect("failed to build worker");
+        assert_eq!(worker.concurrency_limit(), 1);
+
+        // Successful jobs raise the limit by one per round, up to the maximum.
+        for _ in 0..10 {
+            Job::builder("counter")
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+        }
+        wait_for_limit(&test.queue, &worker, 4).await;
+        wait_for("jobs to finish", || async {
+            match worker.counts().finished {
+                10 => Ok(()),
+                n => Err(format!("{n} jobs finished")),
+            }
+        })
+        .await;
+
+        // A round of failures lowers it.
+        for _ in 0..4 {
+            Job::builder("retry")
+                .json_payload(&10)
+                .unwrap()
+                .max_retries(0)
+                .add_to(&test.queue)
+                .await
+                .expect("failed to add job");
+        }
+        wait_for_limit(&test.queue, &worker, 3).await;
+    }
+
+    #[tokio::test]
+    async fn set_job_types() {
+        let test = TestEnvironment::new().await;
+        let worker = test
+            .worker()
+            .limit_job_types(&["counter"])
+            .build()
+            .await
+            .expect("failed to build worker");
+
+        let job_id = Job::builder("push_payload")
+            .json_payload(&"a")
+            .unwrap()
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(test.context.get_values().await.is_empty());
+
+        let err = worker.set_job_types(&["unknown"]).await.unwrap_err();
+        assert!(matches!(err, Error::UnsupportedJobType(t) if t == "unknown"));
+
+        worker
+            .set_job_types(&["counter", "push_payload"])
+            .await
+            .unwrap();
+        wait_for_job("job to run", &test.queue, job_id).await;
+        assert_eq!(test.context.get_values().await, vec!["a".to_string()]);
+
+        let workers = test.queue.list_workers().await;
+        let mut job_types = workers[0].job_types.clone();
+        job_types.sort();
+        assert_eq!(job_types, vec!["counter", "push_payload"]);
+    }
 }
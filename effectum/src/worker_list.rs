@@ -1,15 +1,187 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc, RwLock,
+};
 
 use ahash::HashMap;
+use serde::Serialize;
+use time::OffsetDateTime;
 use tokio::sync::{watch, Notify};
+use tracing::{event, Level};
+use uuid::Uuid;
 
+use crate::adaptive_concurrency::{ConcurrencyController, JobSignal};
+use crate::labels::{labels_match, Labels};
+use crate::queue_metrics::worker_concurrency_limit;
+use crate::resources::Resources;
+use crate::worker::{RunningJobs, WorkerId};
 use crate::SmartString;
-use crate::{Error, Result};
+use crate::{Error, Queue, Result};
 
 pub(crate) struct ListeningWorker {
     pub id: u64,
     pub notify_task_ready: Notify,
-    pub job_types: Vec<SmartString>,
+    /// Wakes the worker when its job types or concurrency limits change.
+    pub settings_changed: Notify,
+    pub job_types: RwLock<Vec<SmartString>>,
+    pub min_concurrency: AtomicU16,
+    pub max_concurrency: AtomicU16,
+    /// The current limit on the weighted load of running jobs. This is always the same as
+    /// `max_concurrency` unless the worker uses adaptive concurrency.
+    pub concurrency_limit: AtomicU16,
+    pub adaptive: Option<ConcurrencyController>,
+    pub running_jobs: Arc<RunningJobs>,
+    /// The worker's labels. Jobs that require labels only run on workers that have them.
+    pub labels: Labels,
+    /// The labels encoded as JSON, for the ready jobs query.
+    pub labels_json: String,
+}
+
+/// Information about a worker, returned from [Queue::list_workers].
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerInfo {
+    /// The worker's internal ID.
+    pub id: WorkerId,
+    /// The job types that the worker runs.
+    pub job_types: Vec<String>,
+    /// The worker fetches new jobs when its weighted load drops below this number.
+    pub min_concurrency: u16,
+    /// The maximum weighted load of the worker's running jobs.
+    pub max_concurrency: u16,
+    /// The current limit on the weighted load. This is `max_concurrency` unless the worker uses
+    /// adaptive concurrency.
+    pub concurrency_limit: u16,
+    /// The sum of the weights of the jobs that the worker is running.
+    pub current_weighted: u32,
+    /// How much of each named resource the worker has. See
+    /// [WorkerBuilder::resource_capacity](crate::WorkerBuilder::resource_capacity).
+    pub resource_capacity: Resources,
+    /// How much of each resource the worker's running jobs are using.
+    pub resources_in_use: Resources,
+    /// The worker's labels. See [WorkerBuilder::label](crate::WorkerBuilder::label).
+    pub labels: Labels,
+    /// The jobs that the worker is running.
+    pub running_jobs: Vec<RunningJobInfo>,
+    /// When the worker last fetched jobs from the queue, or `None` if it has not fetched yet.
+    #[serde(with = "time::serde::timestamp::option")]
+    pub last_fetched: Option<OffsetDateTime>,
+}
+
+/// A job that a worker is running, as part of [WorkerInfo].
+#[derive(Debug, Clone, Serialize)]
+pub struct RunningJobInfo {
+    /// The ID of the job.
+    pub id: Uuid,
+    /// The type of the job.
+    pub job_type: String,
+    /// When the job started.
+    #[serde(with = "time::serde::timestamp")]
+    pub started_at: OffsetDateTime,
+    /// When the job expires, unless it sends a heartbeat first.
+    #[serde(with = "time::serde::timestamp")]
+    pub expires_at: OffsetDateTime,
+}
+
+impl ListeningWorker {
+    pub(crate) fn job_types(&self) -> Vec<SmartString> {
+        self.job_types.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_concurrency(&self, min_concurrency: u16, max_concurrency: u16) {
+        self.min_concurrency
+            .store(min_concurrency, Ordering::Relaxed);
+        self.max_concurrency
+            .store(max_concurrency, Ordering::Relaxed);
+
+        let limit = if self.adaptive.is_some() {
+            self.concurrency_limit()
+                .clamp(min_concurrency, max_concurrency)
+        } else {
+            max_concurrency
+        };
+        self.set_concurrency_limit(limit);
+    }
+
+    pub(crate) fn concurrency_limit(&self) -> u16 {
+        self.concurrency_limit.load(Ordering::Relaxed)
+    }
+
+    fn set_concurrency_limit(&self, limit: u16) {
+        self.concurrency_limit.store(limit, Ordering::Relaxed);
+        worker_concurrency_limit(self.id, limit);
+        self.settings_changed.notify_one();
+    }
+
+    /// The worker fetches more jobs when its weighted load drops below this number. With
+    /// adaptive concurrency, this is the current limit, since the minimum is only the lowest
+    /// that the limit can go.
+    pub(crate) fn fetch_threshold(&self) -> u16 {
+        if self.adaptive.is_some() {
+            self.concurrency_limit()
+        } else {
+            self.min_concurrency.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Update the adaptive concurrency limit with the result of a finished job.
+    pub(crate) fn record_job(&self, signal: JobSignal) {
+        let Some(adaptive) = &self.adaptive else {
+            return;
+        };
+
+        let limit = self.concurrency_limit();
+        let new_limit = adaptive.record(
+            signal,
+            limit,
+            self.min_concurrency.load(Ordering::Relaxed),
+            self.max_concurrency.load(Ordering::Relaxed),
+        );
+        if let Some(new_limit) = new_limit {
+            event!(
+                Level::DEBUG,
+                worker_id = self.id,
+                limit,
+                new_limit,
+                ?signal,
+                "Changing concurrency limit"
+            );
+            self.set_concurrency_limit(new_limit);
+        }
+    }
+
+    fn info(&self) -> WorkerInfo {
+        let mut running_jobs = self
+            .running_jobs
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|job| RunningJobInfo {
+                id: job.id,
+                job_type: job.job_type.clone(),
+                started_at: job.start_time,
+                expires_at: OffsetDateTime::from_unix_timestamp(
+                    job.expires.load(Ordering::Relaxed),
+                )
+                .unwrap_or(OffsetDateTime::UNIX_EPOCH),
+            })
+            .collect::<Vec<_>>();
+        running_jobs.sort_by_key(|job| (job.started_at, job.id));
+
+        WorkerInfo {
+            id: self.id,
+            job_types: self.job_types().iter().map(|t| t.to_string()).collect(),
+            min_concurrency: self.min_concurrency.load(Ordering::Relaxed),
+            max_concurrency: self.max_concurrency.load(Ordering::Relaxed),
+            concurrency_limit: self.concurrency_limit(),
+            current_weighted: self.running_jobs.current_weighted.load(Ordering::Relaxed),
+            resource_capacity: self.running_jobs.resources.capacity().clone(),
+            resources_in_use: self.running_jobs.resources.in_use(),
+            labels: self.labels.clone(),
+            running_jobs,
+            last_fetched: *self.running_jobs.last_fetched.lock().unwrap(),
+        }
+    }
 }
 
 pub(crate) struct Workers {
@@ -30,15 +202,37 @@ impl Workers {
     }
 
     /// Add a new worker, ready to accept jobs.
-    pub(crate) fn add_worker(&mut self, job_types: &[SmartString]) -> Arc<ListeningWorker> {
+    pub(crate) fn add_worker(
+        &mut self,
+        job_types: &[SmartString],
+        min_concurrency: u16,
+        max_concurrency: u16,
+        adaptive: Option<ConcurrencyController>,
+        running_jobs: Arc<RunningJobs>,
+        labels: Labels,
+    ) -> Arc<ListeningWorker> {
         let worker_id = self.next_id;
         self.next_id += 1;
 
         let worker = Arc::new(ListeningWorker {
             id: worker_id,
             notify_task_ready: Notify::new(),
-            job_types: job_types.to_vec(),
+            settings_changed: Notify::new(),
+            job_types: RwLock::new(job_types.to_vec()),
+            min_concurrency: AtomicU16::new(min_concurrency),
+            max_concurrency: AtomicU16::new(max_concurrency),
+            // Adaptive concurrency starts low and works its way up.
+            concurrency_limit: AtomicU16::new(if adaptive.is_some() {
+                min_concurrency
+            } else {
+                max_concurrency
+            }),
+            adaptive,
+            running_jobs,
+            labels_json: serde_json::to_string(&labels).unwrap_or_default(),
+            labels,
         });
+        worker_concurrency_limit(worker.id, worker.concurrency_limit());
 
         for job in job_types {
             self.workers_by_type
@@ -61,31 +255,129 @@ impl Workers {
             .remove(&worker_id)
             .ok_or(Error::WorkerNotFound(worker_id))?;
 
-        for job in &worker.job_types {
+        self.remove_from_job_types(&worker);
+        worker_concurrency_limit(worker.id, 0);
+        self.worker_count_tx.send_replace(self.workers.len());
+
+        Ok(())
+    }
+
+    fn remove_from_job_types(&mut self, worker: &Arc<ListeningWorker>) {
+        for job in worker.job_types.read().unwrap().iter() {
             let type_workers = self.workers_by_type.get_mut(job);
 
             if let Some(type_workers) = type_workers {
-                type_workers.retain(|w| !Arc::ptr_eq(w, &worker));
+                type_workers.retain(|w| !Arc::ptr_eq(w, worker));
             }
         }
+    }
 
-        self.worker_count_tx.send_replace(self.workers.len());
+    /// Change the job types that a worker runs. Jobs that the worker is already running are not
+    /// affected.
+    pub(crate) fn set_job_types(
+        &mut self,
+        worker_id: u64,
+        job_types: &[SmartString],
+    ) -> Result<()> {
+        let worker = self
+            .workers
+            .get(&worker_id)
+            .ok_or(Error::WorkerNotFound(worker_id))?
+            .clone();
+
+        self.remove_from_job_types(&worker);
+        for job in job_types {
+            self.workers_by_type
+                .entry(job.clone())
+                .or_default()
+                .push(worker.clone());
+        }
+
+        *worker.job_types.write().unwrap() = job_types.to_vec();
+        worker.settings_changed.notify_one();
 
         Ok(())
     }
 
-    pub(crate) fn new_job_available(&self, job_type: &str) {
+    /// Wake the workers that run `job_type` and have all of the `required_labels`.
+    pub(crate) fn new_job_available(&self, job_type: &str, required_labels: &Labels) {
         let workers = self.workers_by_type.get(job_type);
         if let Some(workers) = workers {
             for worker in workers {
-                worker.notify_task_ready.notify_one();
+                if labels_match(required_labels, &worker.labels) {
+                    worker.notify_task_ready.notify_one();
+                }
             }
         }
     }
 }
 
+impl Queue {
+    /// Return information about each worker connected to the queue, ordered by ID.
+    pub async fn list_workers(&self) -> Vec<WorkerInfo> {
+        let workers = self.state.workers.read().await;
+        let mut infos = workers
+            .workers
+            .values()
+            .map(|worker| worker.info())
+            .collect::<Vec<_>>();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, AtomicU64},
+        Arc,
+    };
+
+    use futures::FutureExt;
+    use tokio::sync::{watch, Notify};
+
+    use super::{ListeningWorker, Workers};
+    use crate::{
+        labels::Labels,
+        resources::{ResourcePool, Resources},
+        test_util::{wait_for, wait_for_job, TestEnvironment},
+        worker::RunningJobs,
+        Job, SmartString,
+    };
+
+    fn add_test_worker(
+        workers: &mut Workers,
+        job_type: &str,
+        labels: &[(&str, &str)],
+    ) -> Arc<ListeningWorker> {
+        let running_jobs = Arc::new(RunningJobs {
+            started: AtomicU64::new(0),
+            finished: AtomicU64::new(0),
+            current_weighted: AtomicU32::new(0),
+            job_finished: Notify::new(),
+            jobs: std::sync::Mutex::new(Default::default()),
+            last_fetched: std::sync::Mutex::new(None),
+            resources: ResourcePool::new(Resources::new()),
+        });
+        let labels = labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Labels>();
+        workers.add_worker(
+            &[SmartString::from(job_type)],
+            1,
+            1,
+            None,
+            running_jobs,
+            labels,
+        )
+    }
+
+    /// Returns true if the worker was woken, and clears the wakeup.
+    fn notified(worker: &ListeningWorker) -> bool {
+        worker.notify_task_ready.notified().now_or_never().is_some()
+    }
+
     #[test]
     #[ignore]
     fn add_worker() {
@@ -99,8 +391,104 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn new_job_available() {
-        todo!();
+        let (worker_count_tx, _worker_count_rx) = watch::channel(0);
+        let mut workers = Workers::new(worker_count_tx);
+        let unlabeled = add_test_worker(&mut workers, "a", &[]);
+        let eu = add_test_worker(&mut workers, "a", &[("region", "eu"), ("gpu", "true")]);
+        let us = add_test_worker(&mut workers, "a", &[("region", "us")]);
+        let other_type = add_test_worker(&mut workers, "b", &[("region", "eu")]);
+
+        // Jobs without required labels wake every worker for the type.
+        workers.new_job_available("a", &Labels::new());
+        assert!(notified(&unlabeled));
+        assert!(notified(&eu));
+        assert!(notified(&us));
+        assert!(!notified(&other_type));
+
+        // Jobs with required labels only wake workers that have all of them.
+        let required = Labels::from([("region".to_string(), "eu".to_string())]);
+        workers.new_job_available("a", &required);
+        assert!(!notified(&unlabeled));
+        assert!(notified(&eu));
+        assert!(!notified(&us));
+        assert!(!notified(&other_type));
+
+        workers.new_job_available("c", &Labels::new());
+        assert!(!notified(&unlabeled));
+        assert!(!notified(&eu));
+    }
+
+    #[tokio::test]
+    async fn list_workers() {
+        let test = TestEnvironment::new().await;
+        assert!(test.queue.list_workers().await.is_empty());
+
+        let _idle_worker = test
+            .worker()
+            .limit_job_types(&["counter"])
+            .build()
+            .await
+            .expect("failed to build worker");
+        let busy_worker = test
+            .worker()
+            .limit_job_types(&["wait_for_watch"])
+            .min_concurrency(2)
+            .max_concurrency(3)
+            .build()
+            .await
+            .expect("failed to build worker");
+
+        let job_id = Job::builder("wait_for_watch")
+            .json_payload(&1)
+            .unwrap()
+            .weight(2)
+            .add_to(&test.queue)
+            .await
+            .expect("failed to add job");
+
+        let workers = wait_for("job to start", || async {
+            let workers = test.queue.list_workers().await;
+            if workers[1].running_jobs.is_empty() || workers[0].last_fetched.is_none() {
+                Err("job not running")
+            } else {
+                Ok(workers)
+            }
+        })
+        .await;
+
+        assert_eq!(workers.len(), 2);
+        assert_eq!(workers[0].job_types, vec!["counter".to_string()]);
+        assert_eq!(workers[0].current_weighted, 0);
+        assert!(workers[0].running_jobs.is_empty());
+        assert!(workers[0].last_fetched.is_some());
+
+        let busy = &workers[1];
+        assert_eq!(busy.id, busy_worker.id);
+        assert_eq!(busy.job_types, vec!["wait_for_watch".to_string()]);
+        assert_eq!(busy.min_concurrency, 2);
+        assert_eq!(busy.max_concurrency, 3);
+        assert_eq!(busy.current_weighted, 2);
+        assert_eq!(busy.running_jobs.len(), 1);
+        let running = &busy.running_jobs[0];
+        assert_eq!(running.id, job_id);
+        assert_eq!(running.job_type, "wait_for_watch");
+        assert!(running.expires_at > running.started_at);
+
+        test.context.watch_tx.send_replace(1);
+        wait_for_job("job to finish", &test.queue, job_id).await;
+        wait_for("job to be removed from worker", || async {
+            let workers = test.queue.list_workers().await;
+            if workers[1].running_jobs.is_empty() && workers[1].current_weighted == 0 {
+                Ok(())
+            } else {
+                Err("job still running")
+            }
+        })
+        .await;
+
+        busy_worker.unregister(None).await.unwrap();
+        let workers = test.queue.list_workers().await;
+        assert_eq!(workers.len(), 1);
     }
 }